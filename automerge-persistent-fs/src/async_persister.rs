@@ -0,0 +1,368 @@
+use std::{collections::HashSet, io, path::Path};
+
+use automerge::ActorId;
+use automerge_persistent::{AsyncPersister, Persister, StoredSizes};
+use futures::{StreamExt, TryStreamExt};
+
+use crate::{
+    durability::{is_temp_name, sync_dir_async},
+    flight,
+    flush::Failures,
+    is_pending,
+    layout::{make_changes_path, make_shard_path},
+    peers::{make_peer_path, PEER_INDEX_FILE},
+    removal_item, FsPersister, FsPersisterError,
+};
+
+/// The maximum number of files read at once when loading changes.
+const CONCURRENT_READS: usize = 64;
+
+/// Read a file, treating a missing or empty file as not being there.
+async fn read_optional(path: &Path) -> Result<Option<Vec<u8>>, FsPersisterError> {
+    match tokio::fs::read(path).await {
+        Ok(v) if v.is_empty() => Ok(None),
+        Ok(v) => Ok(Some(v)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The length of the file at `path`, if there is one.
+async fn file_len(path: &Path) -> Result<Option<u64>, FsPersisterError> {
+    match tokio::fs::metadata(path).await {
+        Ok(meta) if meta.is_file() => Ok(Some(meta.len())),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The length of a change, whether it is cached, being flushed or stored.
+async fn change_len(
+    persister: &FsPersister,
+    key: &(ActorId, u64),
+) -> Result<Option<u64>, FsPersisterError> {
+    let pending = {
+        let flights = flight::lock(&persister.flights);
+        (persister.cache.change(key))
+            .or_else(|| flights.change(key))
+            .map(|change| change.map(|c| c.len() as u64))
+    };
+    match pending {
+        Some(len) => Ok(len),
+        None => stored_change_len(persister, key).await,
+    }
+}
+
+/// The length of the flushed copy of a change, if there is one.
+async fn stored_change_len(
+    persister: &FsPersister,
+    key: &(ActorId, u64),
+) -> Result<Option<u64>, FsPersisterError> {
+    if let Some(log) = &persister.segments {
+        return Ok(log.change_len(key));
+    }
+    file_len(&make_changes_path(&persister.changes_path, &key.0, key.1)).await
+}
+
+/// The length of the sync state for `peer_id`, whether it is cached, being flushed or stored.
+async fn sync_state_len(
+    persister: &FsPersister,
+    peer_id: &[u8],
+) -> Result<Option<u64>, FsPersisterError> {
+    let pending = {
+        let flights = flight::lock(&persister.flights);
+        (persister.cache.sync_state(peer_id))
+            .or_else(|| flights.sync_state(peer_id))
+            .map(|sync_state| sync_state.map(|s| s.len() as u64))
+    };
+    match pending {
+        Some(len) => Ok(len),
+        None => file_len(&make_peer_path(&persister.sync_states_path, peer_id)).await,
+    }
+}
+
+/// Remove a file if it exists, returning its length.
+async fn remove_file(path: &Path) -> Result<Option<u64>, FsPersisterError> {
+    match tokio::fs::metadata(path).await {
+        Ok(meta) if meta.is_file() => {
+            tokio::fs::remove_file(path).await?;
+            Ok(Some(meta.len()))
+        }
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Collect the paths of all change files in the sharded layout.
+async fn change_files(changes_path: &Path) -> io::Result<Vec<std::path::PathBuf>> {
+    let mut files = Vec::new();
+    let mut shards = tokio::fs::read_dir(changes_path).await?;
+    while let Some(shard) = shards.next_entry().await? {
+        if !shard.file_type().await?.is_dir() || is_temp_name(&shard.file_name()) {
+            continue;
+        }
+        let mut entries = tokio::fs::read_dir(shard.path()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_file() && !is_temp_name(&entry.file_name()) {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Uses the same on disk layout as the blocking [`Persister`] implementation, so the two can be
+/// used interchangeably on the same directory.
+#[async_trait::async_trait]
+impl AsyncPersister for FsPersister {
+    type Error = FsPersisterError;
+
+    async fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        // cached changes, and those being flushed, take the place of any flushed copies. The
+        // flights can't stay locked while reading, so what they hold is copied out first.
+        let (mut changes, pending) = {
+            let flights = flight::lock(&self.flights);
+            let pending = self.pending_changes(&flights);
+            let changes = pending
+                .values()
+                .flatten()
+                .map(|c| c.to_vec())
+                .collect::<Vec<_>>();
+            (
+                changes,
+                pending.into_keys().cloned().collect::<HashSet<_>>(),
+            )
+        };
+        if let Some(log) = &self.segments {
+            changes.extend(log.read_changes_async(|key| pending.contains(key)).await?);
+            return Ok(changes);
+        }
+        let files = change_files(&self.changes_path)
+            .await?
+            .into_iter()
+            .filter(|path| !is_pending(path, |key| pending.contains(key)));
+        let stored: Vec<Vec<u8>> = futures::stream::iter(files)
+            .map(tokio::fs::read)
+            .buffer_unordered(CONCURRENT_READS)
+            .try_collect()
+            .await?;
+        changes.extend(stored);
+        Ok(changes)
+    }
+
+    async fn insert_changes(
+        &mut self,
+        changes: Vec<(ActorId, u64, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        for (a, s, c) in changes {
+            let key = (a, s);
+            let replaced = change_len(self, &key).await?;
+            if replaced.is_none() {
+                self.counts.changes += 1;
+            }
+            self.sizes.changes += c.len() as u64;
+            self.sizes.changes -= replaced.unwrap_or_default();
+            self.cache.removed_changes.remove(&key);
+            self.cache.changes.insert(key, c);
+        }
+        Ok(())
+    }
+
+    async fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.land_flights();
+        // see the blocking implementation, flushed copies are kept while a document is pending or
+        // anything is in flight
+        let defer = self.cache.document.is_some() || !flight::lock(&self.flights).is_empty();
+        let mut flushed = Vec::new();
+        for (a, s) in changes {
+            let key = (a.clone(), s);
+            if let Some(len) = change_len(self, &key).await? {
+                self.sizes.changes -= len;
+                self.counts.changes -= 1;
+            }
+            let in_flight = flight::lock(&self.flights).change(&key).is_some();
+            let stored = !self.cache.removed_changes.contains(&key)
+                && (in_flight || stored_change_len(self, &key).await?.is_some());
+            self.cache.changes.remove(&key);
+            if stored {
+                if defer {
+                    self.cache.removed_changes.insert(key);
+                } else {
+                    flushed.push((a, s));
+                }
+            }
+        }
+
+        if let Some(log) = &mut self.segments {
+            log.remove_changes_async(flushed, self.durability).await?;
+            return Ok(());
+        }
+
+        let mut shards = HashSet::new();
+        for (a, s) in flushed {
+            if remove_file(&make_changes_path(&self.changes_path, a, s))
+                .await?
+                .is_some()
+            {
+                shards.insert(make_shard_path(&self.changes_path, a, s));
+            }
+        }
+        if self.durability.sync_dirs() {
+            for shard in shards {
+                sync_dir_async(shard).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        let pending = {
+            let flights = flight::lock(&self.flights);
+            self.cache
+                .document
+                .as_ref()
+                .or_else(|| flights.document())
+                .cloned()
+        };
+        if let Some(doc) = pending {
+            return Ok(Some(doc));
+        }
+        read_optional(&self.doc_path).await
+    }
+
+    async fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        Persister::set_document(self, data)
+    }
+
+    async fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let pending = {
+            let flights = flight::lock(&self.flights);
+            (self.cache.sync_state(peer_id))
+                .or_else(|| flights.sync_state(peer_id))
+                .map(|sync_state| sync_state.cloned())
+        };
+        if let Some(sync_state) = pending {
+            return Ok(sync_state);
+        }
+        read_optional(&make_peer_path(&self.sync_states_path, peer_id)).await
+    }
+
+    async fn set_sync_state(
+        &mut self,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        let replaced = sync_state_len(self, &peer_id).await?;
+        self.peers
+            .record_async(&self.sync_states_path, &peer_id, self.durability)
+            .await?;
+        if replaced.is_none() {
+            self.counts.sync_states += 1;
+        }
+        self.sizes.sync_states += sync_state.len() as u64;
+        self.sizes.sync_states -= replaced.unwrap_or_default();
+        self.cache.removed_sync_states.remove(&peer_id);
+        self.cache.sync_states.insert(peer_id, sync_state);
+        Ok(())
+    }
+
+    async fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let mut removed = Vec::new();
+        let mut removed_files = false;
+        for peer_id in peer_ids {
+            if let Some(len) = sync_state_len(self, peer_id).await? {
+                self.sizes.sync_states -= len;
+                self.counts.sync_states -= 1;
+            }
+            self.cache.sync_states.remove(*peer_id);
+            let in_flight = flight::lock(&self.flights).sync_state(peer_id).is_some();
+            if in_flight {
+                // see the blocking implementation, the file is deleted by the next flush
+                self.cache.removed_sync_states.insert(peer_id.to_vec());
+                continue;
+            }
+            let path = make_peer_path(&self.sync_states_path, peer_id);
+            removed_files |= remove_file(&path).await?.is_some();
+            self.cache.removed_sync_states.remove(*peer_id);
+            removed.push(*peer_id);
+        }
+        if removed_files && self.durability.sync_dirs() {
+            sync_dir_async(self.sync_states_path.clone()).await?;
+        }
+        // see the blocking implementation, the files go first
+        self.peers
+            .forget_async(&self.sync_states_path, &removed, self.durability)
+            .await
+    }
+
+    async fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let (mut peer_ids, pending) = {
+            let flights = flight::lock(&self.flights);
+            let pending = self.pending_sync_states(&flights);
+            let peer_ids = pending
+                .iter()
+                .filter(|(_, sync_state)| sync_state.is_some())
+                .map(|(peer_id, _)| peer_id.to_vec())
+                .collect::<Vec<_>>();
+            let pending = pending
+                .into_keys()
+                .map(<[u8]>::to_vec)
+                .collect::<HashSet<_>>();
+            (peer_ids, pending)
+        };
+        let mut entries = tokio::fs::read_dir(&self.sync_states_path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            if !entry.file_type().await?.is_file() || is_temp_name(&name) || name == PEER_INDEX_FILE
+            {
+                continue;
+            }
+            let peer_id = self.peers.peer_id(&self.sync_states_path, &name)?;
+            if !pending.contains(&peer_id) {
+                peer_ids.push(peer_id);
+            }
+        }
+        Ok(peer_ids)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Writes through [`FsPersister::flush_cache_async`], so if this future is dropped part way
+    /// whatever it hadn't written is put back in the cache.
+    ///
+    /// With [`StorageMode::Segments`](crate::StorageMode::Segments) the removals of flushed
+    /// changes are then appended to the log, staying in the cache until that is done.
+    async fn flush(&mut self) -> Result<usize, Self::Error> {
+        let result = self.flush_cache_async().await;
+        self.land_flights();
+        let flushed = result?;
+        let Some(log) = &mut self.segments else {
+            return Ok(flushed);
+        };
+        let flights_empty = flight::lock(&self.flights).is_empty();
+        // until the document is on disk it may be all that covers the removed changes
+        if !flights_empty || self.cache.document.is_some() || self.cache.removed_changes.is_empty()
+        {
+            return Ok(flushed);
+        }
+        let mut failures = Failures::default();
+        let removed = self
+            .cache
+            .removed_changes
+            .iter()
+            .map(|(a, s)| (a, *s))
+            .collect();
+        match log.remove_changes_async(removed, self.durability).await {
+            Ok(()) => self.cache.removed_changes.clear(),
+            Err(e) => failures.extend(
+                self.cache.removed_changes.iter().cloned().map(removal_item),
+                &e,
+            ),
+        }
+        Ok(failures.into_result(flushed)?)
+    }
+}
@@ -0,0 +1,551 @@
+//! Crash tests, which interrupt writes at every point they change the filesystem and check what is
+//! found when the files are opened again, and tests of flushes that fail part way.
+
+use std::{
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use automerge::ActorId;
+use automerge_persistent::Persister;
+
+use crate::{
+    DirEntry, Durability, FileSystem, FlushItem, FsPersister, FsPersisterError, MemoryFileSystem,
+    Metadata, StorageMode, WalPersister,
+};
+
+const ROOT: &str = "/data";
+const PREFIX: &str = "doc";
+const WAL: &str = "/data/doc.wal";
+
+const EIO: i32 = 5;
+const EACCES: i32 = 13;
+const ENOSPC: i32 = 28;
+
+/// A filesystem that loses power part way through one of the operations that change it.
+///
+/// A write or append that is interrupted leaves the first half of its data behind, as though only
+/// some of its blocks reached the disk. Once crashed every operation fails, so nothing more can
+/// be written until the files are opened again through the underlying [`MemoryFileSystem`].
+#[derive(Debug, Clone)]
+struct Crashing {
+    fs: MemoryFileSystem,
+    /// The number of operations left before the crash, `None` once it has happened.
+    left: Arc<Mutex<Option<usize>>>,
+}
+
+impl Crashing {
+    fn new(fs: MemoryFileSystem, left: usize) -> Self {
+        Self {
+            fs,
+            left: Arc::new(Mutex::new(Some(left))),
+        }
+    }
+
+    fn crashed(&self) -> bool {
+        self.left.lock().unwrap().is_none()
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.crashed() {
+            return Err(io::Error::from_raw_os_error(EIO));
+        }
+        Ok(())
+    }
+
+    /// Count an operation that changes the filesystem, crashing after `torn` if it is the one
+    /// interrupted.
+    fn step<T: FnOnce()>(&self, torn: T) -> io::Result<()> {
+        let mut left = self.left.lock().unwrap();
+        match *left {
+            Some(0) => {
+                torn();
+                self.fs.crash();
+                *left = None;
+                Err(io::Error::from_raw_os_error(EIO))
+            }
+            Some(n) => {
+                *left = Some(n - 1);
+                Ok(())
+            }
+            None => Err(io::Error::from_raw_os_error(EIO)),
+        }
+    }
+}
+
+impl FileSystem for Crashing {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.check()?;
+        self.fs.read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.check()?;
+        self.fs.metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        self.check()?;
+        self.fs.read_dir(path)
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.step(|| {})?;
+        self.fs.create_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.step(|| {})?;
+        self.fs.create_dir_all(path)
+    }
+
+    fn write(&self, path: &Path, data: &[u8], durability: Durability) -> io::Result<()> {
+        self.step(|| {
+            let _ = self
+                .fs
+                .write(path, &data[..data.len() / 2], Durability::Full);
+        })?;
+        self.fs.write(path, data, durability)
+    }
+
+    fn append(&self, path: &Path, data: &[u8], durability: Durability) -> io::Result<()> {
+        self.step(|| {
+            let _ = self
+                .fs
+                .append(path, &data[..data.len() / 2], Durability::Full);
+        })?;
+        self.fs.append(path, data, durability)
+    }
+
+    fn truncate(&self, path: &Path, len: u64, durability: Durability) -> io::Result<()> {
+        self.step(|| {})?;
+        self.fs.truncate(path, len, durability)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.step(|| {})?;
+        self.fs.rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.step(|| {})?;
+        self.fs.remove_file(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.step(|| {})?;
+        self.fs.sync_dir(path)
+    }
+}
+
+/// Run `operation` on the files made by `setup`, crashing at each point where it changes the
+/// filesystem in turn, and `check` the files left behind.
+///
+/// `check` is told whether the operation finished, in which case everything it wrote should be
+/// there.
+fn crash_at_each_step<S, O, C>(setup: S, operation: O, check: C)
+where
+    S: Fn(&MemoryFileSystem),
+    O: Fn(Crashing) -> Result<(), FsPersisterError>,
+    C: Fn(&MemoryFileSystem, bool),
+{
+    for step in 0.. {
+        let fs = MemoryFileSystem::default();
+        setup(&fs);
+        let crashing = Crashing::new(fs.clone(), step);
+        let result = operation(crashing.clone());
+        let finished = !crashing.crashed();
+        if finished {
+            result.unwrap();
+            fs.crash();
+        }
+        check(&fs, finished);
+        if finished {
+            break;
+        }
+    }
+}
+
+fn actor() -> ActorId {
+    ActorId::from(&[1; 16][..])
+}
+
+fn open<F: FileSystem>(fs: F, mode: StorageMode) -> Result<FsPersister<F>, FsPersisterError> {
+    FsPersister::with_fs(fs, ROOT, PREFIX, mode, Durability::Full)
+}
+
+fn sorted_changes<P: Persister>(persister: &P) -> Vec<Vec<u8>> {
+    let mut changes = persister.get_changes().ok().unwrap();
+    changes.sort();
+    changes
+}
+
+/// Check that a persister opened after a crash can still be written to, and that what it writes
+/// is found when opening again.
+fn check_writable<P, O>(open: O)
+where
+    P: Persister,
+    O: Fn() -> P,
+{
+    let mut persister = open();
+    persister
+        .insert_changes(vec![(actor(), 9, vec![9])])
+        .ok()
+        .unwrap();
+    persister.flush().ok().unwrap();
+    let before = sorted_changes(&persister);
+    assert!(before.contains(&vec![9]));
+    assert_eq!(sorted_changes(&open()), before);
+}
+
+fn files_setup(mode: StorageMode) -> impl Fn(&MemoryFileSystem) {
+    move |fs| {
+        let mut persister = open(fs.clone(), mode).unwrap();
+        persister.set_document(vec![1]).unwrap();
+        persister
+            .insert_changes(vec![(actor(), 1, vec![1]), (actor(), 2, vec![2])])
+            .unwrap();
+        persister.set_sync_state(vec![1], vec![1]).unwrap();
+        persister.flush().unwrap();
+    }
+}
+
+/// Files are written to a temporary file and renamed into place, so each is either the old or
+/// the new one after a crash, never missing or part written.
+#[test]
+fn files_flush_is_atomic_per_file() {
+    let mode = StorageMode::Files;
+    crash_at_each_step(
+        files_setup(mode),
+        |fs| {
+            let mut persister = open(fs, mode)?;
+            persister.set_document(vec![2, 2])?;
+            persister.insert_changes(vec![(actor(), 3, vec![3, 3])])?;
+            persister.set_sync_state(vec![1], vec![2, 2])?;
+            persister.set_sync_state(vec![2], vec![2])?;
+            persister.flush()?;
+            Ok(())
+        },
+        |fs, finished| {
+            let persister = open(fs.clone(), mode).unwrap();
+            let document = persister.get_document().unwrap().unwrap();
+            assert!(document == vec![1] || document == vec![2, 2]);
+            let changes = sorted_changes(&persister);
+            assert!(
+                changes == vec![vec![1], vec![2]] || changes == vec![vec![1], vec![2], vec![3, 3]]
+            );
+            let sync_state = persister.get_sync_state(&[1]).unwrap().unwrap();
+            assert!(sync_state == vec![1] || sync_state == vec![2, 2]);
+            let added = persister.get_sync_state(&[2]).unwrap();
+            assert!(added.is_none() || added == Some(vec![2]));
+            if finished {
+                assert_eq!(document, vec![2, 2]);
+                assert_eq!(changes.len(), 3);
+                assert_eq!(sync_state, vec![2, 2]);
+                assert_eq!(added, Some(vec![2]));
+            }
+
+            // nothing left behind by an interrupted write is counted
+            let sizes = persister.sizes();
+            assert_eq!(sizes.document, document.len() as u64);
+            assert_eq!(
+                sizes.changes,
+                changes.iter().map(|c| c.len() as u64).sum::<u64>()
+            );
+            assert_eq!(
+                sizes.sync_states,
+                (sync_state.len() + added.map_or(0, |s| s.len())) as u64
+            );
+            check_writable(|| open(fs.clone(), mode).unwrap());
+        },
+    );
+}
+
+/// Compacting only removes changes once the document holding them is on disk.
+fn check_compaction(mode: StorageMode) {
+    crash_at_each_step(
+        files_setup(mode),
+        |fs| {
+            let mut persister = open(fs, mode)?;
+            persister.compact(vec![3, 3, 3], vec![(&actor(), 1)], &[&[1]])?;
+            persister.flush()?;
+            Ok(())
+        },
+        |fs, finished| {
+            let persister = open(fs.clone(), mode).unwrap();
+            let document = persister.get_document().unwrap().unwrap();
+            let changes = sorted_changes(&persister);
+            assert!(changes.contains(&vec![2]));
+            if document == vec![1] {
+                assert!(changes.contains(&vec![1]));
+            } else {
+                assert_eq!(document, vec![3, 3, 3]);
+            }
+            if finished {
+                assert_eq!(document, vec![3, 3, 3]);
+                assert_eq!(changes, vec![vec![2]]);
+                assert_eq!(persister.get_peer_ids().unwrap(), Vec::<Vec<u8>>::new());
+            }
+            check_writable(|| open(fs.clone(), mode).unwrap());
+        },
+    );
+}
+
+#[test]
+fn files_compaction_keeps_changes_until_document_is_written() {
+    check_compaction(StorageMode::Files);
+}
+
+#[test]
+fn segments_compaction_keeps_changes_until_document_is_written() {
+    check_compaction(StorageMode::Segments {
+        max_segment_size: 64,
+    });
+}
+
+/// An append interrupted part way leaves a torn record at the end of the active segment, which is
+/// cut off on open so that later appends follow the last complete record.
+#[test]
+fn segments_cut_off_torn_tails() {
+    // large enough for every record to go in the first segment
+    let mode = StorageMode::Segments {
+        max_segment_size: 1024,
+    };
+    crash_at_each_step(
+        |fs| {
+            let mut persister = open(fs.clone(), mode).unwrap();
+            persister
+                .insert_changes((1..=3).map(|s| (actor(), s, vec![s as u8; 8])).collect())
+                .unwrap();
+            persister.flush().unwrap();
+        },
+        |fs| {
+            // one record in each append, so an interrupted one leaves part of a record
+            let mut persister = open(fs, mode)?;
+            persister.insert_changes(vec![(actor(), 4, vec![4; 8])])?;
+            persister.flush()?;
+            persister.insert_changes(vec![(actor(), 5, vec![5; 8])])?;
+            persister.flush()?;
+            persister.remove_changes(vec![(&actor(), 1)])?;
+            persister.flush()?;
+            Ok(())
+        },
+        |fs, finished| {
+            let persister = open(fs.clone(), mode).unwrap();
+            let changes = sorted_changes(&persister);
+            // each append either lands whole or is cut off, so the changes are as they were after
+            // one of them
+            let history = [
+                &[1, 2, 3][..],
+                &[1, 2, 3, 4],
+                &[1, 2, 3, 4, 5],
+                &[2, 3, 4, 5],
+            ]
+            .iter()
+            .map(|seqs| seqs.iter().map(|s| vec![*s; 8]).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+            assert!(history.contains(&changes));
+            if finished {
+                assert_eq!(changes, history[3]);
+            }
+            check_writable(|| open(fs.clone(), mode).unwrap());
+        },
+    );
+}
+
+fn wal_setup(fs: &MemoryFileSystem) {
+    let mut persister = WalPersister::with_fs(fs.clone(), WAL, Durability::Full).unwrap();
+    persister.set_document(vec![1]).unwrap();
+    persister
+        .insert_changes(vec![(actor(), 1, vec![1]), (actor(), 2, vec![2])])
+        .unwrap();
+    persister.set_sync_state(vec![1], vec![1]).unwrap();
+    persister.flush().unwrap();
+}
+
+fn open_wal(fs: &MemoryFileSystem) -> WalPersister<MemoryFileSystem> {
+    WalPersister::with_fs(fs.clone(), WAL, Durability::Full).unwrap()
+}
+
+/// A torn append at the end of the log is cut off, keeping the records before it.
+#[test]
+fn wal_cuts_off_torn_appends() {
+    crash_at_each_step(
+        wal_setup,
+        |fs| {
+            let mut persister = WalPersister::with_fs(fs, WAL, Durability::Full)?;
+            persister.insert_changes(vec![(actor(), 3, vec![3; 8])])?;
+            persister.set_sync_state(vec![1], vec![2; 8])?;
+            persister.set_document(vec![2; 8])?;
+            persister.flush()?;
+            Ok(())
+        },
+        |fs, finished| {
+            let persister = open_wal(fs);
+            let changes = sorted_changes(&persister);
+            let sync_state = persister.get_sync_state(&[1]).unwrap().unwrap();
+            let document = persister.get_document().unwrap().unwrap();
+            assert!(changes == vec![vec![1], vec![2]] || changes.len() == 3);
+            assert!(sync_state == vec![1] || sync_state == vec![2; 8]);
+            assert!(document == vec![1] || document == vec![2; 8]);
+            // records are replayed in order, so later ones are only there with those before
+            if document == vec![2; 8] {
+                assert_eq!(sync_state, vec![2; 8]);
+            }
+            if sync_state == vec![2; 8] {
+                assert_eq!(changes.len(), 3);
+            }
+            if finished {
+                assert_eq!(document, vec![2; 8]);
+            }
+            assert_eq!(
+                persister.log_len(),
+                fs.read(Path::new(WAL)).unwrap().len() as u64
+            );
+            check_writable(|| open_wal(fs));
+        },
+    );
+}
+
+/// Compacting replaces the log atomically, so either all or none of it is seen.
+#[test]
+fn wal_compaction_is_atomic() {
+    crash_at_each_step(
+        wal_setup,
+        |fs| {
+            let mut persister = WalPersister::with_fs(fs, WAL, Durability::Full)?;
+            persister.compact(vec![3, 3, 3], vec![(&actor(), 1)], &[&[1]])?;
+            Ok(())
+        },
+        |fs, finished| {
+            let persister = open_wal(fs);
+            let document = persister.get_document().unwrap().unwrap();
+            let changes = sorted_changes(&persister);
+            let sync_state = persister.get_sync_state(&[1]).unwrap();
+            if document == vec![1] {
+                assert!(!finished);
+                assert_eq!(changes, vec![vec![1], vec![2]]);
+                assert_eq!(sync_state, Some(vec![1]));
+            } else {
+                assert_eq!(document, vec![3, 3, 3]);
+                assert_eq!(changes, vec![vec![2]]);
+                assert_eq!(sync_state, None);
+            }
+            check_writable(|| open_wal(fs));
+        },
+    );
+}
+
+/// Flush a document, a change and a sync state into `fs` after `fault` is injected, checking
+/// that each is reported as failed with `errno` and kept in the cache, and that they are written
+/// by a second flush once `clear` has removed the fault.
+fn check_fault<F, C>(mode: StorageMode, fault: F, errno: i32, clear: C)
+where
+    F: Fn(&MemoryFileSystem),
+    C: Fn(&MemoryFileSystem),
+{
+    let fs = MemoryFileSystem::default();
+    files_setup(mode)(&fs);
+    let mut persister = open(fs.clone(), mode).unwrap();
+    persister.set_document(vec![2; 64]).unwrap();
+    persister
+        .insert_changes(vec![(actor(), 3, vec![3; 64])])
+        .unwrap();
+    persister.set_sync_state(vec![2], vec![2; 64]).unwrap();
+
+    fault(&fs);
+    let error = match persister.flush() {
+        Err(FsPersisterError::Flush(error)) => error,
+        result => panic!("expected a flush error, got {:?}", result),
+    };
+    let expected = [
+        FlushItem::Document,
+        FlushItem::Change(actor(), 3),
+        FlushItem::SyncState(vec![2]),
+    ];
+    assert_eq!(error.failed.len(), expected.len(), "{:?}", error.failed);
+    for (item, e) in &error.failed {
+        assert!(expected.contains(item), "unexpected failure of {}", item);
+        assert_eq!(e.raw_os_error(), Some(errno), "{}: {}", item, e);
+    }
+
+    // the failed items are still read from the cache
+    assert_eq!(persister.get_document().unwrap(), Some(vec![2; 64]));
+    assert!(sorted_changes(&persister).contains(&vec![3; 64]));
+    assert_eq!(persister.get_sync_state(&[2]).unwrap(), Some(vec![2; 64]));
+
+    clear(&fs);
+    persister.flush().unwrap();
+    fs.crash();
+    let reopened = open(fs, mode).unwrap();
+    assert_eq!(reopened.get_document().unwrap(), Some(vec![2; 64]));
+    assert_eq!(
+        sorted_changes(&reopened),
+        vec![vec![1], vec![2], vec![3; 64]]
+    );
+    assert_eq!(reopened.get_sync_state(&[1]).unwrap(), Some(vec![1]));
+    assert_eq!(reopened.get_sync_state(&[2]).unwrap(), Some(vec![2; 64]));
+}
+
+fn check_full_disk(mode: StorageMode) {
+    check_fault(
+        mode,
+        // every write goes over a capacity of nothing
+        |fs| fs.set_capacity(Some(0)),
+        ENOSPC,
+        |fs| fs.set_capacity(None),
+    );
+}
+
+fn check_read_only(mode: StorageMode) {
+    check_fault(
+        mode,
+        |fs| fs.set_read_only(ROOT, true),
+        EACCES,
+        |fs| fs.set_read_only(ROOT, false),
+    );
+}
+
+fn check_failing_syncs(mode: StorageMode) {
+    check_fault(
+        mode,
+        |fs| fs.set_fail_syncs(true),
+        EIO,
+        |fs| fs.set_fail_syncs(false),
+    );
+}
+
+const SEGMENTS: StorageMode = StorageMode::Segments {
+    max_segment_size: 1024,
+};
+
+#[test]
+fn files_flush_keeps_items_on_full_disk() {
+    check_full_disk(StorageMode::Files);
+}
+
+#[test]
+fn segments_flush_keeps_items_on_full_disk() {
+    check_full_disk(SEGMENTS);
+}
+
+#[test]
+fn files_flush_keeps_items_when_read_only() {
+    check_read_only(StorageMode::Files);
+}
+
+#[test]
+fn segments_flush_keeps_items_when_read_only() {
+    check_read_only(SEGMENTS);
+}
+
+#[test]
+fn files_flush_keeps_items_when_syncs_fail() {
+    check_failing_syncs(StorageMode::Files);
+}
+
+#[test]
+fn segments_flush_keeps_items_when_syncs_fail() {
+    check_failing_syncs(SEGMENTS);
+}
@@ -0,0 +1,142 @@
+use std::{
+    ffi::{OsStr, OsString},
+    io,
+    os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use crate::FileSystem;
+
+/// How hard the persister tries to make flushed data survive a crash or power loss.
+///
+/// Every file is written to a temporary file first and renamed into place, so a crash never leaves
+/// a partially written file behind. The level controls which `fsync` calls are made around that.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Never fsync, leave it to the operating system to write data out eventually.
+    None,
+    /// Fsync the contents of each file before it is renamed into place.
+    Data,
+    /// Fsync file contents and metadata, and the containing directories after renaming so that
+    /// the new entries are durable too.
+    #[default]
+    Full,
+}
+
+impl Durability {
+    /// Whether directories should be synced after entries in them change.
+    pub(crate) fn sync_dirs(self) -> bool {
+        self == Self::Full
+    }
+}
+
+/// The suffix given to temporary files, these are ignored when reading and cleaned up on open.
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// Whether the given file name is one of our temporary (or otherwise hidden) files.
+pub(crate) fn is_temp_name(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(b".")
+}
+
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(TEMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Write `data` to `path` atomically, syncing according to `durability`.
+///
+/// This does not sync the parent directory, callers batch that up with
+/// [`FileSystem::sync_dir`].
+pub(crate) fn write_file<F: FileSystem>(
+    fs: &F,
+    path: &Path,
+    data: &[u8],
+    durability: Durability,
+) -> io::Result<()> {
+    let tmp = temp_path(path);
+    let result = fs
+        .write(&tmp, data, durability)
+        .and_then(|()| fs.rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs.remove_file(&tmp);
+    }
+    result
+}
+
+/// Create a directory and any missing parents, syncing the parent of each one created when
+/// `durability` asks for directories to be synced.
+pub(crate) fn create_dirs<F: FileSystem>(
+    fs: &F,
+    path: &Path,
+    durability: Durability,
+) -> io::Result<()> {
+    let mut missing = Vec::new();
+    for dir in path.ancestors() {
+        match fs.metadata(dir) {
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => missing.push(dir),
+            Err(e) => return Err(e),
+        }
+    }
+    for dir in missing.into_iter().rev() {
+        match fs.create_dir(dir) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+        if durability.sync_dirs() {
+            if let Some(parent) = dir.parent() {
+                fs.sync_dir(parent)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove any temporary files left behind in `dir` by an interrupted write.
+pub(crate) fn remove_temp_files<F: FileSystem>(fs: &F, dir: &Path) -> io::Result<()> {
+    for entry in fs.read_dir(dir)? {
+        if is_temp_name(&entry.name)
+            && entry.name.as_bytes().ends_with(TEMP_SUFFIX.as_bytes())
+            && !entry.is_dir
+        {
+            fs.remove_file(&dir.join(&entry.name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Write `data` to `path` atomically, syncing according to `durability`.
+#[cfg(feature = "async")]
+pub(crate) async fn write_file_async(
+    path: PathBuf,
+    data: &[u8],
+    durability: Durability,
+) -> io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let tmp = temp_path(&path);
+    let result = async {
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(data).await?;
+        match durability {
+            Durability::None => {}
+            Durability::Data => file.sync_data().await?,
+            Durability::Full => file.sync_all().await?,
+        }
+        tokio::fs::rename(&tmp, &path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    result
+}
+
+/// Sync a directory so that entries created, renamed or removed in it are durable.
+#[cfg(feature = "async")]
+pub(crate) async fn sync_dir_async(path: PathBuf) -> io::Result<()> {
+    tokio::fs::File::open(path).await?.sync_all().await
+}
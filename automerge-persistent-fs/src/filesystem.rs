@@ -0,0 +1,154 @@
+//! The filesystem operations [`FsPersister`](crate::FsPersister) is built on.
+
+use std::{
+    ffi::OsString,
+    fmt, fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::Durability;
+
+/// What [`FileSystem::metadata`] reports about a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// The length of the file, in bytes.
+    pub len: u64,
+    /// Whether the path is a directory rather than a file.
+    pub is_dir: bool,
+}
+
+impl Metadata {
+    pub const fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+}
+
+/// An entry returned by [`FileSystem::read_dir`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: OsString,
+    pub is_dir: bool,
+}
+
+/// The filesystem operations that an [`FsPersister`](crate::FsPersister) needs.
+///
+/// Errors should be reported as the equivalent `std::fs` call would, the persister relies on
+/// [`io::ErrorKind::NotFound`] and [`io::ErrorKind::AlreadyExists`] in particular.
+pub trait FileSystem: fmt::Debug {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>>;
+
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Create or truncate the file at `path` and write `data` to it, syncing the file according
+    /// to `durability`.
+    fn write(&self, path: &Path, data: &[u8], durability: Durability) -> io::Result<()>;
+
+    /// Append `data` to the file at `path`, creating it if needed, and sync it according to
+    /// `durability`.
+    ///
+    /// If this fails the file should be left at its previous length.
+    fn append(&self, path: &Path, data: &[u8], durability: Durability) -> io::Result<()>;
+
+    /// Cut the file at `path` down to `len` bytes, and sync it according to `durability`.
+    fn truncate(&self, path: &Path, len: u64, durability: Durability) -> io::Result<()>;
+
+    /// Rename the file at `from` to `to`, replacing any file already there.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Sync a directory so that entries created, renamed or removed in it are durable.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+}
+
+/// The real filesystem, through `std::fs`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StdFileSystem;
+
+fn sync_file(file: &fs::File, durability: Durability) -> io::Result<()> {
+    match durability {
+        Durability::None => Ok(()),
+        Durability::Data => file.sync_data(),
+        Durability::Full => file.sync_all(),
+    }
+}
+
+impl FileSystem for StdFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let meta = fs::metadata(path)?;
+        Ok(Metadata {
+            len: meta.len(),
+            is_dir: meta.is_dir(),
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        fs::read_dir(path)?
+            .map(|entry| {
+                let entry = entry?;
+                Ok(DirEntry {
+                    name: entry.file_name(),
+                    is_dir: entry.file_type()?.is_dir(),
+                })
+            })
+            .collect()
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn write(&self, path: &Path, data: &[u8], durability: Durability) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        file.write_all(data)?;
+        sync_file(&file, durability)
+    }
+
+    fn append(&self, path: &Path, data: &[u8], durability: Durability) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let base = file.metadata()?.len();
+        let result = file
+            .write_all(data)
+            .and_then(|()| sync_file(&file, durability));
+        if result.is_err() {
+            let _ = file.set_len(base);
+        }
+        result
+    }
+
+    fn truncate(&self, path: &Path, len: u64, durability: Durability) -> io::Result<()> {
+        let file = fs::OpenOptions::new().write(true).open(path)?;
+        file.set_len(len)?;
+        sync_file(&file, durability)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        fs::File::open(path)?.sync_all()
+    }
+}
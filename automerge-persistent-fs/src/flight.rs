@@ -0,0 +1,184 @@
+//! Caches being written by the futures from
+//! [`FsPersister::flush_cache_async`](crate::FsPersister::flush_cache_async).
+//!
+//! Each flight stays readable through the persister until it has been applied, that is until the
+//! future writing it has landed it and the persister has committed what it appended to the segment
+//! log and put back whatever couldn't be written. Flights are applied in the order they were
+//! started.
+//!
+//! No item is in more than one flight at once, anything a flight holds is left in the
+//! persister's cache by later flushes until the flight has been applied. This keeps writes of the
+//! same item in order, and a flight never needs to consider those after it.
+
+// flights are only started with the async feature, without it there are never any
+#![cfg_attr(not(feature = "async"), allow(dead_code))]
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use automerge::ActorId;
+
+use crate::{segment::Append, FsPersisterCache};
+#[cfg(feature = "async")]
+use crate::{FsPersister, Written};
+
+/// The flights of a persister, shared with the futures writing them.
+pub(crate) type SharedFlights = Arc<Mutex<Flights>>;
+
+/// Lock the flights.
+///
+/// Every update is made in one step, so if a future panicked while holding the lock what it
+/// guards is still consistent.
+pub(crate) fn lock(flights: &SharedFlights) -> MutexGuard<'_, Flights> {
+    flights.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Flights {
+    next: u64,
+    flights: BTreeMap<u64, Flight>,
+}
+
+#[derive(Debug)]
+struct Flight {
+    /// What is being written, cut down to what is left once the flight has landed.
+    cache: Arc<FsPersisterCache>,
+    /// Whether the flight appends to the segment log, which nothing else may do until it has
+    /// been applied.
+    writes_log: bool,
+    /// Set once the future writing the flight has finished, or been dropped.
+    landed: Option<Landed>,
+}
+
+/// How a flight's append to the segment log went.
+#[derive(Debug)]
+pub(crate) struct Landed {
+    /// The append, if it was written and so needs committing to the log.
+    pub(crate) append: Option<Append>,
+    /// Whether an append was started but not finished, leaving part of it in the segment.
+    pub(crate) torn: bool,
+}
+
+impl Flights {
+    /// Start a flight writing `cache`, returning its id.
+    pub(crate) fn start(&mut self, cache: Arc<FsPersisterCache>, writes_log: bool) -> u64 {
+        let id = self.next;
+        self.next += 1;
+        self.flights.insert(
+            id,
+            Flight {
+                cache,
+                writes_log,
+                landed: None,
+            },
+        );
+        id
+    }
+
+    /// Record that the flight `id` has finished, dropping what it wrote from its cache.
+    ///
+    /// Changes appended to the segment log are kept until the append is committed, as until then
+    /// the log can't find them.
+    #[cfg(feature = "async")]
+    fn land(&mut self, id: u64, written: Written, landed: Landed) {
+        if let Some(flight) = self.flights.get_mut(&id) {
+            Arc::make_mut(&mut flight.cache).forget(written);
+            flight.landed = Some(landed);
+        }
+    }
+
+    /// Take the oldest flight if it has landed, returning what is left of its cache along with
+    /// how its append went.
+    pub(crate) fn pop_landed(&mut self) -> Option<(FsPersisterCache, Landed)> {
+        let mut entry = self.flights.first_entry()?;
+        let landed = entry.get_mut().landed.take()?;
+        let cache = entry.remove().cache;
+        let cache = Arc::try_unwrap(cache).unwrap_or_else(|cache| (*cache).clone());
+        Some((cache, landed))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.flights.is_empty()
+    }
+
+    /// Whether a flight appends to the segment log.
+    pub(crate) fn log_busy(&self) -> bool {
+        self.flights.values().any(|flight| flight.writes_log)
+    }
+
+    /// The caches of the flights, oldest first.
+    pub(crate) fn caches(&self) -> impl DoubleEndedIterator<Item = &FsPersisterCache> {
+        self.flights.values().map(|flight| &*flight.cache)
+    }
+
+    /// The document held by a flight.
+    pub(crate) fn document(&self) -> Option<&Vec<u8>> {
+        self.caches()
+            .rev()
+            .find_map(|cache| cache.document.as_ref())
+    }
+
+    /// The change `key` held by a flight, see [`FsPersisterCache::change`].
+    pub(crate) fn change(&self, key: &(ActorId, u64)) -> Option<Option<&Vec<u8>>> {
+        self.caches().rev().find_map(|cache| cache.change(key))
+    }
+
+    /// The sync state for `peer_id` held by a flight, see [`FsPersisterCache::sync_state`].
+    pub(crate) fn sync_state(&self, peer_id: &[u8]) -> Option<Option<&Vec<u8>>> {
+        self.caches()
+            .rev()
+            .find_map(|cache| cache.sync_state(peer_id))
+    }
+}
+
+/// Lands a flight when dropped, whether its future finished or was dropped part way through.
+#[cfg(feature = "async")]
+pub(crate) struct Landing {
+    flights: SharedFlights,
+    id: u64,
+    pub(crate) cache: Arc<FsPersisterCache>,
+    /// What has been written so far.
+    pub(crate) written: Written,
+    /// The segment log append to write, if there is one.
+    pub(crate) append: Option<Append>,
+    pub(crate) appended: bool,
+}
+
+#[cfg(feature = "async")]
+impl Landing {
+    /// Start a flight writing `cache` for `persister`, along with `append` to the segment log.
+    pub(crate) fn start<F>(
+        persister: &FsPersister<F>,
+        cache: FsPersisterCache,
+        append: Option<Append>,
+    ) -> Self {
+        let cache = Arc::new(cache);
+        let flights = Arc::clone(&persister.flights);
+        let id = lock(&flights).start(Arc::clone(&cache), append.is_some());
+        Self {
+            flights,
+            id,
+            cache,
+            written: Written::default(),
+            append,
+            appended: false,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for Landing {
+    fn drop(&mut self) {
+        // let go of the cache so that the flight's copy can be cut down without cloning it
+        self.cache = Arc::default();
+        let append = self.append.take();
+        let landed = Landed {
+            torn: append.is_some() && !self.appended,
+            append: append.filter(|_| self.appended),
+        };
+        let written = std::mem::take(&mut self.written);
+        lock(&self.flights).land(self.id, written, landed);
+    }
+}
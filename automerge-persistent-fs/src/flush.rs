@@ -0,0 +1,90 @@
+//! Reporting on flushes that could not write everything.
+
+use std::{fmt, io};
+
+use automerge::ActorId;
+
+/// Something held in the cache waiting to be flushed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlushItem {
+    Document,
+    Change(ActorId, u64),
+    /// The deletion of a change that was removed while a document was pending.
+    Removal(ActorId, u64),
+    SyncState(Vec<u8>),
+    /// The deletion of a sync state that was removed while it was being flushed.
+    SyncStateRemoval(Vec<u8>),
+}
+
+impl fmt::Display for FlushItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Document => write!(f, "document"),
+            Self::Change(actor, seq) => write!(f, "change {}-{}", actor, seq),
+            Self::Removal(actor, seq) => write!(f, "removal of change {}-{}", actor, seq),
+            Self::SyncState(peer_id) => write!(f, "sync state for {}", hex::encode(peer_id)),
+            Self::SyncStateRemoval(peer_id) => {
+                write!(f, "removal of sync state for {}", hex::encode(peer_id))
+            }
+        }
+    }
+}
+
+/// A flush that failed to write some items.
+///
+/// The items that failed are left in the cache, so flushing again retries just those.
+#[derive(Debug)]
+pub struct FlushError {
+    /// The number of bytes that were flushed.
+    pub flushed: usize,
+    /// The items that could not be written and why.
+    pub failed: Vec<(FlushItem, io::Error)>,
+}
+
+impl fmt::Display for FlushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to flush {} item(s)", self.failed.len())?;
+        if let Some((item, error)) = self.failed.first() {
+            write!(f, ", {}: {}", item, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for FlushError {}
+
+/// Collects the items that failed during a flush.
+#[derive(Debug, Default)]
+pub(crate) struct Failures(Vec<(FlushItem, io::Error)>);
+
+impl Failures {
+    pub(crate) fn push(&mut self, item: FlushItem, error: io::Error) {
+        self.0.push((item, error));
+    }
+
+    /// Fail a group of items with the same error, such as when syncing their directory failed.
+    pub(crate) fn extend<I>(&mut self, items: I, error: &io::Error)
+    where
+        I: IntoIterator<Item = FlushItem>,
+    {
+        for item in items {
+            // io::Error isn't Clone, keep the OS error code where there is one
+            let error = error.raw_os_error().map_or_else(
+                || io::Error::new(error.kind(), error.to_string()),
+                io::Error::from_raw_os_error,
+            );
+            self.push(item, error);
+        }
+    }
+
+    pub(crate) fn into_result(self, flushed: usize) -> Result<usize, FlushError> {
+        if self.0.is_empty() {
+            Ok(flushed)
+        } else {
+            Err(FlushError {
+                flushed,
+                failed: self.0,
+            })
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+};
+
+use automerge::ActorId;
+
+use crate::{durability::is_temp_name, Durability, FileSystem, FsPersisterError};
+
+/// The number of shard directories that change files are spread over.
+///
+/// Keeping directories small keeps lookups and listings fast on filesystems that degrade with
+/// large directories.
+const CHANGE_SHARDS: u64 = 256;
+
+/// A stable 64 bit FNV-1a hash, used to pick the shard for a change.
+///
+/// This must never change as it determines where existing changes live on disk.
+fn fnv1a(chunks: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for chunk in chunks {
+        for byte in *chunk {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// The name of the shard directory that the change with the given actor and seq lives in.
+fn shard_name(actor: &[u8], seq: u64) -> String {
+    format!(
+        "{:02x}",
+        fnv1a(&[actor, &seq.to_be_bytes()]) % CHANGE_SHARDS
+    )
+}
+
+/// The directory the change with the given `actor_id` and `seq` is stored in.
+pub(crate) fn make_shard_path<P: AsRef<Path>>(
+    changes_path: P,
+    actor_id: &ActorId,
+    seq: u64,
+) -> PathBuf {
+    changes_path
+        .as_ref()
+        .join(shard_name(actor_id.to_bytes(), seq))
+}
+
+/// The file the change with the given `actor_id` and `seq` is stored in.
+pub(crate) fn make_changes_path<P: AsRef<Path>>(
+    changes_path: P,
+    actor_id: &ActorId,
+    seq: u64,
+) -> PathBuf {
+    make_shard_path(&changes_path, actor_id, seq).join(change_file_name(actor_id.to_bytes(), seq))
+}
+
+fn change_file_name(actor: &[u8], seq: u64) -> String {
+    format!("{}-{}", hex::encode(actor), seq)
+}
+
+/// The actor and seq of the change stored in the file at `path`.
+pub(crate) fn change_key(path: &Path) -> Option<(ActorId, u64)> {
+    parse_change_file_name(path).map(|(actor, seq)| (ActorId::from(actor), seq))
+}
+
+/// Parse a change file name back into the actor bytes and seq.
+fn parse_change_file_name(path: &Path) -> Option<(Vec<u8>, u64)> {
+    let name = path.file_name()?.to_str()?;
+    let (actor, seq) = name.rsplit_once('-')?;
+    Some((hex::decode(actor).ok()?, seq.parse().ok()?))
+}
+
+/// Group change keys by the shard directory they are stored in.
+pub(crate) fn shard_groups<'a, I>(
+    changes_path: &Path,
+    keys: I,
+) -> HashMap<PathBuf, Vec<(ActorId, u64)>>
+where
+    I: IntoIterator<Item = &'a (ActorId, u64)>,
+{
+    let mut groups: HashMap<PathBuf, Vec<(ActorId, u64)>> = HashMap::new();
+    for (actor, seq) in keys {
+        groups
+            .entry(make_shard_path(changes_path, actor, *seq))
+            .or_default()
+            .push((actor.clone(), *seq));
+    }
+    groups
+}
+
+/// Create the directory if it doesn't exist, returning whether it was created.
+pub(crate) fn ensure_dir<F: FileSystem>(fs: &F, path: &Path) -> io::Result<bool> {
+    match fs.create_dir(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Collect the paths of all change files in the sharded layout.
+pub(crate) fn change_files<F: FileSystem>(fs: &F, changes_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for shard in fs.read_dir(changes_path)? {
+        if !shard.is_dir || is_temp_name(&shard.name) {
+            continue;
+        }
+        let shard_path = changes_path.join(&shard.name);
+        for entry in fs.read_dir(&shard_path)? {
+            if !entry.is_dir && !is_temp_name(&entry.name) {
+                files.push(shard_path.join(&entry.name));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Move change files from the old flat layout, where every change lived directly in the changes
+/// directory, into their shard directories.
+///
+/// Files are moved with a rename so an interrupted migration leaves every change in exactly one
+/// of the two places and is simply continued on the next open.
+///
+/// Returns the number of files moved.
+pub(crate) fn migrate_flat_layout<F: FileSystem>(
+    fs: &F,
+    changes_path: &Path,
+    durability: Durability,
+) -> Result<usize, FsPersisterError> {
+    let mut touched = HashSet::new();
+    let mut moved = 0;
+    for entry in fs.read_dir(changes_path)? {
+        if entry.is_dir || is_temp_name(&entry.name) {
+            continue;
+        }
+        let path = changes_path.join(&entry.name);
+        let (actor, seq) = parse_change_file_name(&path)
+            .ok_or_else(|| FsPersisterError::UnexpectedFile(path.clone()))?;
+        let shard = changes_path.join(shard_name(&actor, seq));
+        ensure_dir(fs, &shard)?;
+        fs.rename(&path, &shard.join(&entry.name))?;
+        touched.insert(shard);
+        moved += 1;
+    }
+    if moved > 0 && durability.sync_dirs() {
+        for shard in &touched {
+            fs.sync_dir(shard)?;
+        }
+        fs.sync_dir(changes_path)?;
+    }
+    Ok(moved)
+}
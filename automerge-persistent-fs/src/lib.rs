@@ -1,139 +1,638 @@
+#[cfg(feature = "async")]
+mod async_persister;
+#[cfg(test)]
+mod crash;
+mod durability;
+mod filesystem;
+mod flight;
+mod flush;
+mod layout;
+mod memfs;
+mod peers;
+mod record;
+mod segment;
+mod wal;
+#[cfg(feature = "watch")]
+mod watch;
+
 use std::{
-    collections::HashMap,
-    fs,
-    os::unix::prelude::OsStrExt,
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
 };
 
 use automerge::ActorId;
-use automerge_persistent::{Persister, StoredSizes};
+use automerge_persistent::{Persister, StoredCounts, StoredSizes};
+pub use durability::Durability;
+use durability::{create_dirs, is_temp_name, remove_temp_files, write_file};
+#[cfg(feature = "async")]
+use durability::{sync_dir_async, write_file_async};
+pub use filesystem::{DirEntry, FileSystem, Metadata, StdFileSystem};
 #[cfg(feature = "async")]
-use futures::{Future, FutureExt, TryStreamExt};
+use flight::Landing;
+use flight::{Flights, SharedFlights};
+use flush::Failures;
+pub use flush::{FlushError, FlushItem};
+#[cfg(feature = "async")]
+use futures::{Future, StreamExt};
 use hex::FromHexError;
+use layout::{
+    change_files, change_key, ensure_dir, make_changes_path, make_shard_path, migrate_flat_layout,
+    shard_groups,
+};
+pub use memfs::MemoryFileSystem;
+use peers::{make_peer_path, PeerIndex, PEER_INDEX_FILE};
+use segment::SegmentLog;
+pub use segment::DEFAULT_SEGMENT_SIZE;
+pub use wal::WalPersister;
+#[cfg(feature = "watch")]
+pub use watch::{FsWatchEvent, FsWatcher, FsWatcherError};
 
+/// Stores changes, documents and sync states as files under a directory.
+///
+/// By default this uses the real filesystem, [`FsPersister::with_fs`] can be used to run it on
+/// another [`FileSystem`], such as a [`MemoryFileSystem`] in tests.
+///
+/// Sizes and counts are worked out when opening and kept up to date as things are flushed. No
+/// record of them is kept on disk, unlike with `SledPersister` which updates one in the same
+/// transaction as each write. Without a way to lock the directory, a record here could only be
+/// kept in step with other processes and with crashes part way through a flush by syncing it
+/// around every flush. So opening lists every change and sync state file and adds up their
+/// lengths, without reading them. With [`StorageMode::Segments`] the change lengths come from
+/// the index built by replaying the log.
 #[derive(Debug)]
-pub struct FsPersister {
+pub struct FsPersister<F = StdFileSystem> {
+    fs: F,
     changes_path: PathBuf,
     doc_path: PathBuf,
     sync_states_path: PathBuf,
+    /// The long peer ids whose sync states are stored under hashed file names.
+    peers: PeerIndex,
+    /// The segment log changes are stored in when using [`StorageMode::Segments`].
+    segments: Option<SegmentLog>,
     cache: FsPersisterCache,
+    /// Caches being written by futures from [`FsPersister::flush_cache_async`].
+    flights: SharedFlights,
     sizes: StoredSizes,
+    counts: StoredCounts,
+    durability: Durability,
 }
 
-#[derive(Debug)]
+/// How changes are laid out on disk.
+///
+/// A directory must always be opened with the mode it was created with, opening it with the other
+/// returns [`FsPersisterError::StorageModeMismatch`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+    /// Each change is stored in its own file, spread over sharded directories.
+    #[default]
+    Files,
+    /// Changes are appended to rolling segment files, a new segment is started once the current
+    /// one reaches `max_segment_size` bytes.
+    ///
+    /// This avoids creating a file per change and makes loading a few large sequential reads.
+    Segments {
+        /// The size a segment can grow to before a new one is started.
+        max_segment_size: u64,
+    },
+}
+
+#[derive(Debug, Default, Clone)]
 pub struct FsPersisterCache {
     changes: HashMap<(ActorId, u64), Vec<u8>>,
     document: Option<Vec<u8>>,
     sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    /// Flushed changes that have been removed while a document was pending. They are only
+    /// deleted from disk once that document has been flushed, so a crash never leaves neither.
+    removed_changes: HashSet<(ActorId, u64)>,
+    /// Sync states that have been removed while they were being flushed, so that their files are
+    /// deleted once that flush has finished.
+    removed_sync_states: HashSet<Vec<u8>>,
+    /// Whether shard directories have been created without the changes directory being synced,
+    /// so that the next flush of changes syncs it even though the shards already exist.
+    unsynced_shards: bool,
+}
+
+/// The parts of a cache that have been written by a flush.
+#[cfg(feature = "async")]
+#[derive(Debug, Default)]
+struct Written {
+    document: bool,
+    changes: Vec<(ActorId, u64)>,
+    removed_changes: Vec<(ActorId, u64)>,
+    sync_states: Vec<Vec<u8>>,
+    removed_sync_states: Vec<Vec<u8>>,
+    /// Whether shard directories are left unsynced, `None` if the changes weren't written.
+    unsynced_shards: Option<bool>,
 }
 
 impl FsPersisterCache {
-    fn flush_changes(&mut self, changes_path: PathBuf) -> Result<usize, std::io::Error> {
-        let mut flushed = 0;
-        for ((a, s), c) in self.changes.drain() {
-            fs::write(make_changes_path(&changes_path, &a, s), &c)?;
-            flushed += c.len();
+    /// Whether the flushed copy of a change is hidden, by a newer cached copy or a pending
+    /// removal.
+    fn shadows(&self, key: &(ActorId, u64)) -> bool {
+        self.changes.contains_key(key) || self.removed_changes.contains(key)
+    }
+
+    /// The cached copy of a change, `Some(None)` if it has been removed and `None` if the cache
+    /// doesn't know about it.
+    fn change(&self, key: &(ActorId, u64)) -> Option<Option<&Vec<u8>>> {
+        match self.changes.get(key) {
+            Some(change) => Some(Some(change)),
+            None if self.removed_changes.contains(key) => Some(None),
+            None => None,
         }
-        Ok(flushed)
     }
 
-    fn flush_document(&mut self, doc_path: PathBuf) -> Result<usize, std::io::Error> {
-        let mut flushed = 0;
-        if let Some(data) = self.document.take() {
-            fs::write(&doc_path, &data)?;
-            flushed = data.len();
+    /// The cached sync state for `peer_id`, `Some(None)` if it has been removed and `None` if the
+    /// cache doesn't know about it.
+    fn sync_state(&self, peer_id: &[u8]) -> Option<Option<&Vec<u8>>> {
+        match self.sync_states.get(peer_id) {
+            Some(sync_state) => Some(Some(sync_state)),
+            None if self.removed_sync_states.contains(peer_id) => Some(None),
+            None => None,
         }
-        Ok(flushed)
     }
 
-    fn flush_sync_states(&mut self, sync_states_path: PathBuf) -> Result<usize, std::io::Error> {
-        let mut flushed = 0;
-        for (peer_id, sync_state) in self.sync_states.drain() {
-            fs::write(make_peer_path(&sync_states_path, &peer_id), &sync_state)?;
-            flushed += sync_state.len();
+    fn flush_document<F: FileSystem>(
+        &mut self,
+        fs: &F,
+        doc_path: &Path,
+        durability: Durability,
+        failures: &mut Failures,
+    ) -> usize {
+        let data = match &self.document {
+            Some(data) => data,
+            None => return 0,
+        };
+        let mut result = write_file(fs, doc_path, data, durability);
+        if result.is_ok() && durability.sync_dirs() {
+            if let Some(parent) = doc_path.parent() {
+                result = fs.sync_dir(parent);
+            }
+        }
+        match result {
+            Ok(()) => self.document.take().map_or(0, |data| data.len()),
+            Err(e) => {
+                failures.push(FlushItem::Document, e);
+                0
+            }
         }
-        Ok(flushed)
     }
 
-    #[cfg(feature = "async")]
-    async fn flush_changes_async(
+    fn flush_changes<F: FileSystem>(
         &mut self,
-        changes_path: PathBuf,
-    ) -> Result<usize, std::io::Error> {
-        let futs = futures::stream::FuturesUnordered::new();
-        for ((a, s), c) in self.changes.drain() {
-            let len = c.len();
-            futs.push(
-                tokio::fs::write(make_changes_path(&changes_path, &a, s), c).map(move |_| Ok(len)),
-            );
+        fs: &F,
+        changes_path: &Path,
+        durability: Durability,
+        failures: &mut Failures,
+    ) -> usize {
+        let mut new_shard = self.unsynced_shards;
+        let mut written = Vec::new();
+        for (shard, keys) in shard_groups(changes_path, self.changes.keys()) {
+            match ensure_dir(fs, &shard) {
+                Ok(created) => new_shard |= created,
+                Err(e) => {
+                    failures.extend(keys.into_iter().map(change_item), &e);
+                    continue;
+                }
+            }
+            let mut shard_written = Vec::new();
+            for (a, s) in keys {
+                let path = make_changes_path(changes_path, &a, s);
+                match write_file(fs, &path, &self.changes[&(a.clone(), s)], durability) {
+                    Ok(()) => shard_written.push((a, s)),
+                    Err(e) => failures.push(FlushItem::Change(a, s), e),
+                }
+            }
+            if durability.sync_dirs() && !shard_written.is_empty() {
+                if let Err(e) = fs.sync_dir(&shard) {
+                    failures.extend(shard_written.into_iter().map(change_item), &e);
+                    continue;
+                }
+            }
+            written.extend(shard_written);
+        }
+        if new_shard && durability.sync_dirs() {
+            let result = fs.sync_dir(changes_path);
+            self.unsynced_shards = result.is_err();
+            if let Err(e) = result {
+                failures.extend(written.drain(..).map(change_item), &e);
+            }
+        }
+        self.take_changes(written)
+    }
+
+    fn flush_removals<F: FileSystem>(
+        &mut self,
+        fs: &F,
+        changes_path: &Path,
+        durability: Durability,
+        failures: &mut Failures,
+    ) {
+        // until the document is on disk it may be all that covers the removed changes
+        if self.document.is_some() {
+            return;
+        }
+        let mut removed = Vec::new();
+        for (shard, keys) in shard_groups(changes_path, &self.removed_changes) {
+            let mut shard_removed = Vec::new();
+            for (a, s) in keys {
+                match fs.remove_file(&make_changes_path(changes_path, &a, s)) {
+                    Ok(()) => shard_removed.push((a, s)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => removed.push((a, s)),
+                    Err(e) => failures.push(FlushItem::Removal(a, s), e),
+                }
+            }
+            if durability.sync_dirs() && !shard_removed.is_empty() {
+                if let Err(e) = fs.sync_dir(&shard) {
+                    failures.extend(shard_removed.into_iter().map(removal_item), &e);
+                    continue;
+                }
+            }
+            removed.extend(shard_removed);
+        }
+        for key in removed {
+            self.removed_changes.remove(&key);
         }
-        let res: Result<Vec<usize>, std::io::Error> = futs.try_collect().await;
-        Ok(res?.iter().sum())
     }
 
+    /// Write the cached sync states and delete the files of those that were removed.
+    fn flush_sync_states<F: FileSystem>(
+        &mut self,
+        fs: &F,
+        sync_states_path: &Path,
+        durability: Durability,
+        failures: &mut Failures,
+    ) -> usize {
+        let mut written = Vec::new();
+        for (peer_id, sync_state) in &self.sync_states {
+            let path = make_peer_path(sync_states_path, peer_id);
+            match write_file(fs, &path, sync_state, durability) {
+                Ok(()) => written.push(peer_id.clone()),
+                Err(e) => failures.push(FlushItem::SyncState(peer_id.clone()), e),
+            }
+        }
+        let mut removed = Vec::new();
+        for peer_id in &self.removed_sync_states {
+            match fs.remove_file(&make_peer_path(sync_states_path, peer_id)) {
+                Ok(()) => removed.push(peer_id.clone()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => removed.push(peer_id.clone()),
+                Err(e) => failures.push(FlushItem::SyncStateRemoval(peer_id.clone()), e),
+            }
+        }
+        if durability.sync_dirs() && !(written.is_empty() && removed.is_empty()) {
+            if let Err(e) = fs.sync_dir(sync_states_path) {
+                failures.extend(written.into_iter().map(FlushItem::SyncState), &e);
+                failures.extend(removed.into_iter().map(FlushItem::SyncStateRemoval), &e);
+                return 0;
+            }
+        }
+        for peer_id in removed {
+            self.removed_sync_states.remove(&peer_id);
+        }
+        self.take_sync_states(written)
+    }
+
+    /// Write the cached document, returning whether it was written.
     #[cfg(feature = "async")]
-    async fn flush_document_async(&mut self, doc_path: PathBuf) -> Result<usize, std::io::Error> {
-        let mut flushed = 0;
-        if let Some(data) = self.document.take() {
-            tokio::fs::write(&doc_path, &data).await?;
-            flushed = data.len();
+    async fn write_document_async(
+        &self,
+        doc_path: &Path,
+        durability: Durability,
+        failures: &mut Failures,
+    ) -> bool {
+        let data = match &self.document {
+            Some(data) => data,
+            None => return false,
+        };
+        let mut result = write_file_async(doc_path.to_owned(), data, durability).await;
+        if result.is_ok() && durability.sync_dirs() {
+            if let Some(parent) = doc_path.parent() {
+                result = sync_dir_async(parent.to_owned()).await;
+            }
+        }
+        match result {
+            Ok(()) => true,
+            Err(e) => {
+                failures.push(FlushItem::Document, e);
+                false
+            }
         }
-        Ok(flushed)
     }
 
+    /// Write the cached changes to their files, returning those that were written and whether
+    /// shard directories are left unsynced.
     #[cfg(feature = "async")]
-    async fn flush_sync_states_async(
-        &mut self,
-        sync_states_path: PathBuf,
-    ) -> Result<usize, std::io::Error> {
-        let futs = futures::stream::FuturesUnordered::new();
-        for (peer_id, sync_state) in self.sync_states.drain() {
-            let len = sync_state.len();
-            futs.push(
-                tokio::fs::write(make_peer_path(&sync_states_path, &peer_id), sync_state)
-                    .map(move |_| Ok(len)),
-            );
+    async fn write_changes_async(
+        &self,
+        changes_path: &Path,
+        durability: Durability,
+        failures: &mut Failures,
+    ) -> (Vec<(ActorId, u64)>, bool) {
+        let mut new_shard = self.unsynced_shards;
+        let mut shards = Vec::new();
+        for (shard, keys) in shard_groups(changes_path, self.changes.keys()) {
+            match tokio::fs::create_dir(&shard).await {
+                Ok(()) => new_shard = true,
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => {
+                    failures.extend(keys.into_iter().map(change_item), &e);
+                    continue;
+                }
+            }
+            shards.push((shard, keys));
+        }
+
+        let changes = &self.changes;
+        let mut results: HashMap<_, _> = shards
+            .iter()
+            .flat_map(|(_, keys)| keys)
+            .map(|(a, s)| async move {
+                let path = make_changes_path(changes_path, a, *s);
+                let result = write_file_async(path, &changes[&(a.clone(), *s)], durability).await;
+                ((a.clone(), *s), result)
+            })
+            .collect::<futures::stream::FuturesUnordered<_>>()
+            .collect()
+            .await;
+
+        let mut written = Vec::new();
+        for (shard, keys) in shards {
+            let mut shard_written = Vec::new();
+            for key in keys {
+                match results.remove(&key) {
+                    Some(Ok(())) => shard_written.push(key),
+                    Some(Err(e)) => failures.push(change_item(key), e),
+                    None => {}
+                }
+            }
+            if durability.sync_dirs() && !shard_written.is_empty() {
+                if let Err(e) = sync_dir_async(shard).await {
+                    failures.extend(shard_written.into_iter().map(change_item), &e);
+                    continue;
+                }
+            }
+            written.extend(shard_written);
+        }
+        if new_shard && durability.sync_dirs() {
+            if let Err(e) = sync_dir_async(changes_path.to_owned()).await {
+                failures.extend(written.drain(..).map(change_item), &e);
+                return (written, true);
+            }
         }
-        let res: Result<Vec<usize>, std::io::Error> = futs.try_collect().await;
-        Ok(res?.iter().sum())
+        (written, false)
     }
 
+    /// Delete the files of removed changes, returning those that are gone.
+    ///
+    /// This must only be called once the cached document, if there is one, has been written.
+    #[cfg(feature = "async")]
+    async fn write_removals_async(
+        &self,
+        changes_path: &Path,
+        durability: Durability,
+        failures: &mut Failures,
+    ) -> Vec<(ActorId, u64)> {
+        let mut removed = Vec::new();
+        for (shard, keys) in shard_groups(changes_path, &self.removed_changes) {
+            let mut shard_removed = Vec::new();
+            for (a, s) in keys {
+                match tokio::fs::remove_file(make_changes_path(changes_path, &a, s)).await {
+                    Ok(()) => shard_removed.push((a, s)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => removed.push((a, s)),
+                    Err(e) => failures.push(FlushItem::Removal(a, s), e),
+                }
+            }
+            if durability.sync_dirs() && !shard_removed.is_empty() {
+                if let Err(e) = sync_dir_async(shard).await {
+                    failures.extend(shard_removed.into_iter().map(removal_item), &e);
+                    continue;
+                }
+            }
+            removed.extend(shard_removed);
+        }
+        removed
+    }
+
+    /// Write the cached sync states and delete the files of those that were removed, returning
+    /// the peer ids of each that were done.
+    #[cfg(feature = "async")]
+    async fn write_sync_states_async(
+        &self,
+        sync_states_path: &Path,
+        durability: Durability,
+        failures: &mut Failures,
+    ) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let results: Vec<_> = self
+            .sync_states
+            .iter()
+            .map(|(peer_id, sync_state)| async move {
+                let path = make_peer_path(sync_states_path, peer_id);
+                (
+                    peer_id.clone(),
+                    write_file_async(path, sync_state, durability).await,
+                )
+            })
+            .collect::<futures::stream::FuturesUnordered<_>>()
+            .collect()
+            .await;
+        let mut written = Vec::new();
+        for (peer_id, result) in results {
+            match result {
+                Ok(()) => written.push(peer_id),
+                Err(e) => failures.push(FlushItem::SyncState(peer_id), e),
+            }
+        }
+        let mut removed = Vec::new();
+        for peer_id in &self.removed_sync_states {
+            match tokio::fs::remove_file(make_peer_path(sync_states_path, peer_id)).await {
+                Ok(()) => removed.push(peer_id.clone()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => removed.push(peer_id.clone()),
+                Err(e) => failures.push(FlushItem::SyncStateRemoval(peer_id.clone()), e),
+            }
+        }
+        if durability.sync_dirs() && !(written.is_empty() && removed.is_empty()) {
+            if let Err(e) = sync_dir_async(sync_states_path.to_owned()).await {
+                failures.extend(written.into_iter().map(FlushItem::SyncState), &e);
+                failures.extend(removed.into_iter().map(FlushItem::SyncStateRemoval), &e);
+                return (Vec::new(), Vec::new());
+            }
+        }
+        (written, removed)
+    }
+
+    /// Write everything in the cache to the files of [`StorageMode::Files`], returning what was
+    /// written.
+    #[cfg(feature = "async")]
+    async fn write_async(
+        &self,
+        doc_path: &Path,
+        changes_path: &Path,
+        sync_states_path: &Path,
+        durability: Durability,
+        failures: &mut Failures,
+    ) -> Written {
+        let mut written = Written {
+            document: self
+                .write_document_async(doc_path, durability, failures)
+                .await,
+            ..Written::default()
+        };
+        let (changes, unsynced_shards) = self
+            .write_changes_async(changes_path, durability, failures)
+            .await;
+        written.changes = changes;
+        written.unsynced_shards = Some(unsynced_shards);
+        // until the document is on disk it may be all that covers the removed changes
+        if self.document.is_none() || written.document {
+            written.removed_changes = self
+                .write_removals_async(changes_path, durability, failures)
+                .await;
+        }
+        let (sync_states, removed_sync_states) = self
+            .write_sync_states_async(sync_states_path, durability, failures)
+            .await;
+        written.sync_states = sync_states;
+        written.removed_sync_states = removed_sync_states;
+        written
+    }
+
+    /// Flush the cache, items that fail to be written are left in it so that they can be retried.
     #[cfg(feature = "async")]
     pub async fn flush_async(
         &mut self,
         doc_path: PathBuf,
         changes_path: PathBuf,
         sync_states_path: PathBuf,
-    ) -> Result<usize, std::io::Error> {
-        let mut flushed = 0;
-        flushed += self.flush_document_async(doc_path).await?;
-        flushed += self.flush_changes_async(changes_path).await?;
-        flushed += self.flush_sync_states_async(sync_states_path).await?;
-        Ok(flushed)
+        durability: Durability,
+    ) -> Result<usize, FlushError> {
+        let mut failures = Failures::default();
+        let written = self
+            .write_async(
+                &doc_path,
+                &changes_path,
+                &sync_states_path,
+                durability,
+                &mut failures,
+            )
+            .await;
+        let flushed = self.forget(written);
+        failures.into_result(flushed)
     }
 
-    pub fn flush(
+    /// Flush the cache, items that fail to be written are left in it so that they can be retried.
+    pub fn flush<F: FileSystem>(
         &mut self,
+        fs: &F,
         doc_path: PathBuf,
         changes_path: PathBuf,
         sync_states_path: PathBuf,
-    ) -> Result<usize, std::io::Error> {
+        durability: Durability,
+    ) -> Result<usize, FlushError> {
+        let mut failures = Failures::default();
         let mut flushed = 0;
-        flushed += self.flush_document(doc_path)?;
-        flushed += self.flush_changes(changes_path)?;
-        flushed += self.flush_sync_states(sync_states_path)?;
-        Ok(flushed)
+        flushed += self.flush_document(fs, &doc_path, durability, &mut failures);
+        flushed += self.flush_changes(fs, &changes_path, durability, &mut failures);
+        self.flush_removals(fs, &changes_path, durability, &mut failures);
+        flushed += self.flush_sync_states(fs, &sync_states_path, durability, &mut failures);
+        failures.into_result(flushed)
+    }
+
+    /// Drop changes that have been written, returning their total length.
+    fn take_changes(&mut self, written: Vec<(ActorId, u64)>) -> usize {
+        written
+            .into_iter()
+            .filter_map(|key| self.changes.remove(&key))
+            .map(|c| c.len())
+            .sum()
+    }
+
+    /// Drop sync states that have been written, returning their total length.
+    fn take_sync_states(&mut self, written: Vec<Vec<u8>>) -> usize {
+        written
+            .into_iter()
+            .filter_map(|peer_id| self.sync_states.remove(&peer_id))
+            .map(|s| s.len())
+            .sum()
     }
 
-    fn drain_clone(&mut self) -> Self {
-        Self {
-            changes: self.changes.drain().collect(),
-            document: self.document.take(),
-            sync_states: self.sync_states.drain().collect(),
+    /// The total length of what has been written.
+    #[cfg(feature = "async")]
+    fn written_len(&self, written: &Written) -> usize {
+        let document = self.document.as_ref().filter(|_| written.document);
+        let changes = written
+            .changes
+            .iter()
+            .filter_map(|key| self.changes.get(key));
+        let sync_states = written
+            .sync_states
+            .iter()
+            .filter_map(|peer_id| self.sync_states.get(peer_id));
+        document
+            .into_iter()
+            .chain(changes)
+            .chain(sync_states)
+            .map(Vec::len)
+            .sum()
+    }
+
+    /// Drop what has been written, returning its total length.
+    #[cfg(feature = "async")]
+    fn forget(&mut self, written: Written) -> usize {
+        let mut flushed = 0;
+        if written.document {
+            flushed += self.document.take().map_or(0, |data| data.len());
+        }
+        flushed += self.take_changes(written.changes);
+        for key in &written.removed_changes {
+            self.removed_changes.remove(key);
+        }
+        flushed += self.take_sync_states(written.sync_states);
+        for peer_id in &written.removed_sync_states {
+            self.removed_sync_states.remove(peer_id);
+        }
+        if let Some(unsynced_shards) = written.unsynced_shards {
+            self.unsynced_shards = unsynced_shards;
+        }
+        flushed
+    }
+
+    /// Put back what is left of an earlier cache, anything in this one takes precedence.
+    fn requeue(&mut self, cache: Self) {
+        for (key, change) in cache.changes {
+            if !self.shadows(&key) {
+                self.changes.insert(key, change);
+            }
+        }
+        if self.document.is_none() {
+            self.document = cache.document;
         }
+        for (peer_id, sync_state) in cache.sync_states {
+            if self.sync_state(&peer_id).is_none() {
+                self.sync_states.insert(peer_id, sync_state);
+            }
+        }
+        for key in cache.removed_changes {
+            if !self.changes.contains_key(&key) {
+                self.removed_changes.insert(key);
+            }
+        }
+        for peer_id in cache.removed_sync_states {
+            if !self.sync_states.contains_key(&peer_id) {
+                self.removed_sync_states.insert(peer_id);
+            }
+        }
+        self.unsynced_shards |= cache.unsynced_shards;
     }
 }
 
+fn change_item((actor, seq): (ActorId, u64)) -> FlushItem {
+    FlushItem::Change(actor, seq)
+}
+
+fn removal_item((actor, seq): (ActorId, u64)) -> FlushItem {
+    FlushItem::Removal(actor, seq)
+}
+
 /// Possible errors from persisting.
 #[derive(Debug, thiserror::Error)]
 pub enum FsPersisterError {
@@ -141,151 +640,586 @@ pub enum FsPersisterError {
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Hex(#[from] FromHexError),
+    /// A file was found where only change files are expected.
+    #[error("unexpected file {0:?} in changes directory")]
+    UnexpectedFile(PathBuf),
+    /// A segment file has invalid data before its end.
+    #[error("corrupt segment file {0:?}")]
+    CorruptSegment(PathBuf),
+    /// A write-ahead log has a record that can't be understood, or a damaged record before its
+    /// end.
+    #[error("corrupt log file {0:?}")]
+    CorruptLog(PathBuf),
+    /// The directory was created with a different [`StorageMode`] from the one it was opened with.
+    #[error("directory {0:?} was created with a different storage mode")]
+    StorageModeMismatch(PathBuf),
+    /// A sync state file has a hashed name that isn't in the peer index.
+    #[error("sync state file {0:?} is not in the peer index")]
+    UnindexedPeer(PathBuf),
+    /// Some of the cache could not be flushed, it has been kept for the next flush.
+    #[error(transparent)]
+    Flush(#[from] FlushError),
 }
 
 const CHANGES_DIR: &str = "changes";
+const SEGMENTS_DIR: &str = "segments";
 const DOC_FILE: &str = "doc";
 const SYNC_DIR: &str = "sync";
 
 impl FsPersister {
+    /// Construct a new persister storing data under `root/prefix`, with a file per change.
+    ///
+    /// Any temporary files left behind by an interrupted flush are removed. Flushes default to
+    /// [`Durability::Full`], see [`FsPersister::with_durability`] to change this.
     pub fn new<R: AsRef<Path>, P: AsRef<Path>>(
         root: R,
         prefix: P,
+    ) -> Result<Self, FsPersisterError> {
+        Self::with_mode(root, prefix, StorageMode::Files)
+    }
+
+    /// Construct a new persister storing data under `root/prefix`, with changes stored according
+    /// to `mode`.
+    pub fn with_mode<R: AsRef<Path>, P: AsRef<Path>>(
+        root: R,
+        prefix: P,
+        mode: StorageMode,
+    ) -> Result<Self, FsPersisterError> {
+        Self::with_fs(StdFileSystem, root, prefix, mode, Durability::default())
+    }
+
+    /// Take the cache and return a future that flushes it, so that the persister can carry on
+    /// being used while the flush happens.
+    ///
+    /// Nothing is written until the future is polled. What it is writing can still be read
+    /// through the persister, and anything it fails to write, or doesn't get to if it is dropped,
+    /// is put back in the cache for a later flush.
+    ///
+    /// Anything still being written by an earlier future is left in the cache, as are the
+    /// removals of flushed changes while an earlier future is running or when using
+    /// [`StorageMode::Segments`]. These are written by later flushes.
+    #[cfg(feature = "async")]
+    pub fn flush_cache_async(&mut self) -> impl Future<Output = Result<usize, FlushError>> {
+        self.land_flights();
+        let cache = self.take_flushable(self.segments.is_none());
+        // encoding only touches the log's index, the records are written by the future
+        let append = match &mut self.segments {
+            Some(log) => log.encode_changes(&cache.changes),
+            None => None,
+        };
+        let segments = self.segments.is_some();
+        let mut landing = Landing::start(self, cache, append);
+        let doc_path = self.doc_path.clone();
+        let changes_path = self.changes_path.clone();
+        let sync_states_path = self.sync_states_path.clone();
+        let durability = self.durability;
+        async move {
+            let mut failures = Failures::default();
+            let mut flushed = 0;
+            if segments {
+                landing.written.document = landing
+                    .cache
+                    .write_document_async(&doc_path, durability, &mut failures)
+                    .await;
+                if let Some(append) = &landing.append {
+                    let changes = &landing.cache.changes;
+                    match append.write_async(durability).await {
+                        Ok(()) => {
+                            flushed += changes.values().map(Vec::len).sum::<usize>();
+                            landing.appended = true;
+                        }
+                        Err(e) => failures.extend(changes.keys().cloned().map(change_item), &e),
+                    }
+                }
+                let (sync_states, removed_sync_states) = landing
+                    .cache
+                    .write_sync_states_async(&sync_states_path, durability, &mut failures)
+                    .await;
+                landing.written.sync_states = sync_states;
+                landing.written.removed_sync_states = removed_sync_states;
+            } else {
+                landing.written = landing
+                    .cache
+                    .write_async(
+                        &doc_path,
+                        &changes_path,
+                        &sync_states_path,
+                        durability,
+                        &mut failures,
+                    )
+                    .await;
+            }
+            flushed += landing.cache.written_len(&landing.written);
+            failures.into_result(flushed)
+        }
+    }
+
+    pub fn load<R: AsRef<Path>, P: AsRef<Path>>(
+        root: R,
+        prefix: P,
+    ) -> Result<Option<Self>, FsPersisterError> {
+        if !root.as_ref().join(&prefix).exists() {
+            return Ok(None);
+        }
+        let doc = Self::new(root, prefix)?;
+        Ok(Some(doc))
+    }
+}
+
+impl<F: FileSystem> FsPersister<F> {
+    /// Construct a new persister storing data under `root/prefix` on the given filesystem.
+    ///
+    /// `durability` is used for everything written while opening, such as creating directories
+    /// and migrating old layouts, as well as for flushes.
+    pub fn with_fs<R: AsRef<Path>, P: AsRef<Path>>(
+        fs: F,
+        root: R,
+        prefix: P,
+        mode: StorageMode,
+        durability: Durability,
     ) -> Result<Self, FsPersisterError> {
         let root_path = root.as_ref().join(&prefix);
-        fs::create_dir_all(&root_path)?;
+        // each mode only creates its own directory, so finding the other's means a mismatch
+        let other_dir = match mode {
+            StorageMode::Files => SEGMENTS_DIR,
+            StorageMode::Segments { .. } => CHANGES_DIR,
+        };
+        if exists(&fs, &root_path.join(other_dir))? {
+            return Err(FsPersisterError::StorageModeMismatch(root_path));
+        }
+        create_dirs(&fs, &root_path, durability)?;
 
         let changes_path = root_path.join(CHANGES_DIR);
-        if fs::metadata(&changes_path).is_err() {
-            fs::create_dir(&changes_path)?;
-        }
+        let segments = match mode {
+            StorageMode::Files => {
+                create_dirs(&fs, &changes_path, durability)?;
+                remove_temp_files(&fs, &changes_path)?;
+                migrate_flat_layout(&fs, &changes_path, durability)?;
+                None
+            }
+            StorageMode::Segments { max_segment_size } => Some(SegmentLog::open(
+                &fs,
+                root_path.join(SEGMENTS_DIR),
+                max_segment_size,
+                durability,
+            )?),
+        };
 
         let doc_path = root_path.join(DOC_FILE);
 
         let sync_states_path = root_path.join(SYNC_DIR);
-        if fs::metadata(&sync_states_path).is_err() {
-            fs::create_dir(&sync_states_path)?;
-        }
+        create_dirs(&fs, &sync_states_path, durability)?;
+
+        remove_temp_files(&fs, &root_path)?;
+        remove_temp_files(&fs, &sync_states_path)?;
+        let peers = PeerIndex::open(&fs, &sync_states_path, durability)?;
 
         let mut s = Self {
+            fs,
             changes_path,
             doc_path,
             sync_states_path,
-            cache: FsPersisterCache {
-                changes: HashMap::new(),
-                document: None,
-                sync_states: HashMap::new(),
-            },
+            peers,
+            segments,
+            cache: FsPersisterCache::default(),
+            flights: SharedFlights::default(),
             sizes: StoredSizes::default(),
+            counts: StoredCounts::default(),
+            durability,
         };
-
-        s.sizes.changes = s.get_changes()?.iter().map(|v| v.len() as u64).sum();
-        s.sizes.document = s.get_document()?.unwrap_or_default().len() as u64;
-        s.sizes.sync_states = s
-            .get_peer_ids()?
-            .iter()
-            .map(|id| {
-                s.get_sync_state(id)
-                    .map(|o| o.unwrap_or_default().len() as u64)
-            })
-            .collect::<Result<Vec<u64>, _>>()?
-            .iter()
-            .sum();
+        let (sizes, counts) = s.scan_sizes()?;
+        s.sizes = sizes;
+        s.counts = counts;
 
         Ok(s)
     }
 
-    #[cfg(feature = "async")]
-    pub fn flush_cache_async(&mut self) -> impl Future<Output = Result<usize, std::io::Error>> {
-        let doc_path = self.doc_path.clone();
-        let changes_path = self.changes_path.clone();
-        let sync_states_path = self.sync_states_path.clone();
-        let mut cache = self.cache.drain_clone();
-        async move {
-            cache
-                .flush_async(doc_path, changes_path, sync_states_path)
-                .await
+    /// Set the durability used when flushing documents, changes and sync states.
+    ///
+    /// This doesn't cover what was written while opening, see [`FsPersister::with_fs`] for that.
+    #[must_use]
+    pub const fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// The durability used when flushing.
+    pub const fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// The number of changes and sync states stored.
+    pub fn counts(&self) -> StoredCounts {
+        self.counts.clone()
+    }
+
+    /// Work out the sizes and counts from what is stored, correcting them if they were wrong.
+    ///
+    /// They are worked out when opening and kept up to date as things are stored, this is for when
+    /// something else has changed the directory since, such as another process. Returns whether
+    /// they were already right.
+    pub fn verify_sizes(&mut self) -> Result<bool, FsPersisterError> {
+        let (sizes, counts) = self.scan_sizes()?;
+        if sizes == self.sizes && counts == self.counts {
+            return Ok(true);
         }
+        self.sizes = sizes;
+        self.counts = counts;
+        Ok(false)
     }
 
-    pub fn load<R: AsRef<Path>, P: AsRef<Path>>(
-        root: R,
-        prefix: P,
-    ) -> Result<Option<Self>, FsPersisterError> {
-        if !root.as_ref().join(&prefix).exists() {
-            return Ok(None);
+    /// Add up the sizes and counts of everything stored, along with what is cached or being
+    /// flushed.
+    ///
+    /// Only the lengths of files are looked at, along with the segment index, so nothing stored
+    /// has to be read.
+    fn scan_sizes(&self) -> Result<(StoredSizes, StoredCounts), FsPersisterError> {
+        let mut sizes = StoredSizes::default();
+        let mut counts = StoredCounts::default();
+        let flights = flight::lock(&self.flights);
+
+        let pending_changes = self.pending_changes(&flights);
+        for change in pending_changes.values().flatten() {
+            sizes.changes += change.len() as u64;
+            counts.changes += 1;
+        }
+        if let Some(log) = &self.segments {
+            for (key, len) in log.change_lens() {
+                if !pending_changes.contains_key(key) {
+                    sizes.changes += len;
+                    counts.changes += 1;
+                }
+            }
+        } else {
+            for path in change_files(&self.fs, &self.changes_path)? {
+                if !is_pending(&path, |key| pending_changes.contains_key(key)) {
+                    sizes.changes += file_len(&self.fs, &path)?.unwrap_or_default();
+                    counts.changes += 1;
+                }
+            }
+        }
+
+        sizes.document = match self.cache.document.as_ref().or_else(|| flights.document()) {
+            Some(doc) => doc.len() as u64,
+            None => file_len(&self.fs, &self.doc_path)?.unwrap_or_default(),
+        };
+
+        let pending_sync_states = self.pending_sync_states(&flights);
+        for sync_state in pending_sync_states.values().flatten() {
+            sizes.sync_states += sync_state.len() as u64;
+            counts.sync_states += 1;
+        }
+        for entry in self.fs.read_dir(&self.sync_states_path)? {
+            if entry.is_dir || is_temp_name(&entry.name) || entry.name == PEER_INDEX_FILE {
+                continue;
+            }
+            let peer_id = self.peers.peer_id(&self.sync_states_path, &entry.name)?;
+            if !pending_sync_states.contains_key(peer_id.as_slice()) {
+                let path = self.sync_states_path.join(&entry.name);
+                sizes.sync_states += file_len(&self.fs, &path)?.unwrap_or_default();
+                counts.sync_states += 1;
+            }
+        }
+        Ok((sizes, counts))
+    }
+
+    /// The filesystem the persister stores its files on.
+    pub const fn filesystem(&self) -> &F {
+        &self.fs
+    }
+
+    /// Apply the flights that have landed, committing what they appended to the segment log and
+    /// putting back what they didn't write.
+    fn land_flights(&mut self) {
+        let mut flights = flight::lock(&self.flights);
+        while let Some((mut left, landed)) = flights.pop_landed() {
+            if let Some(log) = &mut self.segments {
+                if landed.torn {
+                    log.abandon_append();
+                }
+                if let Some(append) = landed.append {
+                    for key in append.inserted() {
+                        left.changes.remove(&key);
+                    }
+                    log.commit(append);
+                }
+            }
+            self.cache.requeue(left);
         }
-        let doc = Self::new(root, prefix)?;
-        Ok(Some(doc))
     }
+
+    /// Take what can be flushed out of the cache.
+    ///
+    /// Items held by a flight are left, so that they aren't written until it has been applied,
+    /// as are changes while a flight appends to the segment log. Removals of flushed changes are
+    /// only taken if `removals` is set and nothing is in flight, as a flight may be writing the
+    /// document that covers them.
+    fn take_flushable(&mut self, removals: bool) -> FsPersisterCache {
+        let flights = flight::lock(&self.flights);
+        let cache = &mut self.cache;
+        let mut taken = FsPersisterCache::default();
+        if flights.document().is_none() {
+            taken.document = cache.document.take();
+        }
+        if !flights.log_busy() {
+            let (held, free) = cache
+                .changes
+                .drain()
+                .partition(|(key, _)| flights.change(key).is_some());
+            cache.changes = held;
+            taken.changes = free;
+            taken.unsynced_shards = std::mem::take(&mut cache.unsynced_shards);
+        }
+        if removals && flights.is_empty() {
+            taken.removed_changes = std::mem::take(&mut cache.removed_changes);
+        }
+        let (held, free) = cache
+            .sync_states
+            .drain()
+            .partition(|(peer_id, _)| flights.sync_state(peer_id).is_some());
+        cache.sync_states = held;
+        taken.sync_states = free;
+        let (held, free) = cache
+            .removed_sync_states
+            .drain()
+            .partition(|peer_id| flights.sync_state(peer_id).is_some());
+        cache.removed_sync_states = held;
+        taken.removed_sync_states = free;
+        taken
+    }
+
+    /// The changes that are cached or being flushed, with `None` for those that have been
+    /// removed. These take the place of any flushed copies.
+    fn pending_changes<'a>(
+        &'a self,
+        flights: &'a Flights,
+    ) -> HashMap<&'a (ActorId, u64), Option<&'a Vec<u8>>> {
+        let mut pending = HashMap::new();
+        for cache in flights.caches().chain(std::iter::once(&self.cache)) {
+            pending.extend(
+                cache
+                    .changes
+                    .iter()
+                    .map(|(key, change)| (key, Some(change))),
+            );
+            pending.extend(cache.removed_changes.iter().map(|key| (key, None)));
+        }
+        pending
+    }
+
+    /// The sync states that are cached or being flushed, with `None` for those that have been
+    /// removed. These take the place of any flushed copies.
+    fn pending_sync_states<'a>(
+        &'a self,
+        flights: &'a Flights,
+    ) -> HashMap<&'a [u8], Option<&'a Vec<u8>>> {
+        let mut pending = HashMap::new();
+        for cache in flights.caches().chain(std::iter::once(&self.cache)) {
+            pending.extend(
+                cache
+                    .sync_states
+                    .iter()
+                    .map(|(peer_id, sync_state)| (peer_id.as_slice(), Some(sync_state))),
+            );
+            pending.extend(
+                cache
+                    .removed_sync_states
+                    .iter()
+                    .map(|peer_id| (peer_id.as_slice(), None)),
+            );
+        }
+        pending
+    }
+
+    /// The length of a change, whether it is cached, being flushed or stored.
+    fn change_len(&self, flights: &Flights, key: &(ActorId, u64)) -> std::io::Result<Option<u64>> {
+        match self.cache.change(key).or_else(|| flights.change(key)) {
+            Some(change) => Ok(change.map(|c| c.len() as u64)),
+            None => self.stored_change_len(key),
+        }
+    }
+
+    /// The length of the flushed copy of a change, if there is one.
+    fn stored_change_len(&self, key: &(ActorId, u64)) -> std::io::Result<Option<u64>> {
+        if let Some(log) = &self.segments {
+            return Ok(log.change_len(key));
+        }
+        file_len(
+            &self.fs,
+            &make_changes_path(&self.changes_path, &key.0, key.1),
+        )
+    }
+
+    /// The length of the sync state for `peer_id`, whether it is cached, being flushed or
+    /// stored.
+    fn sync_state_len(&self, flights: &Flights, peer_id: &[u8]) -> std::io::Result<Option<u64>> {
+        match self
+            .cache
+            .sync_state(peer_id)
+            .or_else(|| flights.sync_state(peer_id))
+        {
+            Some(sync_state) => Ok(sync_state.map(|s| s.len() as u64)),
+            None => file_len(&self.fs, &make_peer_path(&self.sync_states_path, peer_id)),
+        }
+    }
+}
+
+/// Whether the change stored at `path` is hidden by one that `pending` says is cached or being
+/// flushed, see [`FsPersister::pending_changes`].
+fn is_pending<P: Fn(&(ActorId, u64)) -> bool>(path: &Path, pending: P) -> bool {
+    matches!(change_key(path), Some(key) if pending(&key))
+}
+
+/// Write the document, changes and removals in `cache` to the segment log.
+///
+/// The document goes first, as it may be all that covers the removed changes.
+fn flush_segments<F: FileSystem>(
+    log: &mut SegmentLog,
+    fs: &F,
+    cache: &mut FsPersisterCache,
+    doc_path: &Path,
+    durability: Durability,
+    failures: &mut Failures,
+) -> usize {
+    let mut flushed = cache.flush_document(fs, doc_path, durability, failures);
+    match log.append_changes(fs, &cache.changes, durability) {
+        Ok(n) => {
+            flushed += n;
+            cache.changes.clear();
+        }
+        Err(e) => failures.extend(cache.changes.keys().cloned().map(change_item), &e),
+    }
+    if cache.document.is_none() && !cache.removed_changes.is_empty() {
+        let removed = cache.removed_changes.iter().map(|(a, s)| (a, *s)).collect();
+        match log.remove_changes(fs, removed, durability) {
+            Ok(()) => cache.removed_changes.clear(),
+            Err(e) => failures.extend(cache.removed_changes.iter().cloned().map(removal_item), &e),
+        }
+    }
+    flushed
 }
 
-fn make_changes_path<P: AsRef<Path>>(changes_path: P, actor_id: &ActorId, seq: u64) -> PathBuf {
-    changes_path
-        .as_ref()
-        .join(format!("{}-{}", actor_id.to_hex_string(), seq))
+/// Whether there is a file or directory at `path`.
+fn exists<F: FileSystem>(fs: &F, path: &Path) -> std::io::Result<bool> {
+    match fs.metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
 }
 
-fn make_peer_path<P: AsRef<Path>>(sync_states_path: P, peer_id: &[u8]) -> PathBuf {
-    sync_states_path.as_ref().join(hex::encode(peer_id))
+/// The length of the file at `path`, if there is one.
+fn file_len<F: FileSystem>(fs: &F, path: &Path) -> std::io::Result<Option<u64>> {
+    match fs.metadata(path) {
+        Ok(meta) if meta.is_file() => Ok(Some(meta.len)),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
 }
 
-impl Persister for FsPersister {
+impl<F: FileSystem> Persister for FsPersister<F> {
     type Error = FsPersisterError;
 
     fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
-        fs::read_dir(&self.changes_path)?
-            .filter_map(|entry| {
-                if let Ok((Ok(file_type), path)) =
-                    entry.map(|entry| (entry.file_type(), entry.path()))
-                {
-                    if file_type.is_file() {
-                        Some(fs::read(path).map_err(FsPersisterError::from))
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect()
+        let flights = flight::lock(&self.flights);
+        // cached changes, and those being flushed, take the place of any flushed copies
+        let pending = self.pending_changes(&flights);
+        let mut changes = pending
+            .values()
+            .flatten()
+            .map(|c| c.to_vec())
+            .collect::<Vec<_>>();
+        if let Some(log) = &self.segments {
+            changes.extend(log.read_changes(&self.fs, |key| pending.contains_key(key))?);
+            return Ok(changes);
+        }
+        for path in change_files(&self.fs, &self.changes_path)? {
+            if !is_pending(&path, |key| pending.contains_key(key)) {
+                changes.push(self.fs.read(&path)?);
+            }
+        }
+        Ok(changes)
     }
 
     fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let flights = flight::lock(&self.flights);
         for (a, s, c) in changes {
-            self.sizes.changes += c.len() as u64;
-            if let Some(old) = self.cache.changes.insert((a, s), c) {
-                self.sizes.changes -= old.len() as u64;
+            let key = (a, s);
+            let replaced = self.change_len(&flights, &key)?;
+            if replaced.is_none() {
+                self.counts.changes += 1;
             }
+            self.sizes.changes += c.len() as u64;
+            self.sizes.changes -= replaced.unwrap_or_default();
+            self.cache.removed_changes.remove(&key);
+            self.cache.changes.insert(key, c);
         }
         Ok(())
     }
 
     fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.land_flights();
+        let flights = flight::lock(&self.flights);
+        // a pending document may be the only thing covering these changes once they are gone, so
+        // flushed copies are kept until it is on disk. A flight may be writing one, or the changes
+        // themselves, so they are kept until it is done too.
+        let defer = self.cache.document.is_some() || !flights.is_empty();
+        let mut flushed = Vec::new();
         for (a, s) in changes {
-            if let Some(old) = self.cache.changes.remove(&(a.clone(), s)) {
-                // not flushed yet
-                self.sizes.changes -= old.len() as u64;
-                continue;
+            let key = (a.clone(), s);
+            if let Some(len) = self.change_len(&flights, &key)? {
+                self.sizes.changes -= len;
+                self.counts.changes -= 1;
             }
-
-            let path = make_changes_path(&self.changes_path, a, s);
-            if let Ok(meta) = fs::metadata(&path) {
-                if meta.is_file() {
-                    fs::remove_file(&path)?;
-                    self.sizes.changes -= meta.len();
+            // a change may have been flushed, or be being flushed, before being cached again so
+            // all of the copies are checked
+            let stored = !self.cache.removed_changes.contains(&key)
+                && (flights.change(&key).is_some() || self.stored_change_len(&key)?.is_some());
+            self.cache.changes.remove(&key);
+            if stored {
+                if defer {
+                    self.cache.removed_changes.insert(key);
+                } else {
+                    flushed.push((a, s));
                 }
             }
         }
+        drop(flights);
+
+        if let Some(log) = &mut self.segments {
+            log.remove_changes(&self.fs, flushed, self.durability)?;
+            return Ok(());
+        }
+
+        let mut shards = HashSet::new();
+        for (a, s) in flushed {
+            self.fs
+                .remove_file(&make_changes_path(&self.changes_path, a, s))?;
+            shards.insert(make_shard_path(&self.changes_path, a, s));
+        }
+        if self.durability.sync_dirs() {
+            for shard in shards {
+                self.fs.sync_dir(&shard)?;
+            }
+        }
         Ok(())
     }
 
     fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
-        if let Some(ref doc) = self.cache.document {
+        let flights = flight::lock(&self.flights);
+        if let Some(doc) = self.cache.document.as_ref().or_else(|| flights.document()) {
             return Ok(Some(doc.clone()));
         }
-        if fs::metadata(&self.doc_path).is_ok() {
-            return Ok(fs::read(&self.doc_path).map(|v| if v.is_empty() { None } else { Some(v) })?);
+        drop(flights);
+        if self.fs.metadata(&self.doc_path).is_ok() {
+            return Ok(self.fs.read(&self.doc_path).map(|v| {
+                if v.is_empty() {
+                    None
+                } else {
+                    Some(v)
+                }
+            })?);
         }
         Ok(None)
     }
@@ -297,61 +1231,90 @@ impl Persister for FsPersister {
     }
 
     fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
-        if let Some(sync_state) = self.cache.sync_states.get(peer_id) {
-            return Ok(Some(sync_state.clone()));
+        let flights = flight::lock(&self.flights);
+        if let Some(sync_state) = self
+            .cache
+            .sync_state(peer_id)
+            .or_else(|| flights.sync_state(peer_id))
+        {
+            return Ok(sync_state.cloned());
         }
+        drop(flights);
         let path = make_peer_path(&self.sync_states_path, peer_id);
-        if fs::metadata(&path).is_ok() {
-            return Ok(fs::read(&path).map(|v| if v.is_empty() { None } else { Some(v) })?);
+        if self.fs.metadata(&path).is_ok() {
+            return Ok(self
+                .fs
+                .read(&path)
+                .map(|v| if v.is_empty() { None } else { Some(v) })?);
         }
         Ok(None)
     }
 
     fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
-        self.sizes.sync_states += sync_state.len() as u64;
-        if let Some(old) = self.cache.sync_states.insert(peer_id, sync_state) {
-            self.sizes.sync_states -= old.len() as u64;
+        let replaced = self.sync_state_len(&flight::lock(&self.flights), &peer_id)?;
+        self.peers
+            .record(&self.fs, &self.sync_states_path, &peer_id, self.durability)?;
+        if replaced.is_none() {
+            self.counts.sync_states += 1;
         }
+        self.sizes.sync_states += sync_state.len() as u64;
+        self.sizes.sync_states -= replaced.unwrap_or_default();
+        self.cache.removed_sync_states.remove(&peer_id);
+        self.cache.sync_states.insert(peer_id, sync_state);
         Ok(())
     }
 
     fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let flights = flight::lock(&self.flights);
+        let mut removed = Vec::new();
+        let mut removed_files = false;
         for peer_id in peer_ids {
-            if let Some(old) = self.cache.sync_states.remove(*peer_id) {
-                // not flushed yet
-                self.sizes.sync_states -= old.len() as u64;
+            if let Some(len) = self.sync_state_len(&flights, peer_id)? {
+                self.sizes.sync_states -= len;
+                self.counts.sync_states -= 1;
+            }
+            self.cache.sync_states.remove(*peer_id);
+            if flights.sync_state(peer_id).is_some() {
+                // the flight may write the file after it is deleted here, so it is deleted by the
+                // next flush instead, leaving any index entry to be dropped on open
+                self.cache.removed_sync_states.insert(peer_id.to_vec());
                 continue;
             }
             let path = make_peer_path(&self.sync_states_path, peer_id);
-            if let Ok(meta) = fs::metadata(&path) {
-                if meta.is_file() {
-                    fs::remove_file(&path)?;
-                    self.sizes.sync_states -= meta.len();
-                }
+            if file_len(&self.fs, &path)?.is_some() {
+                self.fs.remove_file(&path)?;
+                removed_files = true;
             }
+            self.cache.removed_sync_states.remove(*peer_id);
+            removed.push(*peer_id);
         }
-        Ok(())
+        drop(flights);
+        if removed_files && self.durability.sync_dirs() {
+            self.fs.sync_dir(&self.sync_states_path)?;
+        }
+        // the files go first, an index entry without a file is harmless but not the other way
+        self.peers
+            .forget(&self.fs, &self.sync_states_path, &removed, self.durability)
     }
 
     fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
-        fs::read_dir(&self.sync_states_path)?
-            .filter_map(|entry| {
-                if let Ok((Ok(file_type), path)) =
-                    entry.map(|entry| (entry.file_type(), entry.path()))
-                {
-                    if file_type.is_file() {
-                        Some(
-                            hex::decode(path.file_name().unwrap().as_bytes())
-                                .map_err(FsPersisterError::from),
-                        )
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
-            .collect()
+        let flights = flight::lock(&self.flights);
+        let pending = self.pending_sync_states(&flights);
+        let mut peer_ids = pending
+            .iter()
+            .filter(|(_, sync_state)| sync_state.is_some())
+            .map(|(peer_id, _)| peer_id.to_vec())
+            .collect::<Vec<_>>();
+        for entry in self.fs.read_dir(&self.sync_states_path)? {
+            if entry.is_dir || is_temp_name(&entry.name) || entry.name == PEER_INDEX_FILE {
+                continue;
+            }
+            let peer_id = self.peers.peer_id(&self.sync_states_path, &entry.name)?;
+            if !pending.contains_key(peer_id.as_slice()) {
+                peer_ids.push(peer_id);
+            }
+        }
+        Ok(peer_ids)
     }
 
     fn sizes(&self) -> StoredSizes {
@@ -359,13 +1322,35 @@ impl Persister for FsPersister {
     }
 
     fn flush(&mut self) -> Result<usize, Self::Error> {
-        self.cache
-            .drain_clone()
-            .flush(
+        self.land_flights();
+        let mut cache = self.take_flushable(true);
+        let flushed = if let Some(log) = &mut self.segments {
+            let mut failures = Failures::default();
+            let mut flushed = flush_segments(
+                log,
+                &self.fs,
+                &mut cache,
+                &self.doc_path,
+                self.durability,
+                &mut failures,
+            );
+            flushed += cache.flush_sync_states(
+                &self.fs,
+                &self.sync_states_path,
+                self.durability,
+                &mut failures,
+            );
+            failures.into_result(flushed)
+        } else {
+            cache.flush(
+                &self.fs,
                 self.doc_path.clone(),
                 self.changes_path.clone(),
                 self.sync_states_path.clone(),
+                self.durability,
             )
-            .map_err(FsPersisterError::from)
+        };
+        self.cache.requeue(cache);
+        Ok(flushed?)
     }
 }
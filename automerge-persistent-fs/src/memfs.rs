@@ -0,0 +1,363 @@
+//! An in-memory [`FileSystem`] that models what survives a power loss.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::{
+    filesystem::{DirEntry, FileSystem, Metadata},
+    Durability,
+};
+
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EACCES: i32 = 13;
+const EEXIST: i32 = 17;
+const ENOTDIR: i32 = 20;
+const EISDIR: i32 = 21;
+const ENOSPC: i32 = 28;
+
+fn error(code: i32) -> io::Error {
+    io::Error::from_raw_os_error(code)
+}
+
+/// A filesystem held in memory, for testing how the persister copes with crashes and faults.
+///
+/// Like a real filesystem, writes only become durable once synced: file contents when the file is
+/// written with a [`Durability`] other than `None`, and directory entries (created, renamed or
+/// removed files and directories) when their directory is synced. [`MemoryFileSystem::crash`]
+/// throws away everything else, as a power loss would.
+///
+/// Clones share the same files, so one can be kept to inject faults or crash while another is in
+/// use by a persister.
+///
+/// ```rust
+/// # use automerge::ActorId;
+/// # use automerge_persistent::Persister;
+/// # use automerge_persistent_fs::{Durability, FsPersister, MemoryFileSystem, StorageMode};
+/// let fs = MemoryFileSystem::default();
+/// let mut persister =
+///     FsPersister::with_fs(fs.clone(), "/data", "doc", StorageMode::Files, Durability::Full)
+///         .unwrap();
+/// persister
+///     .insert_changes(vec![(ActorId::random(), 1, vec![1, 2, 3])])
+///     .unwrap();
+/// persister.flush().unwrap();
+///
+/// fs.crash();
+/// let persister =
+///     FsPersister::with_fs(fs, "/data", "doc", StorageMode::Files, Durability::Full).unwrap();
+/// assert_eq!(persister.get_changes().unwrap(), vec![vec![1, 2, 3]]);
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct MemoryFileSystem {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    dirs: BTreeMap<PathBuf, Dir>,
+    files: Vec<File>,
+    capacity: Option<u64>,
+    read_only: Vec<PathBuf>,
+    fail_syncs: bool,
+}
+
+#[derive(Debug, Default)]
+struct Dir {
+    entries: BTreeMap<OsString, Node>,
+    durable: BTreeMap<OsString, Node>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Node {
+    File(usize),
+    Dir,
+}
+
+#[derive(Debug, Default)]
+struct File {
+    data: Vec<u8>,
+    durable: Vec<u8>,
+}
+
+impl MemoryFileSystem {
+    fn state(&self) -> MutexGuard<'_, State> {
+        // a panic while holding the lock can't leave the state half updated
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Simulate a power loss, dropping anything that wasn't synced.
+    pub fn crash(&self) {
+        let mut state = self.state();
+        for file in &mut state.files {
+            file.data = file.durable.clone();
+        }
+        for dir in state.dirs.values_mut() {
+            dir.entries = dir.durable.clone();
+        }
+        // directories whose entries were lost go with them, parents sort before their children
+        let paths = state.dirs.keys().cloned().collect::<Vec<_>>();
+        for path in paths {
+            let reachable = match (path.parent(), path.file_name()) {
+                (Some(parent), Some(name)) => {
+                    state
+                        .dirs
+                        .get(parent)
+                        .and_then(|dir| dir.entries.get(name))
+                        .copied()
+                        == Some(Node::Dir)
+                }
+                _ => true,
+            };
+            if !reachable {
+                state.dirs.remove(&path);
+            }
+        }
+    }
+
+    /// Limit the total size of all files, writes that would go over it fail with `ENOSPC`.
+    pub fn set_capacity(&self, capacity: Option<u64>) {
+        self.state().capacity = capacity;
+    }
+
+    /// Make changes to anything under `path` fail with `EACCES`, or allow them again.
+    pub fn set_read_only<P: AsRef<Path>>(&self, path: P, read_only: bool) {
+        let mut state = self.state();
+        let path = path.as_ref().to_path_buf();
+        state.read_only.retain(|p| p != &path);
+        if read_only {
+            state.read_only.push(path);
+        }
+    }
+
+    /// Make syncing files and directories fail with `EIO`, or succeed again.
+    pub fn set_fail_syncs(&self, fail: bool) {
+        self.state().fail_syncs = fail;
+    }
+}
+
+impl State {
+    fn node(&self, path: &Path) -> io::Result<Node> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => self
+                .dirs
+                .get(parent)
+                .and_then(|dir| dir.entries.get(name))
+                .copied()
+                .ok_or_else(|| error(ENOENT)),
+            // a filesystem root
+            _ => Ok(Node::Dir),
+        }
+    }
+
+    fn file(&self, path: &Path) -> io::Result<usize> {
+        match self.node(path)? {
+            Node::File(ino) => Ok(ino),
+            Node::Dir => Err(error(EISDIR)),
+        }
+    }
+
+    /// The directory containing `path` along with the name of `path` in it.
+    fn parent_mut(&mut self, path: &Path) -> io::Result<(&mut Dir, OsString)> {
+        let name = path.file_name().ok_or_else(|| error(EEXIST))?.to_owned();
+        let parent = path.parent().unwrap_or_else(|| Path::new(""));
+        if self.node(parent)? != Node::Dir {
+            return Err(error(ENOTDIR));
+        }
+        Ok((self.dirs.entry(parent.to_path_buf()).or_default(), name))
+    }
+
+    fn check_writable(&self, path: &Path) -> io::Result<()> {
+        if self.read_only.iter().any(|p| path.starts_with(p)) {
+            return Err(error(EACCES));
+        }
+        Ok(())
+    }
+
+    fn check_space(&self, old_len: usize, new_len: usize) -> io::Result<()> {
+        if let Some(capacity) = self.capacity {
+            let used: usize = self
+                .dirs
+                .values()
+                .flat_map(|dir| dir.entries.values())
+                .map(|node| match node {
+                    Node::File(ino) => self.files[*ino].data.len(),
+                    Node::Dir => 0,
+                })
+                .sum();
+            if (used - old_len + new_len) as u64 > capacity {
+                return Err(error(ENOSPC));
+            }
+        }
+        Ok(())
+    }
+
+    fn sync_file(&mut self, ino: usize, durability: Durability) -> io::Result<()> {
+        if durability != Durability::None {
+            if self.fail_syncs {
+                return Err(error(EIO));
+            }
+            let file = &mut self.files[ino];
+            file.durable = file.data.clone();
+        }
+        Ok(())
+    }
+
+    /// The file at `path`, created if it doesn't exist.
+    fn open(&mut self, path: &Path) -> io::Result<usize> {
+        match self.node(path) {
+            Ok(Node::File(ino)) => return Ok(ino),
+            Ok(Node::Dir) => return Err(error(EISDIR)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let ino = self.files.len();
+        let (parent, name) = self.parent_mut(path)?;
+        parent.entries.insert(name, Node::File(ino));
+        self.files.push(File::default());
+        Ok(ino)
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let state = self.state();
+        let ino = state.file(path)?;
+        Ok(state.files[ino].data.clone())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.state();
+        Ok(match state.node(path)? {
+            Node::File(ino) => Metadata {
+                len: state.files[ino].data.len() as u64,
+                is_dir: false,
+            },
+            Node::Dir => Metadata {
+                len: 0,
+                is_dir: true,
+            },
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let state = self.state();
+        if state.node(path)? != Node::Dir {
+            return Err(error(ENOTDIR));
+        }
+        Ok(state.dirs.get(path).map_or_else(Vec::new, |dir| {
+            dir.entries
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    is_dir: *node == Node::Dir,
+                })
+                .collect()
+        }))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.check_writable(path)?;
+        if state.node(path).is_ok() {
+            return Err(error(EEXIST));
+        }
+        let (parent, name) = state.parent_mut(path)?;
+        parent.entries.insert(name, Node::Dir);
+        state.dirs.insert(path.to_path_buf(), Dir::default());
+        Ok(())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut ancestors = path.ancestors().collect::<Vec<_>>();
+        ancestors.reverse();
+        for dir in ancestors {
+            match self.create_dir(dir) {
+                Ok(()) => {}
+                Err(_) if matches!(self.metadata(dir), Ok(m) if m.is_dir) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, data: &[u8], durability: Durability) -> io::Result<()> {
+        let mut state = self.state();
+        state.check_writable(path)?;
+        let old_len = match state.file(path) {
+            Ok(ino) => state.files[ino].data.len(),
+            Err(_) => 0,
+        };
+        state.check_space(old_len, data.len())?;
+        let ino = state.open(path)?;
+        state.files[ino].data = data.to_vec();
+        state.sync_file(ino, durability)
+    }
+
+    fn append(&self, path: &Path, data: &[u8], durability: Durability) -> io::Result<()> {
+        let mut state = self.state();
+        state.check_writable(path)?;
+        state.check_space(0, data.len())?;
+        let ino = state.open(path)?;
+        let base = state.files[ino].data.len();
+        state.files[ino].data.extend_from_slice(data);
+        let result = state.sync_file(ino, durability);
+        if result.is_err() {
+            state.files[ino].data.truncate(base);
+        }
+        result
+    }
+
+    fn truncate(&self, path: &Path, len: u64, durability: Durability) -> io::Result<()> {
+        let mut state = self.state();
+        state.check_writable(path)?;
+        let ino = state.file(path)?;
+        state.files[ino].data.resize(len as usize, 0);
+        state.sync_file(ino, durability)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.check_writable(from)?;
+        state.check_writable(to)?;
+        let ino = state.file(from)?;
+        if matches!(state.node(to), Ok(Node::Dir)) {
+            return Err(error(EISDIR));
+        }
+        if from == to {
+            return Ok(());
+        }
+        let (parent, name) = state.parent_mut(to)?;
+        parent.entries.insert(name, Node::File(ino));
+        let (parent, name) = state.parent_mut(from)?;
+        parent.entries.remove(&name);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.check_writable(path)?;
+        state.file(path)?;
+        let (parent, name) = state.parent_mut(path)?;
+        parent.entries.remove(&name);
+        Ok(())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        if state.node(path)? != Node::Dir {
+            return Err(error(ENOTDIR));
+        }
+        if state.fail_syncs {
+            return Err(error(EIO));
+        }
+        let dir = state.dirs.entry(path.to_path_buf()).or_default();
+        dir.durable = dir.entries.clone();
+        Ok(())
+    }
+}
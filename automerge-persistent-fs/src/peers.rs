@@ -0,0 +1,274 @@
+//! Naming sync state files after the peer they belong to.
+//!
+//! Short peer ids are hex encoded into the file name. Long ones would go over filename length
+//! limits, so they are named after a hash of the id instead and the ids themselves are kept in an
+//! index file alongside, so that [`get_peer_ids`](automerge_persistent::Persister::get_peer_ids)
+//! can still return them.
+//!
+//! Earlier versions hex encoded ids of up to 127 bytes, those that are now hashed are moved over
+//! when opening.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+#[cfg(feature = "async")]
+use crate::durability::{sync_dir_async, write_file_async};
+use crate::{
+    durability::{is_temp_name, write_file},
+    Durability, FileSystem, FsPersisterError,
+};
+
+/// The longest peer id, in bytes, that is stored under its hex encoding.
+///
+/// Its file name plus the temporary file decoration stays well within the common 255 byte limit.
+const MAX_HEX_PEER_ID: usize = 100;
+
+/// The prefix of file names made from a hash, hex names can never contain the `-`.
+const HASHED_PREFIX: &str = "sha256-";
+
+/// The file in the sync states directory listing the peer ids that have hashed file names.
+pub(crate) const PEER_INDEX_FILE: &str = "index";
+
+/// The name of the file holding the sync state for `peer_id`.
+fn peer_file_name(peer_id: &[u8]) -> String {
+    if peer_id.len() <= MAX_HEX_PEER_ID {
+        hex::encode(peer_id)
+    } else {
+        format!("{}{}", HASHED_PREFIX, hex::encode(Sha256::digest(peer_id)))
+    }
+}
+
+/// Whether the peer id needs an entry in the index to be found from its file name.
+const fn is_hashed(peer_id: &[u8]) -> bool {
+    peer_id.len() > MAX_HEX_PEER_ID
+}
+
+/// The long peer ids that have been given hashed file names, by file name.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct PeerIndex {
+    peers: HashMap<String, Vec<u8>>,
+}
+
+impl PeerIndex {
+    /// Read the index from the sync states directory, bringing it and the file names up to date.
+    ///
+    /// Sync states under the hex encoding of an id that is now hashed are renamed, and entries
+    /// left behind by sync states that are gone are dropped.
+    pub(crate) fn open<F: FileSystem>(
+        fs: &F,
+        sync_states_path: &Path,
+        durability: Durability,
+    ) -> Result<Self, FsPersisterError> {
+        let mut index = Self::load(fs, sync_states_path)?;
+        let mut names = HashSet::new();
+        let mut renamed = false;
+        for entry in fs.read_dir(sync_states_path)? {
+            if entry.is_dir || is_temp_name(&entry.name) || entry.name == PEER_INDEX_FILE {
+                continue;
+            }
+            let name = entry.name.as_bytes();
+            if name.starts_with(HASHED_PREFIX.as_bytes()) || name.len() <= 2 * MAX_HEX_PEER_ID {
+                names.insert(entry.name.to_string_lossy().into_owned());
+                continue;
+            }
+            let peer_id = hex::decode(name)?;
+            // the index has to have the id before the file takes its hashed name
+            index.record(fs, sync_states_path, &peer_id, durability)?;
+            let path = make_peer_path(sync_states_path, &peer_id);
+            fs.rename(&sync_states_path.join(&entry.name), &path)?;
+            names.insert(peer_file_name(&peer_id));
+            renamed = true;
+        }
+        if renamed && durability.sync_dirs() {
+            fs.sync_dir(sync_states_path)?;
+        }
+
+        let len = index.peers.len();
+        index.peers.retain(|name, _| names.contains(name));
+        if index.peers.len() != len {
+            index.store(fs, sync_states_path, durability)?;
+        }
+        Ok(index)
+    }
+
+    /// Read the index from the sync states directory, it is empty if the file doesn't exist.
+    fn load<F: FileSystem>(fs: &F, sync_states_path: &Path) -> Result<Self, FsPersisterError> {
+        let data = match fs.read(&sync_states_path.join(PEER_INDEX_FILE)) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e.into()),
+        };
+        Self::parse(&data)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, FsPersisterError> {
+        let mut index = Self::default();
+        for line in data.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+            let peer_id = hex::decode(line)?;
+            index.peers.insert(peer_file_name(&peer_id), peer_id);
+        }
+        Ok(index)
+    }
+
+    /// The index as stored on disk, a hex encoded peer id per line.
+    fn encode(&self) -> Vec<u8> {
+        let mut peer_ids = self.peers.values().map(hex::encode).collect::<Vec<_>>();
+        peer_ids.sort();
+        let mut data = Vec::new();
+        for peer_id in peer_ids {
+            data.extend_from_slice(peer_id.as_bytes());
+            data.push(b'\n');
+        }
+        data
+    }
+
+    /// Add a peer id, returning false if it was already there.
+    fn insert(&mut self, peer_id: Vec<u8>) -> bool {
+        self.peers
+            .insert(peer_file_name(&peer_id), peer_id)
+            .is_none()
+    }
+
+    fn remove(&mut self, peer_id: &[u8]) {
+        self.peers.remove(&peer_file_name(peer_id));
+    }
+
+    /// Remove the long ones of `peer_ids`, returning whether any were there.
+    fn remove_all(&mut self, peer_ids: &[&[u8]]) -> bool {
+        let mut removed = false;
+        for peer_id in peer_ids.iter().filter(|p| is_hashed(p)) {
+            removed |= self.peers.remove(&peer_file_name(peer_id)).is_some();
+        }
+        removed
+    }
+
+    /// Make sure a long peer id is in the index, writing it out before any sync state for the
+    /// peer can be.
+    pub(crate) fn record<F: FileSystem>(
+        &mut self,
+        fs: &F,
+        sync_states_path: &Path,
+        peer_id: &[u8],
+        durability: Durability,
+    ) -> Result<(), FsPersisterError> {
+        if !is_hashed(peer_id) || !self.insert(peer_id.to_vec()) {
+            return Ok(());
+        }
+        if let Err(e) = self.store(fs, sync_states_path, durability) {
+            self.remove(peer_id);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Drop peer ids from the index, once their sync states have been deleted.
+    pub(crate) fn forget<F: FileSystem>(
+        &mut self,
+        fs: &F,
+        sync_states_path: &Path,
+        peer_ids: &[&[u8]],
+        durability: Durability,
+    ) -> Result<(), FsPersisterError> {
+        if !self.remove_all(peer_ids) {
+            return Ok(());
+        }
+        Ok(self.store(fs, sync_states_path, durability)?)
+    }
+
+    /// Write the index out.
+    fn store<F: FileSystem>(
+        &self,
+        fs: &F,
+        sync_states_path: &Path,
+        durability: Durability,
+    ) -> std::io::Result<()> {
+        write_file(
+            fs,
+            &sync_states_path.join(PEER_INDEX_FILE),
+            &self.encode(),
+            durability,
+        )?;
+        if durability.sync_dirs() {
+            fs.sync_dir(sync_states_path)?;
+        }
+        Ok(())
+    }
+
+    /// [`PeerIndex::record`] on `tokio::fs`.
+    #[cfg(feature = "async")]
+    pub(crate) async fn record_async(
+        &mut self,
+        sync_states_path: &Path,
+        peer_id: &[u8],
+        durability: Durability,
+    ) -> Result<(), FsPersisterError> {
+        if !is_hashed(peer_id) || !self.insert(peer_id.to_vec()) {
+            return Ok(());
+        }
+        if let Err(e) = self.store_async(sync_states_path, durability).await {
+            self.remove(peer_id);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// [`PeerIndex::forget`] on `tokio::fs`.
+    #[cfg(feature = "async")]
+    pub(crate) async fn forget_async(
+        &mut self,
+        sync_states_path: &Path,
+        peer_ids: &[&[u8]],
+        durability: Durability,
+    ) -> Result<(), FsPersisterError> {
+        if !self.remove_all(peer_ids) {
+            return Ok(());
+        }
+        Ok(self.store_async(sync_states_path, durability).await?)
+    }
+
+    /// [`PeerIndex::store`] on `tokio::fs`.
+    #[cfg(feature = "async")]
+    async fn store_async(
+        &self,
+        sync_states_path: &Path,
+        durability: Durability,
+    ) -> std::io::Result<()> {
+        write_file_async(
+            sync_states_path.join(PEER_INDEX_FILE),
+            &self.encode(),
+            durability,
+        )
+        .await?;
+        if durability.sync_dirs() {
+            sync_dir_async(sync_states_path.to_owned()).await?;
+        }
+        Ok(())
+    }
+
+    /// The peer id whose sync state is stored in the file `name`.
+    pub(crate) fn peer_id(
+        &self,
+        sync_states_path: &Path,
+        name: &OsStr,
+    ) -> Result<Vec<u8>, FsPersisterError> {
+        let bytes = name.as_bytes();
+        if !bytes.starts_with(HASHED_PREFIX.as_bytes()) {
+            return Ok(hex::decode(bytes)?);
+        }
+        name.to_str()
+            .and_then(|name| self.peers.get(name))
+            .cloned()
+            .ok_or_else(|| FsPersisterError::UnindexedPeer(sync_states_path.join(name)))
+    }
+}
+
+/// The file the sync state for `peer_id` is stored in.
+pub(crate) fn make_peer_path<P: AsRef<Path>>(sync_states_path: P, peer_id: &[u8]) -> PathBuf {
+    sync_states_path.as_ref().join(peer_file_name(peer_id))
+}
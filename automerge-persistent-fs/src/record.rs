@@ -0,0 +1,126 @@
+//! Framing for records appended to log files.
+//!
+//! Each record is laid out as:
+//!
+//! ```text
+//! [body length: u32 BE][crc32 of body: u32 BE][body]
+//! ```
+//!
+//! A record that is cut short or fails its checksum marks the end of the valid data. If the record
+//! runs past the end of the file, or nothing complete follows it, the rest is the remains of a
+//! torn append. Otherwise data that was already written has been damaged.
+
+const HEADER_LEN: usize = 8;
+
+/// Append a framed record with the given body parts to `buf`.
+pub(crate) fn encode_into(buf: &mut Vec<u8>, parts: &[&[u8]]) {
+    let len: usize = parts.iter().map(|p| p.len()).sum();
+    let mut hasher = crc32fast::Hasher::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    buf.reserve(HEADER_LEN + len);
+    buf.extend(&(len as u32).to_be_bytes());
+    buf.extend(&hasher.finalize().to_be_bytes());
+    for part in parts {
+        buf.extend(*part);
+    }
+}
+
+/// Decode the valid records at the start of `buf`.
+///
+/// Returns the bodies of the records, paired with their offset in `buf`, along with the number of
+/// bytes they cover. If this is less than the length of `buf` then the remainder is torn or
+/// corrupt.
+pub(crate) fn decode(buf: &[u8]) -> (Vec<(usize, &[u8])>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while buf.len() - offset >= HEADER_LEN {
+        let header = &buf[offset..offset + HEADER_LEN];
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let start = offset + HEADER_LEN;
+        if buf.len() - start < len {
+            break;
+        }
+        let body = &buf[start..start + len];
+        if crc32fast::hash(body) != crc {
+            break;
+        }
+        records.push((start, body));
+        offset = start + len;
+    }
+    (records, offset)
+}
+
+/// Whether the data in `buf` after the valid records, which end at `valid`, is the remains of a
+/// torn append and so can be cut off.
+///
+/// It isn't if the bad record is followed by a complete record, as that was written after it.
+/// Records with empty bodies aren't counted, as they are never written and zeroed space would
+/// otherwise look like them.
+pub(crate) fn is_torn_tail(buf: &[u8], valid: usize) -> bool {
+    let rest = &buf[valid..];
+    if rest.len() < HEADER_LEN {
+        return true;
+    }
+    let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+    let Some(after) = rest.get(HEADER_LEN + len..) else {
+        // runs past the end of the file
+        return true;
+    };
+    decode(after)
+        .0
+        .first()
+        .is_none_or(|(_, body)| body.is_empty())
+}
+
+/// A cursor for reading the fields of a record body.
+pub(crate) struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    pub(crate) const fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        let (first, rest) = self.buf.split_first()?;
+        self.buf = rest;
+        Some(*first)
+    }
+
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        let bytes = self.take(8)?;
+        let mut array = [0; 8];
+        array.copy_from_slice(bytes);
+        Some(u64::from_be_bytes(array))
+    }
+
+    /// A byte string prefixed with its length as a u32.
+    pub(crate) fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.take(4)?;
+        let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        self.take(len)
+    }
+
+    /// Everything that is left.
+    pub(crate) fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (first, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(first)
+    }
+}
+
+/// The length prefix for a byte string field, see [`Fields::bytes`].
+pub(crate) fn len_prefix(bytes: &[u8]) -> [u8; 4] {
+    (bytes.len() as u32).to_be_bytes()
+}
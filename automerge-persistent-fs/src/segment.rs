@@ -0,0 +1,621 @@
+//! An append-only log of changes split over rolling segment files.
+//!
+//! Inserting changes appends records to the active segment and removing them appends tombstones.
+//! An in-memory index, rebuilt on open, tracks where each live change is stored. Once a segment
+//! holds no live changes, and neither do any before it, it is deleted.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+};
+
+use automerge::ActorId;
+
+use crate::{
+    durability::create_dirs,
+    record::{self, len_prefix, Fields},
+    Durability, FileSystem, FsPersisterError,
+};
+
+/// The default size a segment can grow to before a new one is started.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+const SEGMENT_EXTENSION: &str = "log";
+
+const CHANGE_RECORD: u8 = 0;
+const REMOVE_RECORD: u8 = 1;
+
+#[derive(Debug, Default)]
+struct Segment {
+    /// Length of the valid data in the segment file.
+    len: u64,
+    /// Number of live changes stored in this segment.
+    live: usize,
+}
+
+/// Where the data for a change lives.
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct SegmentLog {
+    dir: PathBuf,
+    max_segment_size: u64,
+    segments: BTreeMap<u64, Segment>,
+    index: HashMap<(ActorId, u64), Location>,
+    /// The segment that new records are appended to, this may not exist on disk yet.
+    active: u64,
+    /// Whether an append may have been left part written past the end of the active segment, so
+    /// that the next one needs to cut it off first.
+    torn: bool,
+}
+
+enum Entry<'a> {
+    Change(ActorId, u64, &'a [u8]),
+    Remove(ActorId, u64),
+}
+
+fn parse_entry(body: &[u8]) -> Option<Entry<'_>> {
+    let mut fields = Fields::new(body);
+    let kind = fields.u8()?;
+    let actor = ActorId::from(fields.bytes()?);
+    let seq = fields.u64()?;
+    match kind {
+        CHANGE_RECORD => Some(Entry::Change(actor, seq, fields.rest())),
+        REMOVE_RECORD => Some(Entry::Remove(actor, seq)),
+        _ => None,
+    }
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:016x}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn parse_segment_id(path: &Path) -> Option<u64> {
+    if path.extension()? != SEGMENT_EXTENSION {
+        return None;
+    }
+    u64::from_str_radix(path.file_stem()?.to_str()?, 16).ok()
+}
+
+impl SegmentLog {
+    /// Open the log in `dir`, replaying all segments to rebuild the index.
+    ///
+    /// A torn record at the end of the last segment is truncated away, anywhere else it is an
+    /// error.
+    pub(crate) fn open<F: FileSystem>(
+        fs: &F,
+        dir: PathBuf,
+        max_segment_size: u64,
+        durability: Durability,
+    ) -> Result<Self, FsPersisterError> {
+        create_dirs(fs, &dir, durability)?;
+
+        let mut ids = Vec::new();
+        for entry in fs.read_dir(&dir)? {
+            if entry.is_dir {
+                continue;
+            }
+            if let Some(id) = parse_segment_id(&dir.join(&entry.name)) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+
+        let mut log = Self {
+            dir,
+            max_segment_size,
+            segments: BTreeMap::new(),
+            index: HashMap::new(),
+            active: ids.last().copied().unwrap_or_default(),
+            torn: false,
+        };
+
+        for id in ids {
+            let path = segment_path(&log.dir, id);
+            let data = fs.read(&path)?;
+            let (records, valid) = record::decode(&data);
+            if valid < data.len() {
+                if id != log.active || !record::is_torn_tail(&data, valid) {
+                    return Err(FsPersisterError::CorruptSegment(path));
+                }
+                // torn write at the tail of the log
+                fs.truncate(&path, valid as u64, durability)?;
+            }
+            log.segments.insert(
+                id,
+                Segment {
+                    len: valid as u64,
+                    live: 0,
+                },
+            );
+            for (offset, body) in records {
+                let entry = parse_entry(body)
+                    .ok_or_else(|| FsPersisterError::CorruptSegment(path.clone()))?;
+                match entry {
+                    Entry::Change(actor, seq, change) => {
+                        let location = Location {
+                            segment: id,
+                            offset: (offset + body.len() - change.len()) as u64,
+                            len: change.len() as u64,
+                        };
+                        log.insert_location(actor, seq, location);
+                    }
+                    Entry::Remove(actor, seq) => log.remove_location(&(actor, seq)),
+                }
+            }
+        }
+
+        log.roll_if_full();
+        Ok(log)
+    }
+
+    fn insert_location(&mut self, actor: ActorId, seq: u64, location: Location) {
+        self.segments.entry(location.segment).or_default().live += 1;
+        if let Some(old) = self.index.insert((actor, seq), location) {
+            if let Some(segment) = self.segments.get_mut(&old.segment) {
+                segment.live -= 1;
+            }
+        }
+    }
+
+    fn remove_location(&mut self, key: &(ActorId, u64)) {
+        if let Some(old) = self.index.remove(key) {
+            if let Some(segment) = self.segments.get_mut(&old.segment) {
+                segment.live -= 1;
+            }
+        }
+    }
+
+    /// The length of each live change.
+    pub(crate) fn change_lens(&self) -> impl Iterator<Item = (&(ActorId, u64), u64)> {
+        self.index.iter().map(|(key, l)| (key, l.len))
+    }
+
+    /// The length of the live change stored under `key`, if there is one.
+    pub(crate) fn change_len(&self, key: &(ActorId, u64)) -> Option<u64> {
+        self.index.get(key).map(|l| l.len)
+    }
+
+    /// Group the locations of live changes by the segment they are in, leaving out those for
+    /// which `skip` returns true.
+    fn locations_by_segment<F>(&self, skip: F) -> BTreeMap<u64, Vec<Location>>
+    where
+        F: Fn(&(ActorId, u64)) -> bool,
+    {
+        let mut by_segment: BTreeMap<u64, Vec<Location>> = BTreeMap::new();
+        for (key, location) in &self.index {
+            if skip(key) {
+                continue;
+            }
+            by_segment
+                .entry(location.segment)
+                .or_default()
+                .push(*location);
+        }
+        by_segment
+    }
+
+    /// Read the live changes out of the segments, other than those for which `skip` returns true.
+    pub(crate) fn read_changes<F, S>(
+        &self,
+        fs: &F,
+        skip: S,
+    ) -> Result<Vec<Vec<u8>>, FsPersisterError>
+    where
+        F: FileSystem,
+        S: Fn(&(ActorId, u64)) -> bool,
+    {
+        let mut changes = Vec::with_capacity(self.index.len());
+        for (id, locations) in self.locations_by_segment(skip) {
+            let path = segment_path(&self.dir, id);
+            let data = fs.read(&path)?;
+            extract_changes(&path, &data, &locations, &mut changes)?;
+        }
+        Ok(changes)
+    }
+
+    /// Read the live changes out of the segments, other than those for which `skip` returns true.
+    #[cfg(feature = "async")]
+    pub(crate) async fn read_changes_async<F>(
+        &self,
+        skip: F,
+    ) -> Result<Vec<Vec<u8>>, FsPersisterError>
+    where
+        F: Fn(&(ActorId, u64)) -> bool,
+    {
+        let mut changes = Vec::with_capacity(self.index.len());
+        for (id, locations) in self.locations_by_segment(skip) {
+            let path = segment_path(&self.dir, id);
+            let data = tokio::fs::read(&path).await?;
+            extract_changes(&path, &data, &locations, &mut changes)?;
+        }
+        Ok(changes)
+    }
+
+    /// Append the given changes to the log, returning the number of change bytes written.
+    pub(crate) fn append_changes<F: FileSystem>(
+        &mut self,
+        fs: &F,
+        changes: &HashMap<(ActorId, u64), Vec<u8>>,
+        durability: Durability,
+    ) -> io::Result<usize> {
+        let flushed = changes.values().map(Vec::len).sum();
+        if let Some(append) = self.encode_changes(changes) {
+            append.write(fs, durability)?;
+            self.commit(append);
+        }
+        Ok(flushed)
+    }
+
+    /// Remove the given changes from the log, any that are not in it are ignored.
+    ///
+    /// Segments left without any live changes are deleted.
+    pub(crate) fn remove_changes<F: FileSystem>(
+        &mut self,
+        fs: &F,
+        changes: Vec<(&ActorId, u64)>,
+        durability: Durability,
+    ) -> io::Result<()> {
+        match self.encode_removals(changes) {
+            Removals::Nothing => {}
+            Removals::All => {
+                let all = self.segments.keys().copied().collect::<Vec<_>>();
+                return self.delete_segments(fs, &all, durability);
+            }
+            Removals::Tombstones(append) => {
+                append.write(fs, durability)?;
+                self.commit(append);
+            }
+        }
+        let dead = self.dead_segments();
+        if dead.is_empty() {
+            return Ok(());
+        }
+        self.delete_segments(fs, &dead, durability)
+    }
+
+    /// Delete the segments `ids`, which start the log, then forget them along with any changes
+    /// they hold.
+    ///
+    /// If one can't be deleted those before it are still forgotten, so that the index matches
+    /// what is on disk.
+    fn delete_segments<F: FileSystem>(
+        &mut self,
+        fs: &F,
+        ids: &[u64],
+        durability: Durability,
+    ) -> io::Result<()> {
+        let mut deleted = 0;
+        let mut result = Ok(());
+        for id in ids {
+            if let Err(e) = remove_if_exists(fs, &segment_path(&self.dir, *id)) {
+                result = Err(e);
+                break;
+            }
+            deleted += 1;
+        }
+        if deleted > 0 && result.is_ok() && durability.sync_dirs() {
+            result = fs.sync_dir(&self.dir);
+        }
+        self.forget_segments(&ids[..deleted]);
+        result
+    }
+
+    /// Remove the given changes from the log, any that are not in it are ignored.
+    ///
+    /// Segments left without any live changes are deleted.
+    #[cfg(feature = "async")]
+    pub(crate) async fn remove_changes_async(
+        &mut self,
+        changes: Vec<(&ActorId, u64)>,
+        durability: Durability,
+    ) -> io::Result<()> {
+        match self.encode_removals(changes) {
+            Removals::Nothing => {}
+            Removals::All => {
+                let all = self.segments.keys().copied().collect::<Vec<_>>();
+                return self.delete_segments_async(&all, durability).await;
+            }
+            Removals::Tombstones(append) => {
+                // cleared by the commit, so if this future is dropped while writing the next
+                // append cuts off what it left
+                self.abandon_append();
+                append.write_async(durability).await?;
+                self.commit(append);
+            }
+        }
+        let dead = self.dead_segments();
+        if dead.is_empty() {
+            return Ok(());
+        }
+        self.delete_segments_async(&dead, durability).await
+    }
+
+    /// Delete the segments `ids`, which start the log, then forget them along with any changes
+    /// they hold.
+    ///
+    /// If one can't be deleted those before it are still forgotten, so that the index matches
+    /// what is on disk.
+    #[cfg(feature = "async")]
+    async fn delete_segments_async(
+        &mut self,
+        ids: &[u64],
+        durability: Durability,
+    ) -> io::Result<()> {
+        let mut deleted = 0;
+        let mut result = Ok(());
+        for id in ids {
+            match tokio::fs::remove_file(segment_path(&self.dir, *id)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+            deleted += 1;
+        }
+        if deleted > 0 && result.is_ok() && durability.sync_dirs() {
+            result = crate::durability::sync_dir_async(self.dir.clone()).await;
+        }
+        self.forget_segments(&ids[..deleted]);
+        result
+    }
+
+    /// Encode records for the changes, to be appended to the active segment.
+    ///
+    /// Nothing else may be appended until the append has been written and committed, or
+    /// abandoned.
+    pub(crate) fn encode_changes(
+        &mut self,
+        changes: &HashMap<(ActorId, u64), Vec<u8>>,
+    ) -> Option<Append> {
+        if changes.is_empty() {
+            return None;
+        }
+        self.roll_if_full();
+        let mut append = self.new_append();
+        for ((actor, seq), change) in changes {
+            let actor_bytes = actor.to_bytes();
+            record::encode_into(
+                &mut append.buf,
+                &[
+                    &[CHANGE_RECORD],
+                    &len_prefix(actor_bytes),
+                    actor_bytes,
+                    &seq.to_be_bytes(),
+                    change,
+                ],
+            );
+            let offset = append.base + (append.buf.len() - change.len()) as u64;
+            append.inserted.push((
+                actor.clone(),
+                *seq,
+                Location {
+                    segment: self.active,
+                    offset,
+                    len: change.len() as u64,
+                },
+            ));
+        }
+        Some(append)
+    }
+
+    /// Work out which of the changes are live and encode tombstones for them.
+    fn encode_removals(&mut self, changes: Vec<(&ActorId, u64)>) -> Removals {
+        let keys = changes
+            .into_iter()
+            .map(|(a, s)| (a.clone(), s))
+            .filter(|key| self.index.contains_key(key))
+            .collect::<HashSet<_>>();
+        if keys.is_empty() {
+            return Removals::Nothing;
+        }
+        if keys.len() == self.index.len() {
+            return Removals::All;
+        }
+
+        self.roll_if_full();
+        let mut append = self.new_append();
+        for (actor, seq) in &keys {
+            let actor_bytes = actor.to_bytes();
+            record::encode_into(
+                &mut append.buf,
+                &[
+                    &[REMOVE_RECORD],
+                    &len_prefix(actor_bytes),
+                    actor_bytes,
+                    &seq.to_be_bytes(),
+                ],
+            );
+        }
+        append.removed = keys.into_iter().collect();
+        Removals::Tombstones(append)
+    }
+
+    fn new_append(&self) -> Append {
+        Append {
+            dir: self.dir.clone(),
+            path: segment_path(&self.dir, self.active),
+            base: self.active_len(),
+            created: !self.segments.contains_key(&self.active),
+            truncate: self.torn,
+            buf: Vec::new(),
+            inserted: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+
+    /// Update the index once an append has been written.
+    pub(crate) fn commit(&mut self, append: Append) {
+        self.torn = false;
+        self.segments.entry(self.active).or_default().len = append.base + append.buf.len() as u64;
+        for (actor, seq, location) in append.inserted {
+            self.insert_location(actor, seq, location);
+        }
+        for key in &append.removed {
+            self.remove_location(key);
+        }
+    }
+
+    /// Record that an append was started but not finished, such as when the future writing it
+    /// was dropped.
+    pub(crate) fn abandon_append(&mut self) {
+        self.torn = true;
+    }
+
+    /// Segments from the start of the log that have no live changes.
+    ///
+    /// Only leading segments can go as later segments may hold tombstones for changes in earlier
+    /// ones. When nothing is live at all every segment goes.
+    fn dead_segments(&self) -> Vec<u64> {
+        let all_dead = self.index.is_empty();
+        self.segments
+            .iter()
+            .take_while(|(id, segment)| segment.live == 0 && (all_dead || **id != self.active))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Drop segments whose files have been removed, along with the changes in them.
+    fn forget_segments(&mut self, ids: &[u64]) {
+        for id in ids {
+            self.segments.remove(id);
+        }
+        self.index
+            .retain(|_, location| !ids.contains(&location.segment));
+        if self.segments.is_empty() {
+            self.active += 1;
+        }
+    }
+
+    fn active_len(&self) -> u64 {
+        self.segments.get(&self.active).map_or(0, |s| s.len)
+    }
+
+    fn roll_if_full(&mut self) {
+        if self.active_len() >= self.max_segment_size {
+            self.active += 1;
+        }
+    }
+}
+
+/// What removing changes from the log needs.
+enum Removals {
+    /// None of the changes are live.
+    Nothing,
+    /// Every live change is being removed, so rather than writing tombstones every segment can be
+    /// deleted.
+    All,
+    /// Tombstones for the changes, to be appended.
+    Tombstones(Append),
+}
+
+/// Records encoded for appending to the active segment, along with the index updates to make once
+/// they are written.
+#[derive(Debug)]
+pub(crate) struct Append {
+    dir: PathBuf,
+    path: PathBuf,
+    base: u64,
+    created: bool,
+    /// Whether the segment may have an abandoned append past `base` to cut off first.
+    truncate: bool,
+    buf: Vec<u8>,
+    inserted: Vec<(ActorId, u64, Location)>,
+    removed: Vec<(ActorId, u64)>,
+}
+
+impl Append {
+    /// The changes the append inserts.
+    pub(crate) fn inserted(&self) -> impl Iterator<Item = (ActorId, u64)> + '_ {
+        self.inserted
+            .iter()
+            .map(|(actor, seq, _)| (actor.clone(), *seq))
+    }
+
+    /// Write the records out.
+    ///
+    /// On failure the segment is truncated back so a partial write doesn't leave a torn record in
+    /// the middle of the log.
+    fn write<F: FileSystem>(&self, fs: &F, durability: Durability) -> io::Result<()> {
+        if self.truncate {
+            match fs.truncate(&self.path, self.base, Durability::None) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        fs.append(&self.path, &self.buf, durability)?;
+        if self.created && durability.sync_dirs() {
+            fs.sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    /// Write the records out.
+    ///
+    /// On failure the segment is truncated back so a partial write doesn't leave a torn record in
+    /// the middle of the log.
+    #[cfg(feature = "async")]
+    pub(crate) async fn write_async(&self, durability: Durability) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        if self.truncate {
+            file.set_len(self.base).await?;
+        }
+        let result = async {
+            file.write_all(&self.buf).await?;
+            match durability {
+                Durability::None => Ok(()),
+                Durability::Data => file.sync_data().await,
+                Durability::Full => file.sync_all().await,
+            }
+        }
+        .await;
+        if let Err(e) = result {
+            let _ = file.set_len(self.base).await;
+            return Err(e);
+        }
+        if self.created && durability.sync_dirs() {
+            crate::durability::sync_dir_async(self.dir.clone()).await?;
+        }
+        Ok(())
+    }
+}
+
+fn extract_changes(
+    path: &Path,
+    data: &[u8],
+    locations: &[Location],
+    changes: &mut Vec<Vec<u8>>,
+) -> Result<(), FsPersisterError> {
+    for location in locations {
+        let start = location.offset as usize;
+        let end = start + location.len as usize;
+        let change = data
+            .get(start..end)
+            .ok_or_else(|| FsPersisterError::CorruptSegment(path.to_path_buf()))?;
+        changes.push(change.to_vec());
+    }
+    Ok(())
+}
+
+fn remove_if_exists<F: FileSystem>(fs: &F, path: &Path) -> io::Result<()> {
+    match fs.remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,464 @@
+//! A persister keeping a whole document in a single write-ahead log file.
+//!
+//! Every update is appended to the log as a framed record, see [`crate::record`], and the log is
+//! replayed on open to rebuild the stored state. Compacting rewrites the log to hold just the live
+//! state, replacing the old file atomically.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+};
+
+use automerge::ActorId;
+use automerge_persistent::{Persister, StoredCounts, StoredSizes};
+
+use crate::{
+    durability::{create_dirs, temp_path, write_file},
+    record::{self, len_prefix, Fields},
+    Durability, FileSystem, FsPersisterError, StdFileSystem,
+};
+
+const CHANGE_RECORD: u8 = 0;
+const REMOVE_CHANGE_RECORD: u8 = 1;
+const DOCUMENT_RECORD: u8 = 2;
+const SYNC_STATE_RECORD: u8 = 3;
+const REMOVE_SYNC_STATE_RECORD: u8 = 4;
+
+enum Entry<'a> {
+    Change(ActorId, u64, &'a [u8]),
+    RemoveChange(ActorId, u64),
+    Document(&'a [u8]),
+    SyncState(&'a [u8], &'a [u8]),
+    RemoveSyncState(&'a [u8]),
+}
+
+fn parse_entry(body: &[u8]) -> Option<Entry<'_>> {
+    let mut fields = Fields::new(body);
+    match fields.u8()? {
+        CHANGE_RECORD => {
+            let actor = ActorId::from(fields.bytes()?);
+            let seq = fields.u64()?;
+            Some(Entry::Change(actor, seq, fields.rest()))
+        }
+        REMOVE_CHANGE_RECORD => {
+            let actor = ActorId::from(fields.bytes()?);
+            let seq = fields.u64()?;
+            fields
+                .rest()
+                .is_empty()
+                .then_some(Entry::RemoveChange(actor, seq))
+        }
+        DOCUMENT_RECORD => Some(Entry::Document(fields.rest())),
+        SYNC_STATE_RECORD => {
+            let peer_id = fields.bytes()?;
+            Some(Entry::SyncState(peer_id, fields.rest()))
+        }
+        REMOVE_SYNC_STATE_RECORD => {
+            let peer_id = fields.bytes()?;
+            fields
+                .rest()
+                .is_empty()
+                .then_some(Entry::RemoveSyncState(peer_id))
+        }
+        _ => None,
+    }
+}
+
+fn encode_change(buf: &mut Vec<u8>, actor: &ActorId, seq: u64, change: &[u8]) {
+    let actor_bytes = actor.to_bytes();
+    record::encode_into(
+        buf,
+        &[
+            &[CHANGE_RECORD],
+            &len_prefix(actor_bytes),
+            actor_bytes,
+            &seq.to_be_bytes(),
+            change,
+        ],
+    );
+}
+
+fn encode_remove_change(buf: &mut Vec<u8>, actor: &ActorId, seq: u64) {
+    let actor_bytes = actor.to_bytes();
+    record::encode_into(
+        buf,
+        &[
+            &[REMOVE_CHANGE_RECORD],
+            &len_prefix(actor_bytes),
+            actor_bytes,
+            &seq.to_be_bytes(),
+        ],
+    );
+}
+
+fn encode_document(buf: &mut Vec<u8>, document: &[u8]) {
+    record::encode_into(buf, &[&[DOCUMENT_RECORD], document]);
+}
+
+fn encode_sync_state(buf: &mut Vec<u8>, peer_id: &[u8], sync_state: &[u8]) {
+    record::encode_into(
+        buf,
+        &[
+            &[SYNC_STATE_RECORD],
+            &len_prefix(peer_id),
+            peer_id,
+            sync_state,
+        ],
+    );
+}
+
+fn encode_remove_sync_state(buf: &mut Vec<u8>, peer_id: &[u8]) {
+    record::encode_into(
+        buf,
+        &[&[REMOVE_SYNC_STATE_RECORD], &len_prefix(peer_id), peer_id],
+    );
+}
+
+/// The directory holding the file at `path`, which is the current one for a bare file name.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Stores a document, its changes and sync states in a single append-only log file.
+///
+/// Updates are encoded as checksummed records and held until [`Persister::flush`], which appends
+/// them in one write. Opening the file replays the log, truncating any torn record left at its end
+/// by an interrupted append, while a damaged record with more after it is an error. As the log
+/// only grows, [`Persister::compact`] rewrites it with just the live state, atomically replacing
+/// the old file, and [`WalPersister::log_len`] can be compared against [`Persister::sizes`] to
+/// decide when that is worth doing.
+///
+/// Everything stored is kept in memory, so this suits documents that comfortably fit there, such
+/// as for embedded or command line use.
+///
+/// ```rust
+/// # use automerge::ActorId;
+/// # use automerge_persistent::Persister;
+/// # use automerge_persistent_fs::{FileSystem, Durability, MemoryFileSystem, WalPersister};
+/// # use std::path::Path;
+/// let fs = MemoryFileSystem::default();
+/// let mut persister =
+///     WalPersister::with_fs(fs.clone(), "/data/doc.wal", Durability::Full).unwrap();
+/// let actor = ActorId::random();
+/// persister
+///     .insert_changes(vec![(actor.clone(), 1, vec![1, 2, 3])])
+///     .unwrap();
+/// persister.flush().unwrap();
+///
+/// // a torn append is cut off when the log is next opened
+/// fs.append(Path::new("/data/doc.wal"), &[0, 0, 0, 9], Durability::Full)
+///     .unwrap();
+/// let mut persister = WalPersister::with_fs(fs, "/data/doc.wal", Durability::Full).unwrap();
+/// assert_eq!(persister.get_changes().unwrap(), vec![vec![1, 2, 3]]);
+///
+/// persister.compact(vec![4, 5], vec![(&actor, 1)], &[]).unwrap();
+/// assert_eq!(persister.get_changes().unwrap(), Vec::<Vec<u8>>::new());
+/// assert_eq!(persister.get_document().unwrap(), Some(vec![4, 5]));
+/// ```
+#[derive(Debug)]
+pub struct WalPersister<F = StdFileSystem> {
+    fs: F,
+    path: PathBuf,
+    changes: HashMap<(ActorId, u64), Vec<u8>>,
+    document: Option<Vec<u8>>,
+    sync_states: HashMap<Vec<u8>, Vec<u8>>,
+    /// Records that have not been appended to the log yet.
+    pending: Vec<u8>,
+    /// The number of data bytes in `pending`, reported by flush.
+    pending_data: usize,
+    /// The length of the log file.
+    log_len: u64,
+    /// Whether the log file exists, so the directory is synced once it is created.
+    created: bool,
+    sizes: StoredSizes,
+    counts: StoredCounts,
+    durability: Durability,
+}
+
+impl WalPersister {
+    /// Open the log at `path`, creating it and any missing parent directories if needed.
+    ///
+    /// Appends and compactions default to [`Durability::Full`], see
+    /// [`WalPersister::with_durability`] to change this.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the log can't be read, or has a record that isn't understood.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, FsPersisterError> {
+        Self::with_fs(StdFileSystem, path, Durability::default())
+    }
+}
+
+impl<F: FileSystem> WalPersister<F> {
+    /// Open the log at `path` on the given filesystem, creating it and any missing parent
+    /// directories if needed, with `durability` used for these and for later writes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the log can't be read, has a record that isn't understood, or has a
+    /// damaged record before its end.
+    pub fn with_fs<P: AsRef<Path>>(
+        fs: F,
+        path: P,
+        durability: Durability,
+    ) -> Result<Self, FsPersisterError> {
+        let path = path.as_ref().to_path_buf();
+        create_dirs(&fs, parent_dir(&path), durability)?;
+        // left behind by a compaction that didn't finish
+        match fs.remove_file(&temp_path(&path)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let (data, created) = match fs.read(&path) {
+            Ok(data) => (data, true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (Vec::new(), false),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut persister = Self {
+            fs,
+            path,
+            changes: HashMap::new(),
+            document: None,
+            sync_states: HashMap::new(),
+            pending: Vec::new(),
+            pending_data: 0,
+            log_len: 0,
+            created,
+            sizes: StoredSizes::default(),
+            counts: StoredCounts::default(),
+            durability,
+        };
+
+        let (records, valid) = record::decode(&data);
+        if !record::is_torn_tail(&data, valid) {
+            return Err(FsPersisterError::CorruptLog(persister.path));
+        }
+        for (_, body) in records {
+            let entry = parse_entry(body)
+                .ok_or_else(|| FsPersisterError::CorruptLog(persister.path.clone()))?;
+            persister.replay(entry);
+        }
+        if valid < data.len() {
+            // torn write at the tail of the log
+            persister
+                .fs
+                .truncate(&persister.path, valid as u64, durability)?;
+        }
+        persister.log_len = valid as u64;
+        persister.recount();
+        Ok(persister)
+    }
+
+    /// Set the durability used when appending to and rewriting the log.
+    #[must_use]
+    pub const fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// The durability used when appending to and rewriting the log.
+    pub const fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// The path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The number of changes and sync states stored.
+    pub fn counts(&self) -> StoredCounts {
+        self.counts.clone()
+    }
+
+    /// The length of the log file in bytes, not counting records waiting to be flushed.
+    ///
+    /// This includes records that have since been superseded, unlike [`Persister::sizes`].
+    pub const fn log_len(&self) -> u64 {
+        self.log_len
+    }
+
+    fn replay(&mut self, entry: Entry<'_>) {
+        match entry {
+            Entry::Change(actor, seq, change) => {
+                self.changes.insert((actor, seq), change.to_vec());
+            }
+            Entry::RemoveChange(actor, seq) => {
+                self.changes.remove(&(actor, seq));
+            }
+            Entry::Document(document) => self.document = Some(document.to_vec()),
+            Entry::SyncState(peer_id, sync_state) => {
+                self.sync_states
+                    .insert(peer_id.to_vec(), sync_state.to_vec());
+            }
+            Entry::RemoveSyncState(peer_id) => {
+                self.sync_states.remove(peer_id);
+            }
+        }
+    }
+
+    fn recount(&mut self) {
+        self.sizes = StoredSizes {
+            changes: self.changes.values().map(|c| c.len() as u64).sum(),
+            document: self.document.as_ref().map_or(0, |d| d.len() as u64),
+            sync_states: self.sync_states.values().map(|s| s.len() as u64).sum(),
+        };
+        self.counts = StoredCounts {
+            changes: self.changes.len() as u64,
+            sync_states: self.sync_states.len() as u64,
+        };
+    }
+
+    /// Sync the directory holding the log, after the log file has been created or replaced.
+    fn sync_parent(&self) -> io::Result<()> {
+        if self.durability.sync_dirs() {
+            self.fs.sync_dir(parent_dir(&self.path))?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: FileSystem> Persister for WalPersister<F> {
+    type Error = FsPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.changes.values().cloned().collect())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        for (a, s, c) in changes {
+            encode_change(&mut self.pending, &a, s, &c);
+            self.pending_data += c.len();
+            self.sizes.changes += c.len() as u64;
+            match self.changes.insert((a, s), c) {
+                Some(old) => self.sizes.changes -= old.len() as u64,
+                None => self.counts.changes += 1,
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        for (a, s) in changes {
+            if let Some(old) = self.changes.remove(&(a.clone(), s)) {
+                encode_remove_change(&mut self.pending, a, s);
+                self.sizes.changes -= old.len() as u64;
+                self.counts.changes -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.document.clone())
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        encode_document(&mut self.pending, &data);
+        self.pending_data += data.len();
+        self.sizes.document = data.len() as u64;
+        self.document = Some(data);
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.get(peer_id).cloned())
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        encode_sync_state(&mut self.pending, &peer_id, &sync_state);
+        self.pending_data += sync_state.len();
+        self.sizes.sync_states += sync_state.len() as u64;
+        match self.sync_states.insert(peer_id, sync_state) {
+            Some(old) => self.sizes.sync_states -= old.len() as u64,
+            None => self.counts.sync_states += 1,
+        }
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for peer_id in peer_ids {
+            if let Some(old) = self.sync_states.remove(*peer_id) {
+                encode_remove_sync_state(&mut self.pending, peer_id);
+                self.sizes.sync_states -= old.len() as u64;
+                self.counts.sync_states -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self.sync_states.keys().cloned().collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Append the pending records to the log in a single write.
+    ///
+    /// If the write fails the records are kept for the next flush.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
+        self.fs.append(&self.path, &self.pending, self.durability)?;
+        if !self.created {
+            self.sync_parent()?;
+            self.created = true;
+        }
+        self.log_len += self.pending.len() as u64;
+        self.pending.clear();
+        Ok(std::mem::take(&mut self.pending_data))
+    }
+
+    /// Rewrite the log with just the live state after applying the compaction, replacing the old
+    /// file atomically so a crash leaves one or the other.
+    ///
+    /// Pending records are included in the rewrite, so nothing is left to flush afterwards. If the
+    /// rewrite fails nothing is changed.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let removed = changes
+            .into_iter()
+            .map(|(a, s)| (a.clone(), s))
+            .collect::<HashSet<_>>();
+        let mut buf = Vec::new();
+        encode_document(&mut buf, &document);
+        for (key, change) in &self.changes {
+            if !removed.contains(key) {
+                encode_change(&mut buf, &key.0, key.1, change);
+            }
+        }
+        for (peer_id, sync_state) in &self.sync_states {
+            if !old_peer_ids.contains(&peer_id.as_slice()) {
+                encode_sync_state(&mut buf, peer_id, sync_state);
+            }
+        }
+        write_file(&self.fs, &self.path, &buf, self.durability)?;
+
+        self.created = true;
+        self.log_len = buf.len() as u64;
+        self.pending.clear();
+        self.pending_data = 0;
+        self.document = Some(document);
+        self.changes.retain(|key, _| !removed.contains(key));
+        for peer_id in old_peer_ids {
+            self.sync_states.remove(*peer_id);
+        }
+        self.recount();
+        self.sync_parent()?;
+        Ok(())
+    }
+}
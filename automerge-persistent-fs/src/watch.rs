@@ -0,0 +1,286 @@
+//! Watching an [`FsPersister`] directory for changes written by other processes.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs, io,
+    os::unix::io::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+};
+
+use automerge::{Automerge, AutomergeError, Change, ChangeHash, LoadChangeError, ReadDoc};
+use automerge_persistent::PersistentAutomerge;
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::{durability::is_temp_name, layout::change_files, FsPersister, StdFileSystem, DOC_FILE};
+
+/// Something the watcher found and applied to the document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsWatchEvent {
+    /// New change files were found, these changes were applied to the document.
+    Changes(Vec<ChangeHash>),
+    /// A new document was written, merging it in gave these new changes.
+    Document(Vec<ChangeHash>),
+}
+
+/// Possible errors from watching.
+#[derive(Debug, thiserror::Error)]
+pub enum FsWatcherError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Automerge(#[from] AutomergeError),
+    #[error(transparent)]
+    LoadChange(#[from] LoadChangeError),
+    /// Only the file per change layout can be watched.
+    #[error("watching is only supported with StorageMode::Files")]
+    Unsupported,
+}
+
+/// Watches the directories of an [`FsPersister`] using inotify.
+///
+/// Change and document files that appear, from another process or a file sync tool, are loaded
+/// and applied to the live document. Changes that the document already has, such as those it
+/// flushed itself, are skipped.
+///
+/// The watcher never blocks unless asked to with [`FsWatcher::wait`], [`FsWatcher::poll`] can be
+/// driven from an event loop using the file descriptor from [`AsRawFd`].
+///
+/// ```rust,no_run
+/// # use automerge_persistent::PersistentAutomerge;
+/// # use automerge_persistent_fs::{FsPersister, FsWatcher, FsWatchEvent};
+/// let persister = FsPersister::new("data", "doc").unwrap();
+/// let mut doc = PersistentAutomerge::load(persister).unwrap();
+/// let mut watcher = FsWatcher::new(doc.persister()).unwrap();
+/// loop {
+///     watcher
+///         .wait(&mut doc, |event| println!("applied {:?}", event))
+///         .unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct FsWatcher {
+    inotify: Inotify,
+    root_path: PathBuf,
+    changes_path: PathBuf,
+    root: WatchDescriptor,
+    changes: WatchDescriptor,
+    shards: HashMap<WatchDescriptor, PathBuf>,
+    buffer: Vec<u8>,
+}
+
+const FILE_MASK: WatchMask = WatchMask::CLOSE_WRITE.union(WatchMask::MOVED_TO);
+
+impl FsWatcher {
+    /// Start watching the directories used by `persister`.
+    pub fn new(persister: &FsPersister) -> Result<Self, FsWatcherError> {
+        if persister.segments.is_some() {
+            return Err(FsWatcherError::Unsupported);
+        }
+        let root_path = persister
+            .doc_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let changes_path = persister.changes_path.clone();
+
+        let inotify = Inotify::init()?;
+        let root = inotify.watches().add(&root_path, FILE_MASK)?;
+        let changes = inotify
+            .watches()
+            .add(&changes_path, WatchMask::CREATE | WatchMask::MOVED_TO)?;
+
+        let mut watcher = Self {
+            inotify,
+            root_path,
+            changes_path,
+            root,
+            changes,
+            shards: HashMap::new(),
+            buffer: vec![0; 4096],
+        };
+        for entry in fs::read_dir(&watcher.changes_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() && !is_temp_name(&entry.file_name()) {
+                watcher.watch_shard(entry.path())?;
+            }
+        }
+        Ok(watcher)
+    }
+
+    fn watch_shard(&mut self, path: PathBuf) -> io::Result<()> {
+        let wd = self.inotify.watches().add(&path, FILE_MASK)?;
+        self.shards.insert(wd, path);
+        Ok(())
+    }
+
+    /// Apply any updates that have happened since the last call, without blocking.
+    ///
+    /// `on_event` is called for each batch of changes that was applied. Returns the number of
+    /// changes applied.
+    pub fn poll<F>(
+        &mut self,
+        doc: &mut PersistentAutomerge<FsPersister>,
+        on_event: F,
+    ) -> Result<usize, FsWatcherError>
+    where
+        F: FnMut(FsWatchEvent),
+    {
+        self.read_and_apply(doc, on_event, false)
+    }
+
+    /// Block until there are updates and then apply them.
+    ///
+    /// Events for files that turn out to have nothing new in them still wake this up, in which
+    /// case nothing is applied and 0 is returned.
+    pub fn wait<F>(
+        &mut self,
+        doc: &mut PersistentAutomerge<FsPersister>,
+        on_event: F,
+    ) -> Result<usize, FsWatcherError>
+    where
+        F: FnMut(FsWatchEvent),
+    {
+        self.read_and_apply(doc, on_event, true)
+    }
+
+    fn read_and_apply<F>(
+        &mut self,
+        doc: &mut PersistentAutomerge<FsPersister>,
+        mut on_event: F,
+        mut block: bool,
+    ) -> Result<usize, FsWatcherError>
+    where
+        F: FnMut(FsWatchEvent),
+    {
+        let mut change_paths = HashSet::new();
+        let mut new_shards = Vec::new();
+        let mut document = false;
+        let mut rescan = false;
+
+        loop {
+            let events = if block {
+                block = false;
+                self.inotify.read_events_blocking(&mut self.buffer)
+            } else {
+                self.inotify.read_events(&mut self.buffer)
+            };
+            let events = match events {
+                Ok(events) => events
+                    .map(|e| (e.wd, e.mask, e.name.map(OsString::from)))
+                    .collect::<Vec<_>>(),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            };
+            if events.is_empty() {
+                break;
+            }
+            for (wd, mask, name) in events {
+                if mask.contains(EventMask::Q_OVERFLOW) {
+                    rescan = true;
+                    continue;
+                }
+                let name = match name {
+                    Some(name) if !is_temp_name(&name) => name,
+                    _ => continue,
+                };
+                if wd == self.root {
+                    document |= name == DOC_FILE;
+                } else if wd == self.changes {
+                    if mask.contains(EventMask::ISDIR) {
+                        new_shards.push(self.changes_path.join(name));
+                    }
+                } else if let Some(shard) = self.shards.get(&wd) {
+                    change_paths.insert(shard.join(name));
+                }
+            }
+        }
+
+        for shard in new_shards {
+            if self.shards.values().any(|s| s == &shard) {
+                continue;
+            }
+            self.watch_shard(shard.clone())?;
+            // files may have been written before the watch was in place
+            for entry in fs::read_dir(&shard)? {
+                let entry = entry?;
+                if entry.file_type()?.is_file() && !is_temp_name(&entry.file_name()) {
+                    change_paths.insert(entry.path());
+                }
+            }
+        }
+        if rescan {
+            document = true;
+            change_paths.extend(change_files(&StdFileSystem, &self.changes_path)?);
+        }
+
+        let mut applied = 0;
+        if document {
+            applied += self.apply_document(doc, &mut on_event)?;
+        }
+        applied += apply_change_files(change_paths, doc, &mut on_event)?;
+        Ok(applied)
+    }
+
+    fn apply_document<F>(
+        &self,
+        doc: &mut PersistentAutomerge<FsPersister>,
+        on_event: &mut F,
+    ) -> Result<usize, FsWatcherError>
+    where
+        F: FnMut(FsWatchEvent),
+    {
+        let bytes = match fs::read(self.root_path.join(DOC_FILE)) {
+            Ok(bytes) if !bytes.is_empty() => bytes,
+            Ok(_) => return Ok(0),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut other = Automerge::load(&bytes)?;
+        // the document is already on disk so it is applied directly rather than persisted again
+        let new = doc.document_mut().merge(&mut other)?;
+        let applied = new.len();
+        if applied > 0 {
+            on_event(FsWatchEvent::Document(new));
+        }
+        Ok(applied)
+    }
+}
+
+fn apply_change_files<F>(
+    paths: HashSet<PathBuf>,
+    doc: &mut PersistentAutomerge<FsPersister>,
+    on_event: &mut F,
+) -> Result<usize, FsWatcherError>
+where
+    F: FnMut(FsWatchEvent),
+{
+    let mut changes = Vec::new();
+    for path in paths {
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            // removed by a compaction in the meantime
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let change = Change::from_bytes(bytes)?;
+        if doc.document().get_change_by_hash(&change.hash()).is_none() {
+            changes.push(change);
+        }
+    }
+    if changes.is_empty() {
+        return Ok(0);
+    }
+    let hashes = changes.iter().map(Change::hash).collect::<Vec<_>>();
+    let applied = hashes.len();
+    // the changes are already on disk so they are applied directly rather than persisted again
+    doc.document_mut().apply_changes(changes)?;
+    on_event(FsWatchEvent::Changes(hashes));
+    Ok(applied)
+}
+
+impl AsRawFd for FsWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
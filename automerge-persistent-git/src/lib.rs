@@ -0,0 +1,731 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting a git repository through [gix](https://github.com/Byron/gitoxide).
+//!
+//! Documents are kept as ordinary git objects and references, so they can be pushed, fetched and
+//! inspected with the usual git tooling. The repository can be bare.
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_git::{GitPersister, GitPersisterError};
+//! # fn main() -> Result<(), GitPersisterError> {
+//! # let dir = tempfile::tempdir().unwrap();
+//! let repo = gix::init_bare(dir.path()).unwrap();
+//!
+//! let persister = GitPersister::new(repo, "document")?;
+//! let doc = PersistentAutomerge::load(persister);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The references live under `refs/automerge`, which git doesn't transfer by default, so name
+//! them when pushing or fetching:
+//!
+//! ```sh
+//! git push origin 'refs/automerge/*:refs/automerge/*'
+//! ```
+
+use std::{collections::BTreeMap, convert::TryFrom, path::Path};
+
+use automerge::ActorId;
+use automerge_persistent::{Persister, StoredCounts, StoredSizes};
+use gix::{
+    objs::{
+        tree::{Entry, EntryKind},
+        Commit, Tree,
+    },
+    refs::{
+        transaction::{Change, LogChange, PreviousValue, RefEdit, RefLog},
+        FullName, Target,
+    },
+    ObjectId, Repository,
+};
+
+/// The name used for the author and committer of the commits.
+const SIGNATURE_NAME: &str = "automerge-persistent";
+/// The email used for the author and committer of the commits.
+const SIGNATURE_EMAIL: &str = "automerge-persistent@localhost";
+/// The name of the document within the tree of a snapshot commit.
+const DOCUMENT_ENTRY: &str = "document";
+
+/// The persister that stores changes, documents and sync states in a git repository.
+///
+/// Everything is stored as blobs, reachable from commits on three references per document:
+///
+/// - `refs/automerge/{name}/document`: a commit for each snapshot of the document, with the
+///   document as the `document` entry of its tree. Each new snapshot, such as from a compaction,
+///   has the previous one as its parent, so `git log` shows the history of snapshots.
+/// - `refs/automerge/{name}/changes`: the changes since the last compaction, at
+///   `{actor id in hex}/{sequence number}`. Each write is a commit on top of the last, and
+///   compaction starts again from a commit without parents so that the removed changes can be
+///   garbage collected.
+/// - `refs/automerge/{name}/sync-states`: the sync states, at `{peer id in hex}`. These are
+///   replaced often and have no use later on, so each commit has no parents.
+///
+/// A name can't be the start of another name followed by a `/`, such as `a` and `a/b`, as git
+/// can't have both `refs/automerge/a/document` and `refs/automerge/a/b/document`.
+///
+/// Compaction updates all three references in one reference transaction. The references are only
+/// updated if they still point where this persister last saw them, so writes from another handle
+/// on the same name cause an error rather than being lost. After an error the persister should be
+/// created again to pick up what is stored.
+#[derive(Debug)]
+pub struct GitPersister {
+    repo: Repository,
+    name: String,
+    /// The stored changes, by actor id in hex.
+    changes: BTreeMap<String, ActorChanges>,
+    document: Option<StoredBlob>,
+    /// The stored sync states, by peer id in hex.
+    sync_states: BTreeMap<String, StoredBlob>,
+    /// The commits the references pointed at when last read or written.
+    heads: Heads,
+    sizes: StoredSizes,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum GitPersisterError {
+    /// The repository couldn't be opened.
+    #[error(transparent)]
+    OpenError(#[from] Box<gix::open::Error>),
+    /// An object couldn't be read.
+    #[error(transparent)]
+    FindObjectError(#[from] gix::object::find::existing::Error),
+    /// An object couldn't be read as the expected kind.
+    #[error(transparent)]
+    FindKindError(#[from] gix::object::find::existing::with_conversion::Error),
+    /// An object couldn't be decoded.
+    #[error(transparent)]
+    DecodeError(#[from] gix::objs::decode::Error),
+    /// An object couldn't be written.
+    #[error(transparent)]
+    WriteObjectError(#[from] gix::object::write::Error),
+    /// A reference couldn't be read.
+    #[error(transparent)]
+    FindReferenceError(#[from] gix::reference::find::Error),
+    /// The references couldn't be updated, such as when another handle has moved them.
+    #[error(transparent)]
+    EditReferenceError(#[from] gix::reference::edit::Error),
+    /// The name doesn't make a valid reference name.
+    #[error(transparent)]
+    InvalidName(#[from] gix::validate::reference::name::Error),
+    /// A tree has an entry that this persister didn't write.
+    #[error("unexpected entry {0} in the tree of {1}")]
+    InvalidEntry(String, String),
+}
+
+/// A blob that has been written to the repository.
+#[derive(Debug, Clone, Copy)]
+struct StoredBlob {
+    id: ObjectId,
+    len: u64,
+}
+
+/// The changes from a single actor.
+#[derive(Debug, Default, Clone)]
+struct ActorChanges {
+    /// The stored changes, by sequence number.
+    blobs: BTreeMap<u64, StoredBlob>,
+    /// The tree of `blobs`, if it has been written since they last changed.
+    tree: Option<ObjectId>,
+}
+
+/// The commits of the references of a document.
+#[derive(Debug, Default)]
+struct Heads {
+    document: Option<ObjectId>,
+    changes: Option<ObjectId>,
+    sync_states: Option<ObjectId>,
+}
+
+/// An edit to the stored changes, made on copies of the actors it touches so that the persister
+/// only changes once the references have moved.
+#[derive(Debug)]
+struct ChangesEdit {
+    /// The changes of each actor touched, with no blobs if the actor has none left.
+    actors: BTreeMap<String, ActorChanges>,
+    /// The total size of the changes after the edit.
+    size: u64,
+}
+
+impl ChangesEdit {
+    /// The changes of `actor` to edit, copied from `stored` the first time.
+    fn actor(
+        &mut self,
+        stored: &BTreeMap<String, ActorChanges>,
+        actor: String,
+    ) -> &mut ActorChanges {
+        self.actors
+            .entry(actor)
+            .or_insert_with_key(|actor| stored.get(actor).cloned().unwrap_or_default())
+    }
+
+    /// Remove the given changes, returning how many there were.
+    fn remove(
+        &mut self,
+        stored: &BTreeMap<String, ActorChanges>,
+        changes: Vec<(&ActorId, u64)>,
+    ) -> usize {
+        let mut removed = 0;
+        for (a, s) in changes {
+            let actor = a.to_hex_string();
+            if !stored.contains_key(&actor) && !self.actors.contains_key(&actor) {
+                continue;
+            }
+            let actor_changes = self.actor(stored, actor);
+            if let Some(old) = actor_changes.blobs.remove(&s) {
+                actor_changes.tree = None;
+                self.size -= old.len;
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// The changes of each actor once the edit is made.
+    fn merged<'a>(
+        &'a self,
+        stored: &'a BTreeMap<String, ActorChanges>,
+    ) -> impl Iterator<Item = (&'a String, &'a ActorChanges)> {
+        let unchanged = stored
+            .iter()
+            .filter(move |(actor, _)| !self.actors.contains_key(*actor));
+        let edited = self
+            .actors
+            .iter()
+            .filter(|(_, changes)| !changes.blobs.is_empty());
+        unchanged.chain(edited)
+    }
+}
+
+/// The kind of each entry in a tree, with its name and id.
+type TreeEntries = Vec<(String, bool, ObjectId)>;
+
+impl GitPersister {
+    /// Open the repository at `path`, which may be bare, and construct a new persister for the
+    /// document called `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the repository can't be opened, or if the stored document can't be
+    /// read.
+    pub fn open<P>(path: P, name: &str) -> Result<Self, GitPersisterError>
+    where
+        P: AsRef<Path>,
+    {
+        Self::new(gix::open(path.as_ref()).map_err(Box::new)?, name)
+    }
+
+    /// Construct a new persister for the document called `name` in `repo`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the stored document can't be read, or if `name` doesn't make a valid
+    /// reference name.
+    pub fn new(repo: Repository, name: &str) -> Result<Self, GitPersisterError> {
+        let mut persister = Self {
+            repo,
+            name: name.to_owned(),
+            changes: BTreeMap::new(),
+            document: None,
+            sync_states: BTreeMap::new(),
+            heads: Heads::default(),
+            sizes: StoredSizes::default(),
+        };
+        persister.heads.document = persister.read_reference("document")?;
+        persister.heads.changes = persister.read_reference("changes")?;
+        persister.heads.sync_states = persister.read_reference("sync-states")?;
+
+        for (entry, _, id) in persister.read_tree(persister.heads.document)? {
+            if entry == DOCUMENT_ENTRY {
+                persister.document = Some(persister.stored_blob(id)?);
+            }
+        }
+        for (actor, is_tree, id) in persister.read_tree(persister.heads.changes)? {
+            if !is_tree {
+                return Err(GitPersisterError::InvalidEntry(actor, "changes".to_owned()));
+            }
+            let mut changes = ActorChanges {
+                blobs: BTreeMap::new(),
+                tree: Some(id),
+            };
+            for (seq, _, id) in persister.read_subtree(id)? {
+                let seq = seq.parse::<u64>().map_err(|_| {
+                    GitPersisterError::InvalidEntry(format!("{actor}/{seq}"), "changes".to_owned())
+                })?;
+                changes.blobs.insert(seq, persister.stored_blob(id)?);
+            }
+            persister.changes.insert(actor, changes);
+        }
+        for (peer, _, id) in persister.read_tree(persister.heads.sync_states)? {
+            persister
+                .sync_states
+                .insert(peer, persister.stored_blob(id)?);
+        }
+
+        persister.sizes = StoredSizes {
+            changes: persister
+                .changes
+                .values()
+                .flat_map(|changes| changes.blobs.values())
+                .map(|blob| blob.len)
+                .sum(),
+            document: persister.document.map_or(0, |blob| blob.len),
+            sync_states: persister.sync_states.values().map(|blob| blob.len).sum(),
+        };
+        Ok(persister)
+    }
+
+    /// The name of the document this persister stores.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of changes and sync states stored.
+    #[must_use]
+    pub fn counts(&self) -> StoredCounts {
+        StoredCounts {
+            changes: self
+                .changes
+                .values()
+                .map(|changes| changes.blobs.len() as u64)
+                .sum(),
+            sync_states: self.sync_states.len() as u64,
+        }
+    }
+
+    fn reference_name(&self, kind: &str) -> Result<FullName, GitPersisterError> {
+        Ok(FullName::try_from(format!(
+            "refs/automerge/{}/{}",
+            self.name, kind
+        ))?)
+    }
+
+    /// The commit the reference of `kind` points at, if it exists.
+    fn read_reference(&self, kind: &str) -> Result<Option<ObjectId>, GitPersisterError> {
+        let name = self.reference_name(kind)?;
+        Ok(self
+            .repo
+            .try_find_reference(&name)?
+            .and_then(|reference| reference.target().try_id().map(ToOwned::to_owned)))
+    }
+
+    /// The entries in the tree of `commit`, along with whether each is a tree itself.
+    fn read_tree(&self, commit: Option<ObjectId>) -> Result<TreeEntries, GitPersisterError> {
+        let Some(commit) = commit else {
+            return Ok(Vec::new());
+        };
+        let tree_id = self.repo.find_commit(commit)?.tree_id()?;
+        self.read_subtree(tree_id.detach())
+    }
+
+    fn read_subtree(&self, tree: ObjectId) -> Result<TreeEntries, GitPersisterError> {
+        let tree = self.repo.find_tree(tree)?;
+        Ok(tree
+            .decode()?
+            .entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.filename.to_string(),
+                    entry.mode.is_tree(),
+                    entry.oid.to_owned(),
+                )
+            })
+            .collect())
+    }
+
+    fn stored_blob(&self, id: ObjectId) -> Result<StoredBlob, GitPersisterError> {
+        let header = self.repo.find_header(id)?;
+        Ok(StoredBlob {
+            id,
+            len: header.size(),
+        })
+    }
+
+    fn read_blob(&self, blob: StoredBlob) -> Result<Vec<u8>, GitPersisterError> {
+        Ok(self.repo.find_object(blob.id)?.detach().data)
+    }
+
+    fn write_blob(&self, data: &[u8]) -> Result<StoredBlob, GitPersisterError> {
+        Ok(StoredBlob {
+            id: self.repo.write_blob(data)?.detach(),
+            len: data.len() as u64,
+        })
+    }
+
+    fn write_tree<I>(&self, entries: I) -> Result<ObjectId, GitPersisterError>
+    where
+        I: IntoIterator<Item = (String, EntryKind, ObjectId)>,
+    {
+        let mut entries = entries
+            .into_iter()
+            .map(|(filename, kind, oid)| Entry {
+                mode: kind.into(),
+                filename: filename.into(),
+                oid,
+            })
+            .collect::<Vec<_>>();
+        // git has its own order for tree entries, which `Entry` follows
+        entries.sort();
+        Ok(self.repo.write_object(&Tree { entries })?.detach())
+    }
+
+    /// Write the tree of the changes once `edit` is made, reusing the trees of actors that haven't
+    /// changed and recording the new ones in `edit`.
+    fn write_changes_tree(&self, edit: &mut ChangesEdit) -> Result<ObjectId, GitPersisterError> {
+        let mut entries = Vec::new();
+        for (actor, changes) in edit.merged(&self.changes) {
+            let tree = match changes.tree {
+                Some(tree) => tree,
+                None => self.write_tree(
+                    changes
+                        .blobs
+                        .iter()
+                        .map(|(seq, blob)| (seq.to_string(), EntryKind::Blob, blob.id)),
+                )?,
+            };
+            entries.push((actor.clone(), tree));
+        }
+        for (actor, tree) in &entries {
+            if let Some(changes) = edit.actors.get_mut(actor) {
+                changes.tree = Some(*tree);
+            }
+        }
+        self.write_tree(
+            entries
+                .into_iter()
+                .map(|(actor, tree)| (actor, EntryKind::Tree, tree)),
+        )
+    }
+
+    fn write_sync_states_tree(
+        &self,
+        sync_states: &BTreeMap<String, StoredBlob>,
+    ) -> Result<ObjectId, GitPersisterError> {
+        self.write_tree(
+            sync_states
+                .iter()
+                .map(|(peer, blob)| (peer.clone(), EntryKind::Blob, blob.id)),
+        )
+    }
+
+    fn write_commit(
+        &self,
+        tree: ObjectId,
+        parent: Option<ObjectId>,
+        message: &str,
+    ) -> Result<ObjectId, GitPersisterError> {
+        let signature = gix::actor::Signature {
+            name: SIGNATURE_NAME.into(),
+            email: SIGNATURE_EMAIL.into(),
+            time: gix::date::Time::now_local_or_utc(),
+        };
+        let commit = Commit {
+            tree,
+            parents: parent.into_iter().collect(),
+            author: signature.clone(),
+            committer: signature,
+            encoding: None,
+            message: message.into(),
+            extra_headers: Vec::new(),
+        };
+        Ok(self.repo.write_object(&commit)?.detach())
+    }
+
+    /// An edit moving the reference of `kind` from `previous` to `new`, deleting it if `new` is
+    /// `None`.
+    fn reference_edit(
+        &self,
+        kind: &str,
+        previous: Option<ObjectId>,
+        new: Option<ObjectId>,
+        message: &str,
+    ) -> Result<RefEdit, GitPersisterError> {
+        let expected = previous.map_or(PreviousValue::MustNotExist, |id| {
+            PreviousValue::MustExistAndMatch(Target::Object(id))
+        });
+        let change = match new {
+            Some(id) => Change::Update {
+                log: LogChange {
+                    mode: RefLog::AndReference,
+                    force_create_reflog: false,
+                    message: message.into(),
+                },
+                expected,
+                new: Target::Object(id),
+            },
+            None => Change::Delete {
+                expected,
+                log: RefLog::AndReference,
+            },
+        };
+        Ok(RefEdit {
+            change,
+            name: self.reference_name(kind)?,
+            deref: false,
+        })
+    }
+
+    /// Commit the changes once `edit` is made on top of the last commit, or as a new history if
+    /// `parent` is `None`.
+    ///
+    /// Returns the edit to the reference and the new commit. Starting a new history without any
+    /// changes deletes the reference instead.
+    fn commit_changes(
+        &self,
+        edit: &mut ChangesEdit,
+        parent: Option<ObjectId>,
+        message: &str,
+    ) -> Result<(RefEdit, Option<ObjectId>), GitPersisterError> {
+        let commit = if edit.merged(&self.changes).next().is_none() && parent.is_none() {
+            None
+        } else {
+            let tree = self.write_changes_tree(edit)?;
+            Some(self.write_commit(tree, parent, message)?)
+        };
+        let edit = self.reference_edit("changes", self.heads.changes, commit, message)?;
+        Ok((edit, commit))
+    }
+
+    /// Commit `sync_states`, replacing the last commit.
+    fn commit_sync_states(
+        &self,
+        sync_states: &BTreeMap<String, StoredBlob>,
+        message: &str,
+    ) -> Result<(RefEdit, Option<ObjectId>), GitPersisterError> {
+        let commit = if sync_states.is_empty() {
+            None
+        } else {
+            let tree = self.write_sync_states_tree(sync_states)?;
+            Some(self.write_commit(tree, None, message)?)
+        };
+        let edit = self.reference_edit("sync-states", self.heads.sync_states, commit, message)?;
+        Ok((edit, commit))
+    }
+
+    /// Commit `document` as a new snapshot on top of the last one.
+    fn commit_document(
+        &self,
+        document: StoredBlob,
+        message: &str,
+    ) -> Result<(RefEdit, ObjectId), GitPersisterError> {
+        let tree = self.write_tree(Some((
+            DOCUMENT_ENTRY.to_owned(),
+            EntryKind::Blob,
+            document.id,
+        )))?;
+        let commit = self.write_commit(tree, self.heads.document, message)?;
+        let edit = self.reference_edit("document", self.heads.document, Some(commit), message)?;
+        Ok((edit, commit))
+    }
+
+    /// Apply the `edits`, skipping those that wouldn't change anything.
+    fn edit_references(&self, edits: Vec<RefEdit>) -> Result<(), GitPersisterError> {
+        let edits = edits
+            .into_iter()
+            .filter(|edit| {
+                !matches!(
+                    edit.change,
+                    Change::Delete {
+                        expected: PreviousValue::MustNotExist,
+                        ..
+                    }
+                )
+            })
+            .collect::<Vec<_>>();
+        if !edits.is_empty() {
+            self.repo.edit_references(edits)?;
+        }
+        Ok(())
+    }
+
+    /// Start an edit to the stored changes.
+    const fn edit_changes(&self) -> ChangesEdit {
+        ChangesEdit {
+            actors: BTreeMap::new(),
+            size: self.sizes.changes,
+        }
+    }
+
+    /// Make `edit` to the stored changes, once the references have moved.
+    fn apply_changes(&mut self, edit: ChangesEdit) {
+        for (actor, changes) in edit.actors {
+            if changes.blobs.is_empty() {
+                self.changes.remove(&actor);
+            } else {
+                self.changes.insert(actor, changes);
+            }
+        }
+        self.sizes.changes = edit.size;
+    }
+
+    /// Replace the stored sync states, once the references have moved.
+    fn apply_sync_states(&mut self, sync_states: BTreeMap<String, StoredBlob>) {
+        self.sizes.sync_states = sync_states.values().map(|blob| blob.len).sum();
+        self.sync_states = sync_states;
+    }
+}
+
+/// Remove the sync states for `peer_ids` from `sync_states`, returning how many there were.
+fn remove_sync_states(sync_states: &mut BTreeMap<String, StoredBlob>, peer_ids: &[&[u8]]) -> usize {
+    peer_ids
+        .iter()
+        .filter(|peer_id| sync_states.remove(&hex::encode(peer_id)).is_some())
+        .count()
+}
+
+impl Persister for GitPersister {
+    type Error = GitPersisterError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.changes
+            .values()
+            .flat_map(|changes| changes.blobs.values())
+            .map(|blob| self.read_blob(*blob))
+            .collect()
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let message = format!("Insert {} changes", changes.len());
+        let mut edit = self.edit_changes();
+        for (a, s, c) in changes {
+            let blob = self.write_blob(&c)?;
+            let actor_changes = edit.actor(&self.changes, a.to_hex_string());
+            let old = actor_changes.blobs.insert(s, blob);
+            actor_changes.tree = None;
+            edit.size += blob.len;
+            if let Some(old) = old {
+                edit.size -= old.len;
+            }
+        }
+        let (reference_edit, commit) =
+            self.commit_changes(&mut edit, self.heads.changes, &message)?;
+        self.edit_references(vec![reference_edit])?;
+        self.heads.changes = commit;
+        self.apply_changes(edit);
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let mut edit = self.edit_changes();
+        let removed = edit.remove(&self.changes, changes);
+        if removed == 0 {
+            return Ok(());
+        }
+        let message = format!("Remove {removed} changes");
+        let (reference_edit, commit) =
+            self.commit_changes(&mut edit, self.heads.changes, &message)?;
+        self.edit_references(vec![reference_edit])?;
+        self.heads.changes = commit;
+        self.apply_changes(edit);
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.document.map(|blob| self.read_blob(blob)).transpose()
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let blob = self.write_blob(&data)?;
+        let (edit, commit) = self.commit_document(blob, "Set document")?;
+        self.edit_references(vec![edit])?;
+        self.heads.document = Some(commit);
+        self.document = Some(blob);
+        self.sizes.document = blob.len;
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.sync_states
+            .get(&hex::encode(peer_id))
+            .map(|blob| self.read_blob(*blob))
+            .transpose()
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let blob = self.write_blob(&sync_state)?;
+        let mut sync_states = self.sync_states.clone();
+        sync_states.insert(hex::encode(peer_id), blob);
+        let (edit, commit) = self.commit_sync_states(&sync_states, "Set sync state")?;
+        self.edit_references(vec![edit])?;
+        self.heads.sync_states = commit;
+        self.apply_sync_states(sync_states);
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let mut sync_states = self.sync_states.clone();
+        if remove_sync_states(&mut sync_states, peer_ids) == 0 {
+            return Ok(());
+        }
+        let (edit, commit) = self.commit_sync_states(&sync_states, "Remove sync states")?;
+        self.edit_references(vec![edit])?;
+        self.heads.sync_states = commit;
+        self.apply_sync_states(sync_states);
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .sync_states
+            .keys()
+            .filter_map(|peer| hex::decode(peer).ok())
+            .collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        // objects and references are written as they are made
+        Ok(0)
+    }
+
+    /// Commits the document as a new snapshot, starts a new history for the remaining changes and
+    /// replaces the sync states, moving all three references in one transaction.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let mut changes_edit = self.edit_changes();
+        let compacted = changes_edit.remove(&self.changes, changes);
+        let message = format!("Compact {compacted} changes");
+        let blob = self.write_blob(&document)?;
+        let (document_edit, document_commit) = self.commit_document(blob, &message)?;
+        let (changes_reference_edit, changes_commit) =
+            self.commit_changes(&mut changes_edit, None, &message)?;
+        let mut edits = vec![document_edit, changes_reference_edit];
+        let mut sync_states = self.sync_states.clone();
+        let sync_states_commit = if remove_sync_states(&mut sync_states, old_peer_ids) > 0 {
+            let (edit, commit) = self.commit_sync_states(&sync_states, &message)?;
+            edits.push(edit);
+            commit
+        } else {
+            self.heads.sync_states
+        };
+        self.edit_references(edits)?;
+        self.heads = Heads {
+            document: Some(document_commit),
+            changes: changes_commit,
+            sync_states: sync_states_commit,
+        };
+        self.document = Some(blob);
+        self.sizes.document = blob.len;
+        self.apply_changes(changes_edit);
+        self.apply_sync_states(sync_states);
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! IndexedDB in the browser, through [idb](https://docs.rs/idb).
+
+use idb::{
+    Database, DatabaseEvent, Event, Factory, KeyRange, ObjectStoreParams, Query, Request,
+    TransactionMode,
+};
+use js_sys::Uint8Array;
+use wasm_bindgen::JsValue;
+
+use crate::{IdbDatabase, IdbWrite, StoreName};
+
+/// An IndexedDB database in the browser.
+///
+/// ```rust,no_run
+/// # use automerge_persistent_indexeddb::{BrowserIdb, IndexedDbPersister};
+/// # async fn open() -> Result<(), Box<dyn std::error::Error>> {
+/// let db = BrowserIdb::open("automerge").await?;
+/// let persister = IndexedDbPersister::new(db, "document".to_owned()).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct BrowserIdb {
+    db: Database,
+}
+
+impl BrowserIdb {
+    /// The version of the database layout, the object stores in [`StoreName::ALL`].
+    pub const VERSION: u32 = 1;
+
+    /// Open the database called `name`, creating it and its object stores if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if IndexedDB isn't available or the database can't be opened.
+    pub async fn open(name: &str) -> Result<Self, idb::Error> {
+        let factory = Factory::new()?;
+        let mut request = factory.open(name, Some(Self::VERSION))?;
+        request.on_upgrade_needed(|event| {
+            let created = event.database().and_then(|db| {
+                let existing = db.store_names();
+                for store in StoreName::ALL {
+                    if !existing.iter().any(|name| name == store.as_str()) {
+                        db.create_object_store(store.as_str(), ObjectStoreParams::new())?;
+                    }
+                }
+                Ok(())
+            });
+            if created.is_err() {
+                // aborting fails the open request rather than leaving the database at the new
+                // version without all of its stores
+                if let Some(transaction) = event.target().ok().and_then(|r| r.transaction()) {
+                    let _ = transaction.abort();
+                }
+            }
+        });
+        Ok(Self { db: request.await? })
+    }
+
+    /// Use an already open database, which must have the object stores in [`StoreName::ALL`].
+    #[must_use]
+    pub const fn new(db: Database) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl IdbDatabase for BrowserIdb {
+    type Error = idb::Error;
+
+    async fn get(&self, store: StoreName, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        let transaction = self
+            .db
+            .transaction(&[store.as_str()], TransactionMode::ReadOnly)?;
+        let value = transaction
+            .object_store(store.as_str())?
+            .get(JsValue::from_str(key))?
+            .await?;
+        Ok(value.map(|value| Uint8Array::new(&value).to_vec()))
+    }
+
+    async fn get_prefixed(
+        &self,
+        store: StoreName,
+        prefix: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, Self::Error> {
+        let range = KeyRange::bound(
+            &JsValue::from_str(prefix),
+            &JsValue::from_str(&format!("{prefix}\u{ffff}")),
+            None,
+            None,
+        )?;
+        let transaction = self
+            .db
+            .transaction(&[store.as_str()], TransactionMode::ReadOnly)?;
+        let object_store = transaction.object_store(store.as_str())?;
+        // both are in key order within the same transaction, so they line up
+        let keys = object_store
+            .get_all_keys(Some(Query::KeyRange(range.clone())), None)?
+            .await?;
+        let values = object_store
+            .get_all(Some(Query::KeyRange(range)), None)?
+            .await?;
+        Ok(keys
+            .into_iter()
+            .zip(values)
+            .filter_map(|(key, value)| Some((key.as_string()?, Uint8Array::new(&value).to_vec())))
+            .collect())
+    }
+
+    async fn write(&self, writes: Vec<IdbWrite>) -> Result<(), Self::Error> {
+        let mut stores = Vec::new();
+        for write in &writes {
+            let (IdbWrite::Put { store, .. } | IdbWrite::Delete { store, .. }) = write;
+            if !stores.contains(&store.as_str()) {
+                stores.push(store.as_str());
+            }
+        }
+        if stores.is_empty() {
+            return Ok(());
+        }
+        let transaction = self.db.transaction(&stores, TransactionMode::ReadWrite)?;
+        for write in writes {
+            // the requests complete with the transaction, which is waited on below
+            match write {
+                IdbWrite::Put { store, key, value } => {
+                    transaction.object_store(store.as_str())?.put(
+                        &Uint8Array::from(value.as_slice()).into(),
+                        Some(&JsValue::from_str(&key)),
+                    )?;
+                }
+                IdbWrite::Delete { store, key } => {
+                    transaction
+                        .object_store(store.as_str())?
+                        .delete(JsValue::from_str(&key))?;
+                }
+            }
+        }
+        if transaction.commit()?.await?.is_aborted() {
+            return Err(idb::Error::TransactionAbortError(JsValue::from_str(
+                "the transaction was aborted",
+            )));
+        }
+        Ok(())
+    }
+}
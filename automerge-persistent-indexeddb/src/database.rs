@@ -0,0 +1,86 @@
+//! The parts of the IndexedDB API that the persister uses.
+
+use std::error::Error;
+
+/// `Send` and `Sync` everywhere but on `wasm32`, where IndexedDB handles are single threaded.
+#[cfg(not(target_arch = "wasm32"))]
+pub trait MaybeSend: Send + Sync {}
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: Send + Sync> MaybeSend for T {}
+
+/// `Send` and `Sync` everywhere but on `wasm32`, where IndexedDB handles are single threaded.
+#[cfg(target_arch = "wasm32")]
+pub trait MaybeSend {}
+#[cfg(target_arch = "wasm32")]
+impl<T> MaybeSend for T {}
+
+/// One of the object stores in the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StoreName {
+    /// Holds the changes, keyed by `{name}/{actor id in hex}/{sequence number}`.
+    Changes,
+    /// Holds the documents, keyed by `{name}`.
+    Documents,
+    /// Holds the sync states, keyed by `{name}/{peer id in hex}`.
+    SyncStates,
+}
+
+impl StoreName {
+    /// All of the object stores, which a database needs to have.
+    pub const ALL: [Self; 3] = [Self::Changes, Self::Documents, Self::SyncStates];
+
+    /// The name of the object store in the database.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Changes => "changes",
+            Self::Documents => "documents",
+            Self::SyncStates => "sync_states",
+        }
+    }
+}
+
+/// A write to make as part of [`IdbDatabase::write`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IdbWrite {
+    /// Put `value` at `key`, replacing anything already there.
+    Put {
+        /// The object store to write to.
+        store: StoreName,
+        /// The key to write at.
+        key: String,
+        /// The value to write.
+        value: Vec<u8>,
+    },
+    /// Delete the value at `key`, if there is one.
+    Delete {
+        /// The object store to delete from.
+        store: StoreName,
+        /// The key to delete.
+        key: String,
+    },
+}
+
+/// A database with the object stores in [`StoreName::ALL`], using string keys and byte values.
+///
+/// `BrowserIdb` implements this on top of IndexedDB in the browser (on `wasm32` only) and
+/// [`MemoryIdb`](crate::MemoryIdb) stands in for it elsewhere, such as in native tests.
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+pub trait IdbDatabase: MaybeSend {
+    /// The error type that the operations can produce.
+    type Error: Error + MaybeSend + 'static;
+
+    /// Get the value at `key` in `store`.
+    async fn get(&self, store: StoreName, key: &str) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Get the keys and values in `store` with keys that start with `prefix`, ordered by key.
+    async fn get_prefixed(
+        &self,
+        store: StoreName,
+        prefix: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, Self::Error>;
+
+    /// Make all of the `writes` in one transaction, so either all or none of them happen.
+    async fn write(&self, writes: Vec<IdbWrite>) -> Result<(), Self::Error>;
+}
@@ -0,0 +1,549 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+// IndexedDB handles are single threaded, so nothing there can be sent
+#![cfg_attr(target_arch = "wasm32", allow(clippy::future_not_send))]
+
+//! A persister targetting IndexedDB in the browser.
+//!
+//! The persister works with anything implementing [`IdbDatabase`]. In the browser that is
+//! `BrowserIdb`, while [`MemoryIdb`] stands in for it elsewhere so the same logic can be used and
+//! tested natively.
+//!
+//! ```rust
+//! # use automerge_persistent::AsyncPersister;
+//! # use automerge_persistent_indexeddb::{IndexedDbPersister, MemoryIdb};
+//! # futures::executor::block_on(async {
+//! let db = MemoryIdb::default();
+//!
+//! let mut persister = IndexedDbPersister::new(db.clone(), "document".to_owned())
+//!     .await
+//!     .unwrap();
+//! persister.set_document(vec![1, 2, 3]).await.unwrap();
+//!
+//! // another persister on the same database sees the same document
+//! let other = IndexedDbPersister::new(db, "document".to_owned())
+//!     .await
+//!     .unwrap();
+//! assert_eq!(other.get_document().await.unwrap(), Some(vec![1, 2, 3]));
+//! # });
+//! ```
+
+#[cfg(target_arch = "wasm32")]
+mod browser;
+mod database;
+mod memory;
+
+use std::collections::HashMap;
+
+use automerge::ActorId;
+use automerge_persistent::{AsyncPersister, StoredCounts, StoredSizes};
+
+#[cfg(target_arch = "wasm32")]
+pub use crate::browser::BrowserIdb;
+pub use crate::{
+    database::{IdbDatabase, IdbWrite, MaybeSend, StoreName},
+    memory::MemoryIdb,
+};
+
+/// Persist changes, documents and sync states in to IndexedDB.
+///
+/// Unlike [`LocalStoragePersister`](https://docs.rs/automerge-persistent-localstorage) each change
+/// and sync state gets its own key, so inserts and removals only write what they touch. Keys
+/// start with the name of the persister, so any number of documents can share a database:
+///
+/// - changes: `{name}/{actor id in hex}/{sequence number}`
+/// - documents: `{name}`
+/// - sync states: `{name}/{peer id in hex}`
+///
+/// Each operation is a single transaction, including compaction, so other tabs never see one half
+/// done.
+///
+/// The sizes of the stored values are read when the persister is created and kept up to date
+/// with what it writes. Writes from other tabs for the same name aren't seen until
+/// [`IndexedDbPersister::verify_sizes`] is called.
+#[derive(Debug)]
+pub struct IndexedDbPersister<D> {
+    db: D,
+    name: String,
+    /// The lengths of the stored changes, by key.
+    changes: HashMap<String, u64>,
+    /// The lengths of the stored sync states, by key.
+    sync_states: HashMap<String, u64>,
+    sizes: StoredSizes,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum IndexedDbPersisterError<E> {
+    /// An underlying database error.
+    #[error(transparent)]
+    DatabaseError(#[from] E),
+    /// A key under the name of the persister that it didn't write.
+    #[error("unexpected key {0}")]
+    InvalidKey(String),
+}
+
+impl<D> IndexedDbPersister<D>
+where
+    D: IdbDatabase,
+{
+    /// Construct a new `IndexedDbPersister` for the values under `name` in `db`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from the database fails, or if a key under `name` wasn't
+    /// written by a persister.
+    pub async fn new(db: D, name: String) -> Result<Self, IndexedDbPersisterError<D::Error>> {
+        let mut persister = Self {
+            db,
+            name,
+            changes: HashMap::new(),
+            sync_states: HashMap::new(),
+            sizes: StoredSizes::default(),
+        };
+        persister.verify_sizes().await?;
+        Ok(persister)
+    }
+
+    /// The name the values of this persister are stored under.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of changes and sync states stored.
+    #[must_use]
+    pub fn counts(&self) -> StoredCounts {
+        StoredCounts {
+            changes: self.changes.len() as u64,
+            sync_states: self.sync_states.len() as u64,
+        }
+    }
+
+    /// Read the sizes from what is stored, correcting them if they were wrong.
+    ///
+    /// This is for when other tabs may have written under the same name. Returns whether they
+    /// were already right.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from the database fails, or if a key under the name wasn't
+    /// written by a persister.
+    pub async fn verify_sizes(&mut self) -> Result<bool, IndexedDbPersisterError<D::Error>> {
+        let changes = self
+            .stored_changes()
+            .await?
+            .into_iter()
+            .map(|(key, change)| (key, change.len() as u64))
+            .collect::<HashMap<_, _>>();
+        let sync_states = self
+            .stored_sync_states()
+            .await?
+            .into_iter()
+            .map(|(peer_id, sync_state)| (self.sync_state_key(&peer_id), sync_state.len() as u64))
+            .collect::<HashMap<_, _>>();
+        let document = self
+            .db
+            .get(StoreName::Documents, &self.name)
+            .await?
+            .map_or(0, |document| document.len() as u64);
+        let sizes = StoredSizes {
+            changes: changes.values().sum(),
+            document,
+            sync_states: sync_states.values().sum(),
+        };
+        let unchanged =
+            sizes == self.sizes && changes == self.changes && sync_states == self.sync_states;
+        self.changes = changes;
+        self.sync_states = sync_states;
+        self.sizes = sizes;
+        Ok(unchanged)
+    }
+
+    fn key_prefix(&self) -> String {
+        format!("{}/", self.name)
+    }
+
+    fn change_key(&self, actor_id: &ActorId, seq: u64) -> String {
+        format!("{}/{}/{}", self.name, actor_id.to_hex_string(), seq)
+    }
+
+    fn sync_state_key(&self, peer_id: &[u8]) -> String {
+        format!("{}/{}", self.name, hex::encode(peer_id))
+    }
+
+    /// The stored changes for this name, by key.
+    ///
+    /// The keys of names that start with this name and a `/` are also in the range, these have
+    /// more parts after the name so are skipped.
+    async fn stored_changes(
+        &self,
+    ) -> Result<Vec<(String, Vec<u8>)>, IndexedDbPersisterError<D::Error>> {
+        let prefix = self.key_prefix();
+        Ok(self
+            .db
+            .get_prefixed(StoreName::Changes, &prefix)
+            .await?
+            .into_iter()
+            .filter(|(key, _)| key[prefix.len()..].matches('/').count() == 1)
+            .collect())
+    }
+
+    /// The stored sync states for this name, by peer id.
+    async fn stored_sync_states(
+        &self,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, IndexedDbPersisterError<D::Error>> {
+        let prefix = self.key_prefix();
+        self.db
+            .get_prefixed(StoreName::SyncStates, &prefix)
+            .await?
+            .into_iter()
+            .filter(|(key, _)| !key[prefix.len()..].contains('/'))
+            .map(|(key, sync_state)| {
+                let peer_id = hex::decode(&key[prefix.len()..])
+                    .map_err(|_| IndexedDbPersisterError::InvalidKey(key.clone()))?;
+                Ok((peer_id, sync_state))
+            })
+            .collect()
+    }
+
+    /// The writes to remove `changes`.
+    fn remove_change_writes(&self, changes: Vec<(&ActorId, u64)>) -> Vec<IdbWrite> {
+        // changes written by other tabs may be stored without being known, so all are deleted
+        changes
+            .into_iter()
+            .map(|(a, s)| IdbWrite::Delete {
+                store: StoreName::Changes,
+                key: self.change_key(a, s),
+            })
+            .collect()
+    }
+
+    fn remove_sync_state_writes(&self, peer_ids: &[&[u8]]) -> Vec<IdbWrite> {
+        peer_ids
+            .iter()
+            .map(|peer_id| IdbWrite::Delete {
+                store: StoreName::SyncStates,
+                key: self.sync_state_key(peer_id),
+            })
+            .collect()
+    }
+
+    /// Update the sizes once the `writes` have been made.
+    fn apply_writes(&mut self, writes: &[IdbWrite]) {
+        for write in writes {
+            match write {
+                IdbWrite::Put {
+                    store: StoreName::Changes,
+                    key,
+                    value,
+                } => {
+                    self.sizes.changes += value.len() as u64;
+                    if let Some(old) = self.changes.insert(key.clone(), value.len() as u64) {
+                        self.sizes.changes -= old;
+                    }
+                }
+                IdbWrite::Delete {
+                    store: StoreName::Changes,
+                    key,
+                } => {
+                    if let Some(old) = self.changes.remove(key) {
+                        self.sizes.changes -= old;
+                    }
+                }
+                IdbWrite::Put {
+                    store: StoreName::Documents,
+                    value,
+                    ..
+                } => self.sizes.document = value.len() as u64,
+                IdbWrite::Delete {
+                    store: StoreName::Documents,
+                    ..
+                } => self.sizes.document = 0,
+                IdbWrite::Put {
+                    store: StoreName::SyncStates,
+                    key,
+                    value,
+                } => {
+                    self.sizes.sync_states += value.len() as u64;
+                    if let Some(old) = self.sync_states.insert(key.clone(), value.len() as u64) {
+                        self.sizes.sync_states -= old;
+                    }
+                }
+                IdbWrite::Delete {
+                    store: StoreName::SyncStates,
+                    key,
+                } => {
+                    if let Some(old) = self.sync_states.remove(key) {
+                        self.sizes.sync_states -= old;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Make the `writes` in one transaction, updating the sizes if it succeeds.
+    async fn write(
+        &mut self,
+        writes: Vec<IdbWrite>,
+    ) -> Result<(), IndexedDbPersisterError<D::Error>> {
+        self.db.write(writes.clone()).await?;
+        self.apply_writes(&writes);
+        Ok(())
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl<D> AsyncPersister for IndexedDbPersister<D>
+where
+    D: IdbDatabase,
+{
+    type Error = IndexedDbPersisterError<D::Error>;
+
+    async fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .stored_changes()
+            .await?
+            .into_iter()
+            .map(|(_, change)| change)
+            .collect())
+    }
+
+    async fn insert_changes(
+        &mut self,
+        changes: Vec<(ActorId, u64, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        let writes = changes
+            .into_iter()
+            .map(|(a, s, c)| IdbWrite::Put {
+                store: StoreName::Changes,
+                key: self.change_key(&a, s),
+                value: c,
+            })
+            .collect();
+        self.write(writes).await
+    }
+
+    async fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let writes = self.remove_change_writes(changes);
+        self.write(writes).await
+    }
+
+    async fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.db.get(StoreName::Documents, &self.name).await?)
+    }
+
+    async fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let writes = vec![IdbWrite::Put {
+            store: StoreName::Documents,
+            key: self.name.clone(),
+            value: data,
+        }];
+        self.write(writes).await
+    }
+
+    async fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .db
+            .get(StoreName::SyncStates, &self.sync_state_key(peer_id))
+            .await?)
+    }
+
+    async fn set_sync_state(
+        &mut self,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        let writes = vec![IdbWrite::Put {
+            store: StoreName::SyncStates,
+            key: self.sync_state_key(&peer_id),
+            value: sync_state,
+        }];
+        self.write(writes).await
+    }
+
+    async fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let writes = self.remove_sync_state_writes(peer_ids);
+        self.write(writes).await
+    }
+
+    async fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .stored_sync_states()
+            .await?
+            .into_iter()
+            .map(|(peer_id, _)| peer_id)
+            .collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    async fn flush(&mut self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+
+    /// Sets the document and removes the changes and sync states in a single transaction.
+    async fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let mut writes = vec![IdbWrite::Put {
+            store: StoreName::Documents,
+            key: self.name.clone(),
+            value: document,
+        }];
+        writes.extend(self.remove_change_writes(changes));
+        writes.extend(self.remove_sync_state_writes(old_peer_ids));
+        self.write(writes).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::ActorId;
+    use automerge_persistent::{AsyncPersister, StoredSizes};
+    use futures::executor::block_on;
+
+    use crate::{IndexedDbPersister, MemoryIdb};
+
+    async fn persister(db: &MemoryIdb, name: &str) -> IndexedDbPersister<MemoryIdb> {
+        IndexedDbPersister::new(db.clone(), name.to_owned())
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn names_are_kept_apart() {
+        block_on(async {
+            let db = MemoryIdb::default();
+            let mut a = persister(&db, "a").await;
+            let mut nested = persister(&db, "a/b").await;
+            let actor = ActorId::random();
+
+            a.insert_changes(vec![(actor.clone(), 1, vec![1])])
+                .await
+                .unwrap();
+            a.set_sync_state(vec![0xaa], vec![2, 2]).await.unwrap();
+            nested
+                .insert_changes(vec![(actor.clone(), 1, vec![3, 3, 3])])
+                .await
+                .unwrap();
+            nested.set_sync_state(vec![0xbb], vec![4]).await.unwrap();
+            nested.set_document(vec![5]).await.unwrap();
+
+            assert_eq!(a.get_changes().await.unwrap(), vec![vec![1]]);
+            assert_eq!(a.get_peer_ids().await.unwrap(), vec![vec![0xaa]]);
+            assert_eq!(a.get_document().await.unwrap(), None);
+            assert_eq!(nested.get_changes().await.unwrap(), vec![vec![3, 3, 3]]);
+            assert_eq!(nested.get_peer_ids().await.unwrap(), vec![vec![0xbb]]);
+
+            // reading the sizes back from the database doesn't count the other name's values
+            assert!(a.verify_sizes().await.unwrap());
+            assert!(nested.verify_sizes().await.unwrap());
+            assert_eq!(
+                a.sizes(),
+                StoredSizes {
+                    changes: 1,
+                    document: 0,
+                    sync_states: 2,
+                }
+            );
+
+            a.remove_changes(vec![(&actor, 1)]).await.unwrap();
+            assert_eq!(nested.get_changes().await.unwrap(), vec![vec![3, 3, 3]]);
+        });
+    }
+
+    #[test]
+    fn compact_replaces_changes_with_document() {
+        block_on(async {
+            let db = MemoryIdb::default();
+            let mut persister = persister(&db, "doc").await;
+            let actor = ActorId::random();
+
+            persister
+                .insert_changes(vec![
+                    (actor.clone(), 1, vec![1]),
+                    (actor.clone(), 2, vec![2, 2]),
+                ])
+                .await
+                .unwrap();
+            persister.set_sync_state(vec![1], vec![1]).await.unwrap();
+            persister.set_sync_state(vec![2], vec![2]).await.unwrap();
+
+            // a change written after the document was saved is kept
+            persister
+                .compact(vec![9; 4], vec![(&actor, 1)], &[&[1]])
+                .await
+                .unwrap();
+
+            assert_eq!(persister.get_document().await.unwrap(), Some(vec![9; 4]));
+            assert_eq!(persister.get_changes().await.unwrap(), vec![vec![2, 2]]);
+            assert_eq!(persister.get_peer_ids().await.unwrap(), vec![vec![2]]);
+            assert_eq!(
+                persister.sizes(),
+                StoredSizes {
+                    changes: 2,
+                    document: 4,
+                    sync_states: 1,
+                }
+            );
+            assert!(persister.verify_sizes().await.unwrap());
+        });
+    }
+
+    #[test]
+    fn verify_sizes_sees_other_writers() {
+        block_on(async {
+            let db = MemoryIdb::default();
+            let mut first = persister(&db, "doc").await;
+            let mut second = persister(&db, "doc").await;
+            let actor = ActorId::random();
+
+            first
+                .insert_changes(vec![(actor.clone(), 1, vec![1, 1])])
+                .await
+                .unwrap();
+            second
+                .insert_changes(vec![(actor.clone(), 2, vec![2, 2, 2])])
+                .await
+                .unwrap();
+            second.set_sync_state(vec![1], vec![1]).await.unwrap();
+            second.set_document(vec![5; 5]).await.unwrap();
+
+            assert_eq!(first.sizes().changes, 2);
+            assert!(!first.verify_sizes().await.unwrap());
+            assert_eq!(
+                first.sizes(),
+                StoredSizes {
+                    changes: 5,
+                    document: 5,
+                    sync_states: 1,
+                }
+            );
+            assert_eq!(first.counts().changes, 2);
+            assert!(first.verify_sizes().await.unwrap());
+
+            // removing what the other persister wrote once it is known keeps the sizes right
+            first.remove_changes(vec![(&actor, 2)]).await.unwrap();
+            first.remove_sync_states(&[&[1]]).await.unwrap();
+            assert_eq!(
+                first.sizes(),
+                StoredSizes {
+                    changes: 2,
+                    document: 5,
+                    sync_states: 0,
+                }
+            );
+            assert!(first.verify_sizes().await.unwrap());
+        });
+    }
+}
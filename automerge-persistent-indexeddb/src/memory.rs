@@ -0,0 +1,89 @@
+//! An in-memory stand-in for IndexedDB.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::{IdbDatabase, IdbWrite, StoreName};
+
+/// The values in each object store, by key.
+type Stores = HashMap<StoreName, BTreeMap<String, Vec<u8>>>;
+
+/// A database kept in memory, for using the persister where there is no IndexedDB.
+///
+/// Clones share the same data, like separate connections to the same IndexedDB database.
+///
+/// ```rust
+/// # use automerge_persistent_indexeddb::{IdbDatabase, IdbWrite, MemoryIdb, StoreName};
+/// # futures::executor::block_on(async {
+/// let db = MemoryIdb::default();
+/// let other = db.clone();
+/// db.write(vec![IdbWrite::Put {
+///     store: StoreName::Documents,
+///     key: "doc".to_owned(),
+///     value: vec![1],
+/// }])
+/// .await
+/// .unwrap();
+/// assert_eq!(
+///     other.get(StoreName::Documents, "doc").await.unwrap(),
+///     Some(vec![1])
+/// );
+/// # });
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryIdb {
+    stores: Arc<Mutex<Stores>>,
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl IdbDatabase for MemoryIdb {
+    type Error = Infallible;
+
+    async fn get(&self, store: StoreName, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        let stores = self.stores.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(stores
+            .get(&store)
+            .and_then(|values| values.get(key))
+            .cloned())
+    }
+
+    async fn get_prefixed(
+        &self,
+        store: StoreName,
+        prefix: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, Self::Error> {
+        let stores = self.stores.lock().unwrap_or_else(PoisonError::into_inner);
+        Ok(stores
+            .get(&store)
+            .map(|values| {
+                values
+                    .range(prefix.to_owned()..)
+                    .take_while(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn write(&self, writes: Vec<IdbWrite>) -> Result<(), Self::Error> {
+        // the lock is held for all of the writes so they are seen together
+        let mut stores = self.stores.lock().unwrap_or_else(PoisonError::into_inner);
+        for write in writes {
+            match write {
+                IdbWrite::Put { store, key, value } => {
+                    stores.entry(store).or_default().insert(key, value);
+                }
+                IdbWrite::Delete { store, key } => {
+                    if let Some(values) = stores.get_mut(&store) {
+                        values.remove(&key);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,524 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [LMDB](http://www.lmdb.tech/doc/) through
+//! [heed](https://github.com/meilisearch/heed).
+//!
+//! # Single persister
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_lmdb::LmdbPersister;
+//! # use automerge_persistent_lmdb::LmdbPersisterError;
+//! # fn main() -> Result<(), LmdbPersisterError> {
+//! # let dir = tempfile::tempdir().unwrap();
+//! let env = unsafe {
+//!     heed::EnvOpenOptions::new()
+//!         .max_dbs(LmdbPersister::MAX_DBS)
+//!         .open(dir.path())?
+//! };
+//!
+//! let persister = LmdbPersister::new(env, "")?;
+//! let doc = PersistentAutomerge::load(persister);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Multiple persisters sharing the same environment
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_lmdb::LmdbPersister;
+//! # use automerge_persistent_lmdb::LmdbPersisterError;
+//! # fn main() -> Result<(), LmdbPersisterError> {
+//! # let dir = tempfile::tempdir().unwrap();
+//! let env = unsafe {
+//!     heed::EnvOpenOptions::new()
+//!         .max_dbs(LmdbPersister::MAX_DBS)
+//!         .open(dir.path())?
+//! };
+//!
+//! let persister1 = LmdbPersister::new(env.clone(), "1")?;
+//! let doc1 = PersistentAutomerge::load(persister1);
+//!
+//! let persister2 = LmdbPersister::new(env, "2")?;
+//! let doc2 = PersistentAutomerge::load(persister2);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Followers with read-only environments
+//!
+//! Other processes can open the same environment read-only, once it has been set up by a
+//! writable one. Each read sees the latest committed state without waiting for writers.
+//!
+//! ```rust
+//! # use automerge_persistent::{PersistentAutomerge, Persister};
+//! # use automerge_persistent_lmdb::LmdbPersister;
+//! # use automerge_persistent_lmdb::LmdbPersisterError;
+//! # fn main() -> Result<(), LmdbPersisterError> {
+//! # let dir = tempfile::tempdir().unwrap();
+//! # let env = unsafe {
+//! #     heed::EnvOpenOptions::new()
+//! #         .max_dbs(LmdbPersister::MAX_DBS)
+//! #         .open(dir.path())?
+//! # };
+//! # let mut leader = LmdbPersister::new(env.clone(), "1")?;
+//! # leader.set_document(vec![1, 2, 3])?;
+//! # drop(leader);
+//! # // an environment can only be open once in each process
+//! # env.prepare_for_closing().wait();
+//! let env = unsafe {
+//!     heed::EnvOpenOptions::new()
+//!         .max_dbs(LmdbPersister::MAX_DBS)
+//!         .flags(heed::EnvFlags::READ_ONLY)
+//!         .open(dir.path())?
+//! };
+//!
+//! let mut follower = LmdbPersister::new(env, "1")?;
+//! assert_eq!(follower.get_document()?, Some(vec![1, 2, 3]));
+//! assert!(matches!(
+//!     follower.set_document(vec![]),
+//!     Err(LmdbPersisterError::ReadOnly)
+//! ));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::convert::TryFrom;
+
+use automerge::ActorId;
+use automerge_persistent::{namespace, Persister, StoredCounts, StoredSizes};
+use heed::{types::Bytes, Database, Env, EnvFlags, RoTxn, RwTxn};
+
+/// The persister that stores changes, documents and sync states in LMDB databases.
+///
+/// The environment holds one named database for each of changes, documents and sync states,
+/// plus one for sizes, so it needs room for [`LmdbPersister::MAX_DBS`]. Keys start with the
+/// [`namespace`] of the prefix, so each document has its own key range and any number of
+/// persisters can share the environment, from any number of processes.
+///
+/// LMDB caps keys at [`Env::max_key_size`], 511 bytes unless it was built with a different
+/// limit. A change's key is the namespace followed by the actor id and sequence number, and a
+/// sync state's the namespace followed by the peer id, so writes with a long prefix, actor id or
+/// peer id can fail with [`heed::MdbError::BadValSize`]. Everything stored also has to fit in the
+/// environment's map size, which LMDB defaults to 10 MiB, beyond which writes fail with
+/// [`heed::MdbError::MapFull`].
+///
+/// LMDB runs one write transaction at a time across every process sharing the environment, and
+/// each write here is one transaction that also updates the sizes and counts recorded for the
+/// prefix, so these account for every writer. [`Persister::sizes`] returns them as of the last
+/// write or load through this persister, [`LmdbPersister::reload_sizes`] reads them again.
+/// Readers never wait for the writer, each read sees the last committed write.
+///
+/// If the environment was opened with [`EnvFlags::READ_ONLY`] the persister only reads, and all
+/// writes return [`LmdbPersisterError::ReadOnly`].
+#[derive(Debug)]
+pub struct LmdbPersister {
+    env: Env,
+    dbs: Databases,
+    /// The start of every key belonging to this persister, see [`namespace`].
+    namespace: Vec<u8>,
+    read_only: bool,
+    sizes: StoredSizes,
+    counts: StoredCounts,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum LmdbPersisterError {
+    /// Internal errors from LMDB.
+    #[error(transparent)]
+    HeedError(#[from] heed::Error),
+    /// A write was attempted through a read-only environment.
+    #[error("the environment is read-only")]
+    ReadOnly,
+    /// A read-only environment was opened before a writable one created the databases.
+    #[error("the databases have not been created yet")]
+    Uninitialized,
+}
+
+type Db = Database<Bytes, Bytes>;
+
+/// Keys with their namespace removed, along with their values.
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// The named databases used in the environment.
+#[derive(Debug, Clone, Copy)]
+struct Databases {
+    changes: Db,
+    documents: Db,
+    sync_states: Db,
+    /// The sizes and counts for each prefix, keyed by namespace.
+    sizes: Db,
+}
+
+const CHANGES: &str = "changes";
+const DOCUMENTS: &str = "documents";
+const SYNC_STATES: &str = "sync_states";
+const SIZES: &str = "sizes";
+
+/// The version of the size record layout.
+const SIZES_VERSION: u8 = 1;
+
+impl Databases {
+    fn create(env: &Env) -> heed::Result<Self> {
+        let mut txn = env.write_txn()?;
+        let dbs = Self {
+            changes: env.create_database(&mut txn, Some(CHANGES))?,
+            documents: env.create_database(&mut txn, Some(DOCUMENTS))?,
+            sync_states: env.create_database(&mut txn, Some(SYNC_STATES))?,
+            sizes: env.create_database(&mut txn, Some(SIZES))?,
+        };
+        txn.commit()?;
+        Ok(dbs)
+    }
+
+    /// Open the databases that a writable environment created, `None` if it hasn't yet.
+    fn open(env: &Env) -> heed::Result<Option<Self>> {
+        let txn = env.read_txn()?;
+        let open = |name| env.open_database(&txn, Some(name));
+        let dbs = match (
+            open(CHANGES)?,
+            open(DOCUMENTS)?,
+            open(SYNC_STATES)?,
+            open(SIZES)?,
+        ) {
+            (Some(changes), Some(documents), Some(sync_states), Some(sizes)) => Some(Self {
+                changes,
+                documents,
+                sync_states,
+                sizes,
+            }),
+            _ => None,
+        };
+        // keeps the handles open in the environment for later transactions
+        txn.commit()?;
+        Ok(dbs)
+    }
+
+    /// The recorded sizes and counts for `namespace`.
+    fn sizes(&self, txn: &RoTxn, namespace: &[u8]) -> heed::Result<(StoredSizes, StoredCounts)> {
+        Ok(self
+            .sizes
+            .get(txn, namespace)?
+            .and_then(decode_sizes)
+            .unwrap_or_default())
+    }
+
+    /// Remove the `keys` from `db`, returning the bytes and number of entries removed.
+    fn remove_all(db: Db, txn: &mut RwTxn, keys: &[Vec<u8>]) -> heed::Result<(u64, u64)> {
+        let mut removed = (0, 0);
+        for key in keys {
+            if let Some(old) = db.get(txn, key)? {
+                removed.0 += old.len() as u64;
+                removed.1 += 1;
+                db.delete(txn, key)?;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Store `value` under `key` in `db`, returning the length of the value it replaced.
+    fn replace(db: Db, txn: &mut RwTxn, key: &[u8], value: &[u8]) -> heed::Result<Option<u64>> {
+        let old = db.get(txn, key)?.map(|old| old.len() as u64);
+        db.put(txn, key, value)?;
+        Ok(old)
+    }
+}
+
+fn encode_sizes(sizes: &StoredSizes, counts: &StoredCounts) -> Vec<u8> {
+    let mut record = vec![SIZES_VERSION];
+    for field in &[
+        sizes.changes,
+        sizes.document,
+        sizes.sync_states,
+        counts.changes,
+        counts.sync_states,
+    ] {
+        record.extend(&field.to_be_bytes());
+    }
+    record
+}
+
+fn decode_sizes(record: &[u8]) -> Option<(StoredSizes, StoredCounts)> {
+    let (version, fields) = record.split_first()?;
+    if *version != SIZES_VERSION || fields.len() != 5 * 8 {
+        return None;
+    }
+    let mut fields = fields
+        .chunks_exact(8)
+        .map(|field| u64::from_be_bytes(<[u8; 8]>::try_from(field).unwrap_or_default()));
+    let mut next = || fields.next().unwrap_or_default();
+    Some((
+        StoredSizes {
+            changes: next(),
+            document: next(),
+            sync_states: next(),
+        },
+        StoredCounts {
+            changes: next(),
+            sync_states: next(),
+        },
+    ))
+}
+
+impl LmdbPersister {
+    /// The number of named databases the persister uses, which the environment must have been
+    /// opened with room for through [`heed::EnvOpenOptions::max_dbs`].
+    pub const MAX_DBS: u32 = 4;
+
+    /// Construct a new persister.
+    ///
+    /// The databases are created if they don't exist yet, unless the environment is read-only.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from or writing to LMDB fails, or if the environment is
+    /// read-only and no writable environment has created the databases yet.
+    pub fn new<S>(env: Env, prefix: S) -> Result<Self, LmdbPersisterError>
+    where
+        S: Into<String>,
+    {
+        let read_only = env.get_flags()? & EnvFlags::READ_ONLY.bits() != 0;
+        let dbs = if read_only {
+            Databases::open(&env)?.ok_or(LmdbPersisterError::Uninitialized)?
+        } else {
+            Databases::create(&env)?
+        };
+        let mut s = Self {
+            env,
+            dbs,
+            namespace: namespace(&prefix.into()),
+            read_only,
+            sizes: StoredSizes::default(),
+            counts: StoredCounts::default(),
+        };
+        s.reload_sizes()?;
+        Ok(s)
+    }
+
+    /// Whether the environment is read-only, so that writes will fail.
+    #[must_use]
+    pub const fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The number of changes and sync states stored.
+    #[must_use]
+    pub fn counts(&self) -> StoredCounts {
+        self.counts.clone()
+    }
+
+    /// Read the sizes and counts again, picking up writes from other persisters.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from LMDB fails.
+    pub fn reload_sizes(&mut self) -> Result<(), LmdbPersisterError> {
+        let txn = self.env.read_txn()?;
+        let (sizes, counts) = self.dbs.sizes(&txn, &self.namespace)?;
+        self.sizes = sizes;
+        self.counts = counts;
+        Ok(())
+    }
+
+    /// Make a key from the prefix, `actor_id` and `sequence_number`.
+    ///
+    /// Converts the `actor_id` to bytes and appends the `sequence_number` in big endian form.
+    fn make_key(&self, actor_id: &ActorId, seq: u64) -> Vec<u8> {
+        let mut key = self.namespace.clone();
+        key.extend(actor_id.to_bytes());
+        key.extend(&seq.to_be_bytes());
+        key
+    }
+
+    fn make_peer_key(&self, peer_id: &[u8]) -> Vec<u8> {
+        let mut key = self.namespace.clone();
+        key.extend(peer_id);
+        key
+    }
+
+    /// Run `f` in a write transaction, keeping the recorded sizes and counts up to date with
+    /// what it changes.
+    fn write<F>(&mut self, f: F) -> Result<(), LmdbPersisterError>
+    where
+        F: FnOnce(&Databases, &mut RwTxn, &mut StoredSizes, &mut StoredCounts) -> heed::Result<()>,
+    {
+        if self.read_only {
+            return Err(LmdbPersisterError::ReadOnly);
+        }
+        let mut txn = self.env.write_txn()?;
+        let (mut sizes, mut counts) = self.dbs.sizes(&txn, &self.namespace)?;
+        f(&self.dbs, &mut txn, &mut sizes, &mut counts)?;
+        self.dbs
+            .sizes
+            .put(&mut txn, &self.namespace, &encode_sizes(&sizes, &counts))?;
+        txn.commit()?;
+        self.sizes = sizes;
+        self.counts = counts;
+        Ok(())
+    }
+
+    /// The entries of `db` with keys that start with the namespace, with it removed from the
+    /// keys.
+    fn scan_prefix(&self, db: Db) -> Result<Entries, LmdbPersisterError> {
+        let txn = self.env.read_txn()?;
+        let entries = db
+            .prefix_iter(&txn, &self.namespace)?
+            .map(|entry| {
+                entry.map(|(key, value)| (key[self.namespace.len()..].to_vec(), value.to_vec()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(entries)
+    }
+}
+
+impl Persister for LmdbPersister {
+    type Error = LmdbPersisterError;
+
+    /// Get all of the current changes.
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .scan_prefix(self.dbs.changes)?
+            .into_iter()
+            .map(|(_, change)| change)
+            .collect())
+    }
+
+    /// Insert all of the given changes in a single transaction.
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let keys = changes
+            .iter()
+            .map(|(a, s, _)| self.make_key(a, *s))
+            .collect::<Vec<_>>();
+        self.write(|dbs, txn, sizes, counts| {
+            for (key, (_, _, c)) in keys.iter().zip(&changes) {
+                match Databases::replace(dbs.changes, txn, key, c)? {
+                    Some(old) => sizes.changes = sizes.changes.saturating_sub(old),
+                    None => counts.changes += 1,
+                }
+                sizes.changes += c.len() as u64;
+            }
+            Ok(())
+        })
+    }
+
+    /// Remove all of the given changes in a single transaction.
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let keys = changes
+            .into_iter()
+            .map(|(a, s)| self.make_key(a, s))
+            .collect::<Vec<_>>();
+        self.write(|dbs, txn, sizes, counts| {
+            let removed = Databases::remove_all(dbs.changes, txn, &keys)?;
+            sizes.changes = sizes.changes.saturating_sub(removed.0);
+            counts.changes = counts.changes.saturating_sub(removed.1);
+            Ok(())
+        })
+    }
+
+    /// Retrieve the document.
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        let txn = self.env.read_txn()?;
+        let document = self.dbs.documents.get(&txn, &self.namespace)?;
+        Ok(document.map(<[u8]>::to_vec))
+    }
+
+    /// Set the document.
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let namespace = self.namespace.clone();
+        self.write(|dbs, txn, sizes, _| {
+            dbs.documents.put(txn, &namespace, &data)?;
+            sizes.document = data.len() as u64;
+            Ok(())
+        })
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let txn = self.env.read_txn()?;
+        let sync_state = self
+            .dbs
+            .sync_states
+            .get(&txn, &self.make_peer_key(peer_id))?;
+        Ok(sync_state.map(<[u8]>::to_vec))
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let key = self.make_peer_key(&peer_id);
+        self.write(|dbs, txn, sizes, counts| {
+            match Databases::replace(dbs.sync_states, txn, &key, &sync_state)? {
+                Some(old) => sizes.sync_states = sizes.sync_states.saturating_sub(old),
+                None => counts.sync_states += 1,
+            }
+            sizes.sync_states += sync_state.len() as u64;
+            Ok(())
+        })
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let keys = peer_ids
+            .iter()
+            .map(|id| self.make_peer_key(id))
+            .collect::<Vec<_>>();
+        self.write(|dbs, txn, sizes, counts| {
+            let removed = Databases::remove_all(dbs.sync_states, txn, &keys)?;
+            sizes.sync_states = sizes.sync_states.saturating_sub(removed.0);
+            counts.sync_states = counts.sync_states.saturating_sub(removed.1);
+            Ok(())
+        })
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Ok(self
+            .scan_prefix(self.dbs.sync_states)?
+            .into_iter()
+            .map(|(peer_id, _)| peer_id)
+            .collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Set the document and remove the changes and sync states in one write transaction.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let namespace = self.namespace.clone();
+        let change_keys = changes
+            .into_iter()
+            .map(|(a, s)| self.make_key(a, s))
+            .collect::<Vec<_>>();
+        let peer_keys = old_peer_ids
+            .iter()
+            .map(|id| self.make_peer_key(id))
+            .collect::<Vec<_>>();
+        self.write(|dbs, txn, sizes, counts| {
+            dbs.documents.put(txn, &namespace, &document)?;
+            sizes.document = document.len() as u64;
+            let removed = Databases::remove_all(dbs.changes, txn, &change_keys)?;
+            sizes.changes = sizes.changes.saturating_sub(removed.0);
+            counts.changes = counts.changes.saturating_sub(removed.1);
+            let removed = Databases::remove_all(dbs.sync_states, txn, &peer_keys)?;
+            sizes.sync_states = sizes.sync_states.saturating_sub(removed.0);
+            counts.sync_states = counts.sync_states.saturating_sub(removed.1);
+            Ok(())
+        })
+    }
+
+    /// Commits are durable unless the environment was opened with flags such as
+    /// [`EnvFlags::NO_SYNC`], in which case this syncs it to disk.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        if !self.read_only {
+            self.env.force_sync()?;
+        }
+        Ok(0)
+    }
+}
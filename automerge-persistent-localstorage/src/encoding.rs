@@ -0,0 +1,125 @@
+//! How binary values are stored in the string values of `LocalStorage`.
+//!
+//! Values start with a header naming the version of the encoding, `amp1:` for the current one,
+//! followed by the data in base64. Values written before the header was introduced are JSON
+//! arrays or objects, so start with `[` or `{` and can't be mistaken for a header.
+
+use std::collections::HashMap;
+
+use base64::Engine;
+
+use crate::LocalStoragePersisterError;
+
+/// The version of the encoding that is written.
+pub const VERSION: u32 = 1;
+
+const HEADER_PREFIX: &str = "amp";
+
+/// Sync states keyed by peer id.
+pub type SyncStates = HashMap<Vec<u8>, Vec<u8>>;
+
+/// Encode `bytes` with a header for the current version.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut value = format!("{HEADER_PREFIX}{VERSION}:");
+    base64::engine::general_purpose::STANDARD.encode_string(bytes, &mut value);
+    value
+}
+
+/// Decode a value stored under `key`, returning `None` if it has no header and so is in the old
+/// JSON encoding.
+///
+/// # Errors
+///
+/// Returns an error if the header is for an unknown version or the data can't be decoded.
+pub fn decode(key: &str, value: &str) -> Result<Option<Vec<u8>>, LocalStoragePersisterError> {
+    let Some(rest) = value.strip_prefix(HEADER_PREFIX) else {
+        return Ok(None);
+    };
+    let (version, data) = rest
+        .split_once(':')
+        .ok_or_else(|| LocalStoragePersisterError::InvalidValue(key.to_owned()))?;
+    let version = version
+        .parse::<u32>()
+        .map_err(|_| LocalStoragePersisterError::InvalidValue(key.to_owned()))?;
+    if version != VERSION {
+        return Err(LocalStoragePersisterError::UnsupportedVersion(
+            key.to_owned(),
+            version,
+        ));
+    }
+    Ok(Some(
+        base64::engine::general_purpose::STANDARD.decode(data)?,
+    ))
+}
+
+/// Decode a byte string stored under `key`, returning whether it was in the old JSON encoding
+/// along with it.
+///
+/// # Errors
+///
+/// Returns an error if the value can't be decoded.
+pub fn decode_bytes(key: &str, value: &str) -> Result<(Vec<u8>, bool), LocalStoragePersisterError> {
+    match decode(key, value)? {
+        Some(bytes) => Ok((bytes, false)),
+        None => Ok((serde_json::from_str(value)?, true)),
+    }
+}
+
+/// Encode sync states, keyed by peer id, as a run of length prefixed peer ids and sync states.
+pub fn encode_sync_states(sync_states: &SyncStates) -> String {
+    let mut buf = Vec::new();
+    for (peer_id, sync_state) in sync_states {
+        push_field(&mut buf, peer_id);
+        push_field(&mut buf, sync_state);
+    }
+    encode(&buf)
+}
+
+/// Decode the sync states stored under `key`, returning whether they were in the old JSON
+/// encoding along with them.
+///
+/// # Errors
+///
+/// Returns an error if the value can't be decoded.
+pub fn decode_sync_states(
+    key: &str,
+    value: &str,
+) -> Result<(SyncStates, bool), LocalStoragePersisterError> {
+    let Some(buf) = decode(key, value)? else {
+        // the old encoding used a JSON map from base64 peer ids
+        let legacy = serde_json::from_str::<HashMap<String, Vec<u8>>>(value)?;
+        let mut sync_states = HashMap::with_capacity(legacy.len());
+        for (peer_id, sync_state) in legacy {
+            let peer_id = base64::engine::general_purpose::STANDARD.decode(peer_id)?;
+            sync_states.insert(peer_id, sync_state);
+        }
+        return Ok((sync_states, true));
+    };
+    let mut rest = buf.as_slice();
+    let mut sync_states = HashMap::new();
+    while !rest.is_empty() {
+        let (Some(peer_id), Some(sync_state)) = (take_field(&mut rest), take_field(&mut rest))
+        else {
+            return Err(LocalStoragePersisterError::InvalidValue(key.to_owned()));
+        };
+        sync_states.insert(peer_id.to_vec(), sync_state.to_vec());
+    }
+    Ok((sync_states, false))
+}
+
+/// Append `field` to `buf`, prefixed with its length as a u32.
+// values in LocalStorage are limited to a few megabytes
+#[allow(clippy::cast_possible_truncation)]
+fn push_field(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend(&(field.len() as u32).to_be_bytes());
+    buf.extend(field);
+}
+
+/// Take a field prefixed with its length as a u32 off the front of `buf`.
+fn take_field<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = buf.get(..4)?;
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let field = buf.get(4..4 + len)?;
+    *buf = &buf[4 + len..];
+    Some(field)
+}
@@ -0,0 +1,133 @@
+//! The index of stored changes, kept in the changes key so that each change can live in its own
+//! key.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A set of sequence numbers, held as runs of consecutive numbers.
+///
+/// An actor's changes almost always have consecutive sequence numbers, so this keeps the index
+/// to a handful of runs however many changes are stored.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SeqRanges {
+    /// The first sequence number of each run, mapped to its last.
+    ranges: BTreeMap<u64, u64>,
+}
+
+impl SeqRanges {
+    pub fn contains(&self, seq: u64) -> bool {
+        self.ranges
+            .range(..=seq)
+            .next_back()
+            .is_some_and(|(_, end)| seq <= *end)
+    }
+
+    /// Add `seq`, returning whether it wasn't already present.
+    pub fn insert(&mut self, seq: u64) -> bool {
+        if self.contains(seq) {
+            return false;
+        }
+        let mut start = seq;
+        let mut end = seq;
+        if let Some((before, before_end)) = self.ranges.range(..seq).next_back() {
+            if before_end + 1 == seq {
+                start = *before;
+            }
+        }
+        if let Some(after_end) = seq
+            .checked_add(1)
+            .and_then(|after| self.ranges.remove(&after))
+        {
+            end = after_end;
+        }
+        self.ranges.insert(start, end);
+        true
+    }
+
+    /// Remove `seq`, returning whether it was present.
+    pub fn remove(&mut self, seq: u64) -> bool {
+        let (start, end) = match self.ranges.range(..=seq).next_back() {
+            Some((start, end)) if seq <= *end => (*start, *end),
+            _ => return false,
+        };
+        self.ranges.remove(&start);
+        if start < seq {
+            self.ranges.insert(start, seq - 1);
+        }
+        if seq < end {
+            self.ranges.insert(seq + 1, end);
+        }
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.ranges.iter().flat_map(|(start, end)| *start..=*end)
+    }
+}
+
+/// The changes stored for each actor, keyed by the actor id in hex.
+pub type ChangeIndex = BTreeMap<String, SeqRanges>;
+
+/// The index as it is stored, with each actor's runs as `[first, last]` pairs.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StoredIndex {
+    actors: BTreeMap<String, Vec<(u64, u64)>>,
+}
+
+impl From<&ChangeIndex> for StoredIndex {
+    fn from(index: &ChangeIndex) -> Self {
+        Self {
+            actors: index
+                .iter()
+                .map(|(actor, seqs)| {
+                    let runs = seqs.ranges.iter().map(|(s, e)| (*s, *e)).collect();
+                    (actor.clone(), runs)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<StoredIndex> for ChangeIndex {
+    fn from(stored: StoredIndex) -> Self {
+        stored
+            .actors
+            .into_iter()
+            .map(|(actor, runs)| {
+                let ranges = runs.into_iter().filter(|(s, e)| s <= e).collect();
+                (actor, SeqRanges { ranges })
+            })
+            .collect()
+    }
+}
+
+/// The changes key part way through moving changes out of the old single map.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StoredMigration<'a> {
+    /// The changes that have been moved to their own keys.
+    pub index: StoredIndex,
+    /// The changes still to be moved, as they were in the old map.
+    pub legacy: Cow<'a, HashMap<String, Vec<u8>>>,
+}
+
+/// What can be found in the changes key.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum StoredChanges {
+    /// The index of changes stored in their own keys.
+    Index(StoredIndex),
+    /// Changes being moved from the old map to their own keys.
+    Migrating(StoredMigration<'static>),
+    /// All of the changes in one map, as they were stored before they had their own keys.
+    Legacy(HashMap<String, Vec<u8>>),
+}
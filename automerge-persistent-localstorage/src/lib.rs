@@ -22,28 +22,52 @@
 //! # }
 //! ```
 
-use std::collections::HashMap;
+mod encoding;
+mod index;
+mod quota;
 
-use automerge::ActorId;
+use std::{borrow::Cow, collections::HashMap};
+
+use automerge::{ActorId, Automerge, Change, ReadDoc};
 use automerge_persistent::{Persister, StoredSizes};
-use base64::Engine;
+use encoding::SyncStates;
+use index::{ChangeIndex, StoredChanges, StoredIndex, StoredMigration};
+pub use quota::QuotaPolicy;
+use quota::{is_quota_error, Step};
+
+/// The number of changes moved out of the old single map before the changes key is rewritten.
+const MIGRATION_BATCH_SIZE: usize = 100;
 
 /// Persist changes and documents in to `LocalStorage`.
 ///
 /// While aimed at `LocalStorage`, it accepts any storage that  conforms to the [`web_sys::Storage`]
 /// API.
 ///
-/// Since `LocalStorage` is limited we store changes in a JSON map in one key.
+/// Each change is stored in its own key, `{changes_key}/{actor id in hex}/{sequence number}`, so
+/// inserting and removing changes only writes the keys affected. The `changes_key` itself holds an
+/// index of the stored changes, as runs of sequence numbers for each actor, which stays small as
+/// the number of changes grows. Changes stored by earlier versions as a single map in the
+/// `changes_key` are moved over to this layout by [`LocalStoragePersister::new`].
+///
+/// Documents, changes and sync states are stored in base64 behind a short header giving the
+/// version of the encoding. Values stored by earlier versions as JSON arrays of numbers are
+/// rewritten in this encoding by [`LocalStoragePersister::new`].
+///
+/// A write that fails leaves the persister as it was, so what it holds always matches storage. A
+/// [`QuotaPolicy`] can be set with [`LocalStoragePersister::with_quota_policy`] to make room when
+/// the storage quota is reached.
 #[derive(Debug)]
 pub struct LocalStoragePersister {
     storage: web_sys::Storage,
-    changes: HashMap<String, Vec<u8>>,
-    /// Base64 encoded peer_ids are used for the keys so they can be serialized to json.
-    sync_states: HashMap<String, Vec<u8>>,
+    /// Changes keyed by the actor id in hex and the sequence number.
+    changes: HashMap<(String, u64), Vec<u8>>,
+    index: ChangeIndex,
+    sync_states: SyncStates,
     document_key: String,
     changes_key: String,
     sync_states_key: String,
     sizes: StoredSizes,
+    quota_policy: QuotaPolicy,
 }
 
 /// Possible errors from persisting.
@@ -52,6 +76,26 @@ pub enum LocalStoragePersisterError {
     /// Serde failure, converting the change/document into JSON.
     #[error(transparent)]
     SerdeError(#[from] serde_json::Error),
+    /// A change stored in the old layout couldn't be loaded to find its actor and sequence
+    /// number.
+    #[error(transparent)]
+    LoadChangeError(#[from] automerge::LoadChangeError),
+    /// A stored value couldn't be base64 decoded.
+    #[error(transparent)]
+    Base64Error(#[from] base64::DecodeError),
+    /// The value stored under a key isn't in a form that can be decoded.
+    #[error("invalid value stored under {0:?}")]
+    InvalidValue(String),
+    /// The value stored under a key uses a newer version of the encoding.
+    #[error("value stored under {0:?} uses unsupported encoding version {1}")]
+    UnsupportedVersion(String, u32),
+    /// The stored document couldn't be loaded or the changes applied to it when compacting.
+    #[error(transparent)]
+    AutomergeError(#[from] automerge::AutomergeError),
+    /// Writing to a key would go over the storage quota, even after anything done by the
+    /// [`QuotaPolicy`].
+    #[error("storage quota exceeded writing {0:?}")]
+    QuotaExceeded(String),
     /// An underlying storage error.
     #[error("storage error {0:?}")]
     StorageError(wasm_bindgen::JsValue),
@@ -59,52 +103,379 @@ pub enum LocalStoragePersisterError {
 
 impl LocalStoragePersister {
     /// Construct a new `LocalStoragePersister`.
+    ///
+    /// Changes stored by earlier versions as a single map in `changes_key` are moved into their
+    /// own keys in batches. After each batch `changes_key` is rewritten with the index of the
+    /// changes moved so far and the map of those still to move, so an interrupted migration is
+    /// picked up again next time and no more than a batch is stored twice at once. Likewise any
+    /// values still stored as JSON are rewritten in the current encoding, each one replacing the
+    /// old value in place.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the storage can't be read or written, or holds data that can't be
+    /// decoded.
     pub fn new(
         storage: web_sys::Storage,
         document_key: String,
         changes_key: String,
         sync_states_key: String,
     ) -> Result<Self, LocalStoragePersisterError> {
-        let changes = if let Some(stored) = storage
+        let stored_changes = if let Some(stored) = storage
             .get_item(&changes_key)
             .map_err(LocalStoragePersisterError::StorageError)?
         {
-            serde_json::from_str(&stored)?
+            Some(serde_json::from_str::<StoredChanges>(&stored)?)
         } else {
-            HashMap::new()
+            None
         };
-        let sync_states = if let Some(stored) = storage
+        let (sync_states, legacy_sync_states) = if let Some(stored) = storage
             .get_item(&sync_states_key)
             .map_err(LocalStoragePersisterError::StorageError)?
         {
-            serde_json::from_str(&stored)?
+            encoding::decode_sync_states(&sync_states_key, &stored)?
         } else {
-            HashMap::new()
+            (HashMap::new(), false)
         };
-        let document = if let Some(doc_string) = storage
+        let (document, legacy_document) = if let Some(doc_string) = storage
             .get_item(&document_key)
             .map_err(LocalStoragePersisterError::StorageError)?
         {
-            let doc = serde_json::from_str::<Vec<u8>>(&doc_string)?;
-            Some(doc)
+            let (doc, legacy) = encoding::decode_bytes(&document_key, &doc_string)?;
+            (Some(doc), legacy)
         } else {
-            None
-        };
-        let sizes = StoredSizes {
-            changes: changes.values().map(Vec::len).sum::<usize>() as u64,
-            document: document.unwrap_or_default().len() as u64,
-            sync_states: sync_states.values().map(Vec::len).sum::<usize>() as u64,
+            (None, false)
         };
-        Ok(Self {
+        let mut persister = Self {
             storage,
-            changes,
+            changes: HashMap::new(),
+            index: ChangeIndex::new(),
             sync_states,
             document_key,
             changes_key,
             sync_states_key,
-            sizes,
+            sizes: StoredSizes::default(),
+            quota_policy: QuotaPolicy::default(),
+        };
+        match stored_changes {
+            Some(StoredChanges::Index(index)) => persister.load_changes(&index.into())?,
+            Some(StoredChanges::Migrating(migration)) => {
+                persister.load_changes(&migration.index.into())?;
+                persister.migrate_legacy_changes(migration.legacy.into_owned())?;
+            }
+            Some(StoredChanges::Legacy(changes)) => persister.migrate_legacy_changes(changes)?,
+            None => {}
+        }
+        if legacy_sync_states {
+            persister.store_sync_states(&persister.sync_states)?;
+        }
+        if let (Some(document), true) = (&document, legacy_document) {
+            persister.set_item(&persister.document_key, &encoding::encode(document))?;
+        }
+        persister.sizes = StoredSizes {
+            changes: persister.changes.values().map(Vec::len).sum::<usize>() as u64,
+            document: document.unwrap_or_default().len() as u64,
+            sync_states: persister.sync_states.values().map(Vec::len).sum::<usize>() as u64,
+        };
+        Ok(persister)
+    }
+
+    /// Read in the changes listed in `index`.
+    ///
+    /// Any that are missing are left out of the index, which is written back with the next
+    /// update, and any still stored as JSON are rewritten in the current encoding.
+    fn load_changes(&mut self, index: &ChangeIndex) -> Result<(), LocalStoragePersisterError> {
+        for (actor, seqs) in index {
+            for seq in seqs.iter() {
+                let key = self.change_key(actor, seq);
+                let stored = self
+                    .storage
+                    .get_item(&key)
+                    .map_err(LocalStoragePersisterError::StorageError)?;
+                if let Some(stored) = stored {
+                    let (change, legacy) = encoding::decode_bytes(&key, &stored)?;
+                    if legacy {
+                        self.set_item(&key, &encoding::encode(&change))?;
+                    }
+                    self.index.entry(actor.clone()).or_default().insert(seq);
+                    self.changes.insert((actor.clone(), seq), change);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Move changes stored as a single map into their own keys, a batch at a time, until the map
+    /// is replaced by the index.
+    ///
+    /// If a batch fails the keys it wrote are removed again, as the changes key still has those
+    /// changes in the map.
+    fn migrate_legacy_changes(
+        &mut self,
+        mut legacy: HashMap<String, Vec<u8>>,
+    ) -> Result<(), LocalStoragePersisterError> {
+        while !legacy.is_empty() {
+            let batch = legacy
+                .keys()
+                .take(MIGRATION_BATCH_SIZE)
+                .cloned()
+                .collect::<Vec<_>>();
+            let mut index = self.index.clone();
+            let mut moved = Vec::with_capacity(batch.len());
+            for old_key in batch {
+                let bytes = legacy.remove(&old_key).unwrap_or_default();
+                // the old keys can't be split back up, so the actor and sequence number come from
+                // the change itself
+                let change = Change::from_bytes(bytes)?;
+                let actor = change.actor_id().to_hex_string();
+                // a change that was in the map twice is only moved once
+                if index.entry(actor.clone()).or_default().insert(change.seq()) {
+                    moved.push((actor, change.seq(), change.raw_bytes().to_vec()));
+                }
+            }
+
+            let mut written = Vec::with_capacity(moved.len());
+            let mut result = Ok(());
+            for (actor, seq, bytes) in &moved {
+                let key = self.change_key(actor, *seq);
+                result = self.set_item(&key, &encoding::encode(bytes));
+                if result.is_err() {
+                    break;
+                }
+                written.push(key);
+            }
+            if result.is_ok() {
+                result = self.store_migration(&index, &legacy);
+            }
+            if let Err(e) = result {
+                for key in written {
+                    // there is nothing more to be done if this fails, the key is never read
+                    let _ = self.remove_item(&key);
+                }
+                return Err(e);
+            }
+            self.index = index;
+            for (actor, seq, bytes) in moved {
+                self.changes.insert((actor, seq), bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// Set what to do when a write would go over the storage quota, which defaults to
+    /// [`QuotaPolicy::Fail`].
+    #[must_use]
+    pub const fn with_quota_policy(mut self, quota_policy: QuotaPolicy) -> Self {
+        self.quota_policy = quota_policy;
+        self
+    }
+
+    /// What is done when a write would go over the storage quota.
+    #[must_use]
+    pub const fn quota_policy(&self) -> QuotaPolicy {
+        self.quota_policy
+    }
+
+    /// Run `op`, and if it goes over the quota make room according to the quota policy and run it
+    /// again.
+    ///
+    /// `op` must leave everything as it was when it fails. Compacting is skipped unless `compact`
+    /// is set.
+    fn with_room<F>(&mut self, compact: bool, mut op: F) -> Result<(), LocalStoragePersisterError>
+    where
+        F: FnMut(&mut Self) -> Result<(), LocalStoragePersisterError>,
+    {
+        let mut result = op(self);
+        for step in self.quota_policy.steps() {
+            if !matches!(result, Err(LocalStoragePersisterError::QuotaExceeded(_))) {
+                break;
+            }
+            match step {
+                Step::Compact if !compact => {}
+                Step::Compact => match self.compact_stored() {
+                    Ok(true) => result = op(self),
+                    Ok(false) | Err(LocalStoragePersisterError::QuotaExceeded(_)) => {}
+                    Err(e) => return Err(e),
+                },
+                Step::EvictSyncStates => {
+                    if self.evict_sync_states()? {
+                        result = op(self);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Replace the stored changes with a document covering them, returning whether there were any
+    /// changes to replace.
+    ///
+    /// Changes whose dependencies are missing are held back by the document rather than saved in
+    /// it, so these are left stored.
+    fn compact_stored(&mut self) -> Result<bool, LocalStoragePersisterError> {
+        if self.changes.is_empty() {
+            return Ok(false);
+        }
+        let mut doc = match self.get_document()? {
+            Some(base) => Automerge::load(&base)?,
+            None => Automerge::default(),
+        };
+        let changes = self
+            .changes
+            .iter()
+            .map(|(key, change)| Ok((key.clone(), Change::from_bytes(change.clone())?)))
+            .collect::<Result<Vec<_>, LocalStoragePersisterError>>()?;
+        doc.apply_changes(changes.iter().map(|(_, change)| change.clone()))?;
+        let covered = changes
+            .into_iter()
+            .filter(|(_, change)| doc.get_change_by_hash(&change.hash()).is_some())
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        if covered.is_empty() {
+            return Ok(false);
+        }
+        let document = doc.save();
+        self.set_item(&self.document_key, &encoding::encode(&document))?;
+        self.sizes.document = document.len() as u64;
+
+        // the document covers these changes now, the index goes first so that it never lists a
+        // change that has been removed
+        let mut index = self.index.clone();
+        for (actor, seq) in &covered {
+            if let Some(seqs) = index.get_mut(actor) {
+                seqs.remove(*seq);
+                if seqs.is_empty() {
+                    index.remove(actor);
+                }
+            }
+        }
+        self.store_index(&index)?;
+        self.index = index;
+        let mut keys = Vec::with_capacity(covered.len());
+        for (actor, seq) in covered {
+            keys.push(self.change_key(&actor, seq));
+            if let Some(old) = self.changes.remove(&(actor, seq)) {
+                self.sizes.changes -= old.len() as u64;
+            }
+        }
+        for key in keys {
+            self.remove_item(&key)?;
+        }
+        Ok(true)
+    }
+
+    /// Remove all of the sync states, returning whether there were any.
+    fn evict_sync_states(&mut self) -> Result<bool, LocalStoragePersisterError> {
+        if self.sync_states.is_empty() {
+            return Ok(false);
+        }
+        self.remove_item(&self.sync_states_key)?;
+        self.sync_states.clear();
+        self.sizes.sync_states = 0;
+        Ok(true)
+    }
+
+    /// Write the changes to their keys and update the index, leaving storage as it was if any
+    /// write fails.
+    ///
+    /// Only the index is updated in memory, the changes are left for the caller to add.
+    fn store_changes(
+        &mut self,
+        changes: &[(ActorId, u64, Vec<u8>)],
+    ) -> Result<(), LocalStoragePersisterError> {
+        let mut index = self.index.clone();
+        let mut new_keys = false;
+        let mut written = Vec::new();
+        for (a, s, c) in changes {
+            let actor = a.to_hex_string();
+            let key = self.change_key(&actor, *s);
+            if let Err(e) = self.set_item(&key, &encoding::encode(c)) {
+                self.undo_change_writes(written);
+                return Err(e);
+            }
+            new_keys |= index.entry(actor.clone()).or_default().insert(*s);
+            written.push((key, self.changes.get(&(actor, *s))));
+        }
+        if new_keys {
+            if let Err(e) = self.store_index(&index) {
+                self.undo_change_writes(written);
+                return Err(e);
+            }
+        }
+        self.index = index;
+        Ok(())
+    }
+
+    /// Put back what was stored under each key before it was written, as far as possible.
+    fn undo_change_writes(&self, written: Vec<(String, Option<&Vec<u8>>)>) {
+        for (key, old) in written.into_iter().rev() {
+            // there is nothing more to be done if these fail, the index doesn't list new keys and
+            // a change replaced under the same key has the same contents
+            let _ = old.map_or_else(
+                || self.remove_item(&key),
+                |old| self.set_item(&key, &encoding::encode(old)),
+            );
+        }
+    }
+
+    /// The key a change is stored under.
+    fn change_key(&self, actor: &str, seq: u64) -> String {
+        format!("{}/{actor}/{seq}", self.changes_key)
+    }
+
+    fn store_index(&self, index: &ChangeIndex) -> Result<(), LocalStoragePersisterError> {
+        if index.is_empty() {
+            return self.remove_item(&self.changes_key);
+        }
+        let index = serde_json::to_string(&StoredIndex::from(index))?;
+        self.set_item(&self.changes_key, &index)
+    }
+
+    /// Store the index along with the changes still to be moved out of the old map, or just the
+    /// index once there are none left.
+    fn store_migration(
+        &self,
+        index: &ChangeIndex,
+        legacy: &HashMap<String, Vec<u8>>,
+    ) -> Result<(), LocalStoragePersisterError> {
+        if legacy.is_empty() {
+            return self.store_index(index);
+        }
+        let migration = serde_json::to_string(&StoredMigration {
+            index: StoredIndex::from(index),
+            legacy: Cow::Borrowed(legacy),
+        })?;
+        self.set_item(&self.changes_key, &migration)
+    }
+
+    fn store_sync_states(
+        &self,
+        sync_states: &SyncStates,
+    ) -> Result<(), LocalStoragePersisterError> {
+        if sync_states.is_empty() {
+            return self.remove_item(&self.sync_states_key);
+        }
+        self.set_item(
+            &self.sync_states_key,
+            &encoding::encode_sync_states(sync_states),
+        )
+    }
+
+    fn set_item(&self, key: &str, value: &str) -> Result<(), LocalStoragePersisterError> {
+        self.storage.set_item(key, value).map_err(|e| {
+            if is_quota_error(&e) {
+                LocalStoragePersisterError::QuotaExceeded(key.to_owned())
+            } else {
+                LocalStoragePersisterError::StorageError(e)
+            }
         })
     }
+
+    fn remove_item(&self, key: &str) -> Result<(), LocalStoragePersisterError> {
+        self.storage
+            .remove_item(key)
+            .map_err(LocalStoragePersisterError::StorageError)
+    }
 }
 
 impl Persister for LocalStoragePersister {
@@ -115,35 +486,46 @@ impl Persister for LocalStoragePersister {
     }
 
     fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        self.with_room(true, |this| this.store_changes(&changes))?;
         for (a, s, c) in changes {
-            let key = make_key(&a, s);
-
             self.sizes.changes += c.len() as u64;
-            if let Some(old) = self.changes.insert(key, c) {
+            if let Some(old) = self.changes.insert((a.to_hex_string(), s), c) {
                 self.sizes.changes -= old.len() as u64;
             }
         }
-        self.storage
-            .set_item(&self.changes_key, &serde_json::to_string(&self.changes)?)
-            .map_err(LocalStoragePersisterError::StorageError)?;
         Ok(())
     }
 
     fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
-        let mut some_removal = false;
+        let mut index = self.index.clone();
+        let mut removed = Vec::new();
         for (a, s) in changes {
-            let key = make_key(a, s);
-            if let Some(old) = self.changes.remove(&key) {
-                self.sizes.changes -= old.len() as u64;
-                some_removal = true;
+            let actor = a.to_hex_string();
+            if let Some(seqs) = index.get_mut(&actor) {
+                if seqs.remove(s) {
+                    if seqs.is_empty() {
+                        index.remove(&actor);
+                    }
+                    removed.push((actor, s));
+                }
             }
         }
+        if removed.is_empty() {
+            return Ok(());
+        }
 
-        if some_removal {
-            let s = serde_json::to_string(&self.changes)?;
-            self.storage
-                .set_item(&self.changes_key, &s)
-                .map_err(LocalStoragePersisterError::StorageError)?;
+        // the index goes first so that it never lists a change that has been removed
+        self.store_index(&index)?;
+        self.index = index;
+        let mut keys = Vec::with_capacity(removed.len());
+        for (actor, s) in removed {
+            keys.push(self.change_key(&actor, s));
+            if let Some(old) = self.changes.remove(&(actor, s)) {
+                self.sizes.changes -= old.len() as u64;
+            }
+        }
+        for key in keys {
+            self.remove_item(&key)?;
         }
         Ok(())
     }
@@ -154,7 +536,7 @@ impl Persister for LocalStoragePersister {
             .get_item(&self.document_key)
             .map_err(LocalStoragePersisterError::StorageError)?
         {
-            let doc = serde_json::from_str(&doc_string)?;
+            let (doc, _) = encoding::decode_bytes(&self.document_key, &doc_string)?;
             Ok(Some(doc))
         } else {
             Ok(None)
@@ -162,60 +544,56 @@ impl Persister for LocalStoragePersister {
     }
 
     fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
-        self.sizes.document = data.len() as u64;
-        let data = serde_json::to_string(&data)?;
-        self.storage
-            .set_item(&self.document_key, &data)
-            .map_err(LocalStoragePersisterError::StorageError)?;
-        Ok(())
+        // compacting would write a document holding this one, which can only need more room
+        self.with_room(false, |this| {
+            this.set_item(&this.document_key, &encoding::encode(&data))?;
+            this.sizes.document = data.len() as u64;
+            Ok(())
+        })
     }
 
     fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
-        let peer_id = base64::engine::general_purpose::STANDARD.encode(peer_id);
-        Ok(self.sync_states.get(&peer_id).cloned())
+        Ok(self.sync_states.get(peer_id).cloned())
     }
 
     fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
-        self.sizes.sync_states += sync_state.len() as u64;
-        let peer_id = base64::engine::general_purpose::STANDARD.encode(peer_id);
-        if let Some(old) = self.sync_states.insert(peer_id, sync_state) {
-            self.sizes.sync_states -= old.len() as u64;
-        }
-        self.storage
-            .set_item(
-                &self.sync_states_key,
-                &serde_json::to_string(&self.sync_states)?,
-            )
-            .map_err(LocalStoragePersisterError::StorageError)?;
-        Ok(())
+        self.with_room(true, |this| {
+            let old = this.sync_states.insert(peer_id.clone(), sync_state.clone());
+            if let Err(e) = this.store_sync_states(&this.sync_states) {
+                match old {
+                    Some(old) => this.sync_states.insert(peer_id.clone(), old),
+                    None => this.sync_states.remove(&peer_id),
+                };
+                return Err(e);
+            }
+            this.sizes.sync_states += sync_state.len() as u64;
+            this.sizes.sync_states -= old.map_or(0, |old| old.len() as u64);
+            Ok(())
+        })
     }
 
     fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let mut removed = Vec::new();
         for peer_id in peer_ids {
-            let peer_id = base64::engine::general_purpose::STANDARD.encode(peer_id);
-            if let Some(old) = self.sync_states.remove(&peer_id) {
-                self.sizes.sync_states -= old.len() as u64;
+            if let Some(old) = self.sync_states.remove(*peer_id) {
+                removed.push((peer_id.to_vec(), old));
             }
         }
-        self.storage
-            .set_item(
-                &self.sync_states_key,
-                &serde_json::to_string(&self.sync_states)?,
-            )
-            .map_err(LocalStoragePersisterError::StorageError)?;
+        if removed.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = self.store_sync_states(&self.sync_states) {
+            self.sync_states.extend(removed);
+            return Err(e);
+        }
+        for (_, old) in removed {
+            self.sizes.sync_states -= old.len() as u64;
+        }
         Ok(())
     }
 
     fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
-        Ok(self
-            .sync_states
-            .keys()
-            .map(|key| {
-                base64::engine::general_purpose::STANDARD
-                    .decode(key)
-                    .expect("Failed to base64 decode they peer_id")
-            })
-            .collect())
+        Ok(self.sync_states.keys().cloned().collect())
     }
 
     fn sizes(&self) -> StoredSizes {
@@ -225,13 +603,16 @@ impl Persister for LocalStoragePersister {
     fn flush(&mut self) -> Result<usize, Self::Error> {
         Ok(0)
     }
-}
 
-/// Make a key from the `actor_id` and `sequence_number`.
-///
-/// Converts the `actor_id` to a string and appends the `sequence_number`.
-fn make_key(actor_id: &ActorId, seq: u64) -> String {
-    let mut key = actor_id.to_hex_string();
-    key.push_str(&seq.to_string());
-    key
+    /// Removes the old sync states first, so the room they take up is free for the document.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        self.remove_sync_states(old_peer_ids)?;
+        self.set_document(document)?;
+        self.remove_changes(changes)
+    }
 }
@@ -0,0 +1,59 @@
+//! Handling writes that go over the storage quota.
+
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::DomException;
+
+/// What a [`LocalStoragePersister`](crate::LocalStoragePersister) does when a write would go over
+/// the storage quota.
+///
+/// Whatever the policy, a write that still doesn't fit returns
+/// [`LocalStoragePersisterError::QuotaExceeded`](crate::LocalStoragePersisterError::QuotaExceeded)
+/// and leaves the persister as it was before the write.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    /// Fail the write straight away.
+    #[default]
+    Fail,
+    /// Compact the stored changes into the stored document, then try the write again.
+    ///
+    /// This isn't done when setting the document, as the compacted document would hold the new
+    /// one and so need at least as much room.
+    Compact,
+    /// Remove all of the stored sync states, then try the write again.
+    ///
+    /// Sync states only save work in the next sync with each peer, so losing them costs some
+    /// extra messages rather than any data.
+    EvictSyncStates,
+    /// Compact and try the write again, then if it still doesn't fit remove the sync states and
+    /// try once more.
+    CompactThenEvictSyncStates,
+}
+
+/// A way of making room in storage.
+#[derive(Debug, Clone, Copy)]
+pub enum Step {
+    Compact,
+    EvictSyncStates,
+}
+
+impl QuotaPolicy {
+    /// The ways to make room, in the order they are tried.
+    pub(crate) const fn steps(self) -> &'static [Step] {
+        match self {
+            Self::Fail => &[],
+            Self::Compact => &[Step::Compact],
+            Self::EvictSyncStates => &[Step::EvictSyncStates],
+            Self::CompactThenEvictSyncStates => &[Step::Compact, Step::EvictSyncStates],
+        }
+    }
+}
+
+/// Whether `error`, from writing to storage, is because the quota was exceeded.
+pub fn is_quota_error(error: &JsValue) -> bool {
+    error.dyn_ref::<DomException>().is_some_and(|e| {
+        e.code() == DomException::QUOTA_EXCEEDED_ERR
+            || e.name() == "QuotaExceededError"
+            // older versions of Firefox
+            || e.name() == "NS_ERROR_DOM_QUOTA_REACHED"
+    })
+}
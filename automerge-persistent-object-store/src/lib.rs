@@ -0,0 +1,728 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting object storage, such as S3, through
+//! [object_store](https://docs.rs/object_store).
+//!
+//! Any store from `object_store` can be used, so the same code can run against S3, GCS or Azure
+//! in production and against the in-memory or local filesystem stores in tests. Only the
+//! [`AsyncPersister`] interface is provided as object stores are driven asynchronously.
+//!
+//! # Single persister
+//!
+//! ```rust
+//! # use std::sync::Arc;
+//! # use automerge_persistent::AsyncPersister;
+//! # use automerge_persistent_object_store::{ObjectStorePersister, ObjectStorePersisterError};
+//! # use object_store::memory::InMemory;
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> Result<(), ObjectStorePersisterError> {
+//! let store = Arc::new(InMemory::new());
+//!
+//! let mut persister = ObjectStorePersister::new(store, "documents/1").await?;
+//! persister.set_document(vec![1, 2, 3]).await?;
+//! assert_eq!(persister.get_document().await?, Some(vec![1, 2, 3]));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Multiple persisters sharing the same store
+//!
+//! Each persister has its own prefix, and persisters on other machines can use the same prefix to
+//! share a document.
+//!
+//! ```rust
+//! # use std::sync::Arc;
+//! # use automerge_persistent::AsyncPersister;
+//! # use automerge_persistent_object_store::{ObjectStorePersister, ObjectStorePersisterError};
+//! # use object_store::local::LocalFileSystem;
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> Result<(), ObjectStorePersisterError> {
+//! # let dir = tempfile::tempdir().unwrap();
+//! let store = Arc::new(LocalFileSystem::new_with_prefix(dir.path())?);
+//!
+//! let mut persister1 = ObjectStorePersister::new(store.clone(), "documents/1").await?;
+//! persister1.set_document(vec![1]).await?;
+//!
+//! let persister2 = ObjectStorePersister::new(store.clone(), "documents/2").await?;
+//! assert_eq!(persister2.get_document().await?, None);
+//!
+//! let other = ObjectStorePersister::new(store, "documents/1").await?;
+//! assert_eq!(other.get_document().await?, Some(vec![1]));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use automerge::ActorId;
+use automerge_persistent::{AsyncPersister, StoredCounts, StoredSizes};
+use futures::{StreamExt, TryStreamExt};
+use object_store::{path::Path, ObjectMeta, ObjectStore, PutMode, PutPayload};
+
+/// The maximum number of requests made to the store at once.
+const CONCURRENT_REQUESTS: usize = 16;
+
+/// The persister that stores changes, documents and sync states as objects under a prefix.
+///
+/// The objects are laid out as:
+///
+/// - `{prefix}/changes/{actor id in hex}/{sequence number}`
+/// - `{prefix}/documents/{generation}`
+/// - `{prefix}/sync_states/{peer id in hex}`
+///
+/// A prefix shouldn't be nested within the objects of another prefix, for example `a` and
+/// `a/changes`, as listing one would then pick up the other.
+///
+/// Object stores can't replace several objects at once, so each document is written as a new
+/// generation, numbered one after the generation the persister last read or wrote. The put only
+/// succeeds if no object exists at that key, so when two persisters write a document based on the
+/// same generation one of them fails with [`ObjectStorePersisterError::Conflict`] rather than
+/// losing the changes of the other. Compaction only removes changes once its document has been
+/// written, and older generations are removed last, so readers always find a complete document
+/// and the changes that follow it. After a conflict, read the document again with
+/// [`AsyncPersister::get_document`], merge it into the live document and retry.
+///
+/// Sizes and counts are worked out by listing when the persister is created and kept up to date
+/// with what it writes. Writes from other persisters on the same prefix aren't seen until
+/// [`ObjectStorePersister::verify_sizes`] is called.
+///
+/// ```rust
+/// # use std::sync::Arc;
+/// # use automerge_persistent::AsyncPersister;
+/// # use automerge_persistent_object_store::{ObjectStorePersister, ObjectStorePersisterError};
+/// # use object_store::memory::InMemory;
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), ObjectStorePersisterError> {
+/// let store = Arc::new(InMemory::new());
+/// let mut server1 = ObjectStorePersister::new(store.clone(), "doc").await?;
+/// let mut server2 = ObjectStorePersister::new(store, "doc").await?;
+///
+/// server1.compact(vec![1], Vec::new(), &[]).await?;
+/// assert!(matches!(
+///     server2.compact(vec![2], Vec::new(), &[]).await,
+///     Err(ObjectStorePersisterError::Conflict)
+/// ));
+///
+/// // catch up with the document from the other server before trying again
+/// assert_eq!(server2.get_document().await?, Some(vec![1]));
+/// server2.compact(vec![1, 2], Vec::new(), &[]).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ObjectStorePersister {
+    store: Arc<dyn ObjectStore>,
+    prefix: Path,
+    /// The generation of the document last read or written, 0 if there hasn't been one.
+    generation: AtomicU64,
+    sizes: StoredSizes,
+    counts: StoredCounts,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum ObjectStorePersisterError {
+    /// Internal errors from the object store.
+    #[error(transparent)]
+    ObjectStoreError(#[from] object_store::Error),
+    /// Another persister wrote a document since this one last read or wrote one.
+    #[error("the document was replaced by another writer, it needs reading again before writing")]
+    Conflict,
+    /// An object under the prefix has a key that this persister didn't write.
+    #[error("unexpected object {0}")]
+    InvalidKey(String),
+}
+
+impl ObjectStorePersister {
+    /// Construct a new persister for the objects under `prefix` in `store`.
+    ///
+    /// This lists the objects under the prefix to work out the sizes and the current document
+    /// generation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if listing the objects fails, or if an object has an unexpected key.
+    pub async fn new(
+        store: Arc<dyn ObjectStore>,
+        prefix: &str,
+    ) -> Result<Self, ObjectStorePersisterError> {
+        let mut persister = Self {
+            store,
+            prefix: Path::from(prefix),
+            generation: AtomicU64::new(0),
+            sizes: StoredSizes::default(),
+            counts: StoredCounts::default(),
+        };
+        let generation = persister
+            .latest_document()
+            .await?
+            .map_or(0, |(generation, _)| generation);
+        *persister.generation.get_mut() = generation;
+        persister.verify_sizes().await?;
+        Ok(persister)
+    }
+
+    /// The prefix the objects of this persister are stored under.
+    #[must_use]
+    pub const fn prefix(&self) -> &Path {
+        &self.prefix
+    }
+
+    /// The number of changes and sync states stored.
+    #[must_use]
+    pub fn counts(&self) -> StoredCounts {
+        self.counts.clone()
+    }
+
+    /// Work out the sizes and counts from what is stored, correcting them if they were wrong.
+    ///
+    /// This is for when other persisters may have written to the same prefix. Returns whether
+    /// they were already right.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if listing the objects fails, or if an object has an unexpected key.
+    pub async fn verify_sizes(&mut self) -> Result<bool, ObjectStorePersisterError> {
+        let mut sizes = StoredSizes::default();
+        let mut counts = StoredCounts::default();
+        for meta in self.list(&self.changes_dir()).await? {
+            sizes.changes += meta.size as u64;
+            counts.changes += 1;
+        }
+        for meta in self.list(&self.sync_states_dir()).await? {
+            sizes.sync_states += meta.size as u64;
+            counts.sync_states += 1;
+        }
+        if let Some((_, meta)) = self.latest_document().await? {
+            sizes.document = meta.size as u64;
+        }
+        if sizes == self.sizes && counts == self.counts {
+            return Ok(true);
+        }
+        self.sizes = sizes;
+        self.counts = counts;
+        Ok(false)
+    }
+
+    fn changes_dir(&self) -> Path {
+        self.prefix.child("changes")
+    }
+
+    fn change_path(&self, actor_id: &ActorId, seq: u64) -> Path {
+        self.changes_dir()
+            .child(actor_id.to_hex_string())
+            .child(seq.to_string())
+    }
+
+    fn documents_dir(&self) -> Path {
+        self.prefix.child("documents")
+    }
+
+    fn document_path(&self, generation: u64) -> Path {
+        // padded so that the generations list in order
+        self.documents_dir().child(format!("{generation:020}"))
+    }
+
+    fn sync_states_dir(&self) -> Path {
+        self.prefix.child("sync_states")
+    }
+
+    fn sync_state_path(&self, peer_id: &[u8]) -> Path {
+        self.sync_states_dir().child(hex::encode(peer_id))
+    }
+
+    async fn list(&self, dir: &Path) -> Result<Vec<ObjectMeta>, ObjectStorePersisterError> {
+        Ok(self.store.list(Some(dir)).try_collect().await?)
+    }
+
+    /// The newest generation of the document, along with its object.
+    async fn latest_document(
+        &self,
+    ) -> Result<Option<(u64, ObjectMeta)>, ObjectStorePersisterError> {
+        let mut latest: Option<(u64, ObjectMeta)> = None;
+        for meta in self.list(&self.documents_dir()).await? {
+            let generation = meta
+                .location
+                .filename()
+                .and_then(|name| name.parse::<u64>().ok())
+                .ok_or_else(|| ObjectStorePersisterError::InvalidKey(meta.location.to_string()))?;
+            if !matches!(&latest, Some((g, _)) if *g >= generation) {
+                latest = Some((generation, meta));
+            }
+        }
+        Ok(latest)
+    }
+
+    /// Read an object, treating a missing object as not being there.
+    async fn get_optional(
+        &self,
+        path: &Path,
+    ) -> Result<Option<Vec<u8>>, ObjectStorePersisterError> {
+        match self.store.get(path).await {
+            Ok(result) => Ok(Some(result.bytes().await?.to_vec())),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The size of an object, if there is one.
+    async fn object_len(&self, path: &Path) -> Result<Option<u64>, ObjectStorePersisterError> {
+        match self.store.head(path).await {
+            Ok(meta) => Ok(Some(meta.size as u64)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove an object if it exists, returning its size.
+    async fn remove_object(&self, path: &Path) -> Result<Option<u64>, ObjectStorePersisterError> {
+        let Some(len) = self.object_len(path).await? else {
+            return Ok(None);
+        };
+        match self.store.delete(path).await {
+            Ok(()) => Ok(Some(len)),
+            // removed by another persister in the meantime
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remove the objects at `paths`, returning the sizes of those that existed.
+    async fn remove_objects(
+        &self,
+        paths: Vec<Path>,
+    ) -> Result<Vec<u64>, ObjectStorePersisterError> {
+        let removed: Vec<Option<u64>> = futures::stream::iter(paths)
+            .map(|path| async move { self.remove_object(&path).await })
+            .buffer_unordered(CONCURRENT_REQUESTS)
+            .try_collect()
+            .await?;
+        Ok(removed.into_iter().flatten().collect())
+    }
+
+    /// Write `data` as the next generation of the document, returning the generation.
+    async fn write_document(&mut self, data: Vec<u8>) -> Result<u64, ObjectStorePersisterError> {
+        let generation = *self.generation.get_mut() + 1;
+        let path = self.document_path(generation);
+        let len = data.len() as u64;
+        match self
+            .store
+            .put_opts(&path, PutPayload::from(data), PutMode::Create.into())
+            .await
+        {
+            Ok(_) => {}
+            Err(object_store::Error::AlreadyExists { .. }) => {
+                return Err(ObjectStorePersisterError::Conflict)
+            }
+            Err(e) => return Err(e.into()),
+        }
+        // a generation can be written again once it has been removed, so a later one may already
+        // have replaced it
+        if let Some((latest, _)) = self.latest_document().await? {
+            if latest > generation {
+                self.remove_object(&path).await?;
+                return Err(ObjectStorePersisterError::Conflict);
+            }
+        }
+        *self.generation.get_mut() = generation;
+        self.sizes.document = len;
+        Ok(generation)
+    }
+
+    /// Remove the generations of the document before `generation`.
+    async fn remove_old_documents(&self, generation: u64) -> Result<(), ObjectStorePersisterError> {
+        let old = (1..generation)
+            .map(|g| self.document_path(g))
+            .collect::<std::collections::HashSet<_>>();
+        let paths = self
+            .list(&self.documents_dir())
+            .await?
+            .into_iter()
+            .map(|meta| meta.location)
+            .filter(|path| old.contains(path))
+            .collect();
+        self.remove_objects(paths).await?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl AsyncPersister for ObjectStorePersister {
+    type Error = ObjectStorePersisterError;
+
+    async fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let paths = self
+            .list(&self.changes_dir())
+            .await?
+            .into_iter()
+            .map(|meta| meta.location)
+            .collect::<Vec<_>>();
+        let changes: Vec<Option<Vec<u8>>> = futures::stream::iter(paths)
+            .map(|path| async move { self.get_optional(&path).await })
+            .buffer_unordered(CONCURRENT_REQUESTS)
+            .try_collect()
+            .await?;
+        // changes removed since listing were compacted into the document
+        Ok(changes.into_iter().flatten().collect())
+    }
+
+    async fn insert_changes(
+        &mut self,
+        changes: Vec<(ActorId, u64, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        let this = &*self;
+        let inserted: Vec<Option<u64>> = futures::stream::iter(changes)
+            .map(|(a, s, c)| async move {
+                let len = c.len() as u64;
+                let put = this
+                    .store
+                    .put_opts(
+                        &this.change_path(&a, s),
+                        PutPayload::from(c),
+                        PutMode::Create.into(),
+                    )
+                    .await;
+                match put {
+                    Ok(_) => Ok(Some(len)),
+                    // a change is the same wherever it comes from, so the stored one is kept
+                    Err(object_store::Error::AlreadyExists { .. }) => Ok(None),
+                    Err(e) => Err(ObjectStorePersisterError::from(e)),
+                }
+            })
+            .buffer_unordered(CONCURRENT_REQUESTS)
+            .try_collect()
+            .await?;
+        for len in inserted.into_iter().flatten() {
+            self.sizes.changes += len;
+            self.counts.changes += 1;
+        }
+        Ok(())
+    }
+
+    async fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let paths = changes
+            .into_iter()
+            .map(|(a, s)| self.change_path(a, s))
+            .collect();
+        for len in self.remove_objects(paths).await? {
+            self.sizes.changes = self.sizes.changes.saturating_sub(len);
+            self.counts.changes = self.counts.changes.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    async fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        loop {
+            let Some((generation, meta)) = self.latest_document().await? else {
+                return Ok(None);
+            };
+            // a newer generation may replace this one between listing and reading it
+            if let Some(document) = self.get_optional(&meta.location).await? {
+                self.generation.store(generation, Ordering::Release);
+                return Ok(Some(document));
+            }
+        }
+    }
+
+    async fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let generation = self.write_document(data).await?;
+        self.remove_old_documents(generation).await
+    }
+
+    async fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get_optional(&self.sync_state_path(peer_id)).await
+    }
+
+    async fn set_sync_state(
+        &mut self,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        let path = self.sync_state_path(&peer_id);
+        let replaced = self.object_len(&path).await?;
+        let len = sync_state.len() as u64;
+        self.store.put(&path, PutPayload::from(sync_state)).await?;
+        if replaced.is_none() {
+            self.counts.sync_states += 1;
+        }
+        self.sizes.sync_states += len;
+        self.sizes.sync_states = self
+            .sizes
+            .sync_states
+            .saturating_sub(replaced.unwrap_or_default());
+        Ok(())
+    }
+
+    async fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let paths = peer_ids
+            .iter()
+            .map(|peer_id| self.sync_state_path(peer_id))
+            .collect();
+        for len in self.remove_objects(paths).await? {
+            self.sizes.sync_states = self.sizes.sync_states.saturating_sub(len);
+            self.counts.sync_states = self.counts.sync_states.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    async fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        self.list(&self.sync_states_dir())
+            .await?
+            .into_iter()
+            .map(|meta| {
+                meta.location
+                    .filename()
+                    .and_then(|name| hex::decode(name).ok())
+                    .ok_or_else(|| ObjectStorePersisterError::InvalidKey(meta.location.to_string()))
+            })
+            .collect()
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    async fn flush(&mut self) -> Result<usize, Self::Error> {
+        // every write goes straight to the store
+        Ok(0)
+    }
+
+    /// Writes the document as a new generation first, failing with
+    /// [`ObjectStorePersisterError::Conflict`] if another persister got there first, and only
+    /// then removes the changes, sync states and older generations.
+    async fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let generation = self.write_document(document).await?;
+        self.remove_changes(changes).await?;
+        self.remove_sync_states(old_peer_ids).await?;
+        self.remove_old_documents(generation).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use automerge::ActorId;
+    use automerge_persistent::{AsyncPersister, StoredCounts, StoredSizes};
+    use futures::TryStreamExt;
+    use object_store::{local::LocalFileSystem, memory::InMemory, path::Path, ObjectStore};
+
+    use crate::ObjectStorePersister;
+
+    /// Run `test` against an in-memory store and a local filesystem store.
+    async fn with_stores<F, Fut>(test: F)
+    where
+        F: Fn(Arc<dyn ObjectStore>) -> Fut,
+        Fut: std::future::Future<Output = ()>,
+    {
+        test(Arc::new(InMemory::new())).await;
+        let dir = tempfile::tempdir().unwrap();
+        test(Arc::new(
+            LocalFileSystem::new_with_prefix(dir.path()).unwrap(),
+        ))
+        .await;
+    }
+
+    async fn keys(store: &dyn ObjectStore, dir: &str) -> Vec<String> {
+        let mut keys = store
+            .list(Some(&Path::from(dir)))
+            .map_ok(|meta| meta.location.to_string())
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        keys.sort();
+        keys
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn changes_and_sync_states() {
+        with_stores(|store| async move {
+            let mut persister = ObjectStorePersister::new(store.clone(), "doc")
+                .await
+                .unwrap();
+            let actor = ActorId::random();
+
+            persister
+                .insert_changes(vec![
+                    (actor.clone(), 1, vec![1]),
+                    (actor.clone(), 2, vec![2, 2]),
+                ])
+                .await
+                .unwrap();
+            // an existing change is left as it is
+            persister
+                .insert_changes(vec![(actor.clone(), 1, vec![1])])
+                .await
+                .unwrap();
+            let mut changes = persister.get_changes().await.unwrap();
+            changes.sort();
+            assert_eq!(changes, vec![vec![1], vec![2, 2]]);
+
+            persister.set_sync_state(vec![1], vec![1]).await.unwrap();
+            persister.set_sync_state(vec![1], vec![3; 3]).await.unwrap();
+            assert_eq!(
+                persister.get_sync_state(&[1]).await.unwrap(),
+                Some(vec![3; 3])
+            );
+            assert_eq!(persister.get_sync_state(&[2]).await.unwrap(), None);
+
+            assert_eq!(
+                persister.sizes(),
+                StoredSizes {
+                    changes: 3,
+                    document: 0,
+                    sync_states: 3,
+                }
+            );
+            assert_eq!(
+                persister.counts(),
+                StoredCounts {
+                    changes: 2,
+                    sync_states: 1,
+                }
+            );
+
+            persister.remove_changes(vec![(&actor, 1)]).await.unwrap();
+            persister.remove_sync_states(&[&[1], &[2]]).await.unwrap();
+            assert_eq!(persister.get_changes().await.unwrap(), vec![vec![2, 2]]);
+            assert_eq!(persister.get_sync_state(&[1]).await.unwrap(), None);
+            assert_eq!(
+                persister.counts(),
+                StoredCounts {
+                    changes: 1,
+                    sync_states: 0,
+                }
+            );
+            assert!(persister.verify_sizes().await.unwrap());
+        })
+        .await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn compaction_removes_changes_and_old_generations() {
+        with_stores(|store| async move {
+            let mut persister = ObjectStorePersister::new(store.clone(), "doc")
+                .await
+                .unwrap();
+            let actor = ActorId::random();
+
+            persister.set_document(vec![1]).await.unwrap();
+            persister
+                .insert_changes(vec![
+                    (actor.clone(), 1, vec![1]),
+                    (actor.clone(), 2, vec![2]),
+                ])
+                .await
+                .unwrap();
+            persister.set_sync_state(vec![1], vec![1]).await.unwrap();
+            persister
+                .compact(vec![2, 2], vec![(&actor, 1)], &[&[1]])
+                .await
+                .unwrap();
+
+            assert_eq!(persister.get_document().await.unwrap(), Some(vec![2, 2]));
+            assert_eq!(persister.get_changes().await.unwrap(), vec![vec![2]]);
+            assert_eq!(
+                persister.get_peer_ids().await.unwrap(),
+                Vec::<Vec<u8>>::new()
+            );
+            assert_eq!(
+                keys(&*store, "doc/documents").await,
+                vec!["doc/documents/00000000000000000002".to_owned()]
+            );
+            assert_eq!(
+                persister.sizes(),
+                StoredSizes {
+                    changes: 1,
+                    document: 2,
+                    sync_states: 0,
+                }
+            );
+            assert!(persister.verify_sizes().await.unwrap());
+
+            // a new persister carries on from the latest generation
+            let mut other = ObjectStorePersister::new(store.clone(), "doc")
+                .await
+                .unwrap();
+            other.set_document(vec![3]).await.unwrap();
+            assert_eq!(
+                keys(&*store, "doc/documents").await,
+                vec!["doc/documents/00000000000000000003".to_owned()]
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn peer_ids_round_trip_through_hex() {
+        with_stores(|store| async move {
+            let mut persister = ObjectStorePersister::new(store.clone(), "doc")
+                .await
+                .unwrap();
+            let peer_ids = vec![vec![0x00, 0xff], vec![0xab; 32], b"peer/1".to_vec()];
+            for peer_id in &peer_ids {
+                persister
+                    .set_sync_state(peer_id.clone(), vec![1])
+                    .await
+                    .unwrap();
+            }
+
+            let mut stored = persister.get_peer_ids().await.unwrap();
+            stored.sort();
+            let mut expected = peer_ids.clone();
+            expected.sort();
+            assert_eq!(stored, expected);
+            for peer_id in &peer_ids {
+                assert_eq!(
+                    persister.get_sync_state(peer_id).await.unwrap(),
+                    Some(vec![1])
+                );
+            }
+        })
+        .await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn verify_sizes_sees_other_writers() {
+        with_stores(|store| async move {
+            let mut first = ObjectStorePersister::new(store.clone(), "doc")
+                .await
+                .unwrap();
+            let mut second = ObjectStorePersister::new(store.clone(), "doc")
+                .await
+                .unwrap();
+            // a persister under another prefix doesn't count
+            let mut elsewhere = ObjectStorePersister::new(store.clone(), "other")
+                .await
+                .unwrap();
+            let actor = ActorId::random();
+
+            second
+                .insert_changes(vec![(actor.clone(), 1, vec![1, 1])])
+                .await
+                .unwrap();
+            second.set_sync_state(vec![1], vec![1]).await.unwrap();
+            second.set_document(vec![3; 3]).await.unwrap();
+            elsewhere.set_document(vec![9; 9]).await.unwrap();
+
+            assert_eq!(first.sizes(), StoredSizes::default());
+            assert!(!first.verify_sizes().await.unwrap());
+            assert_eq!(first.sizes(), second.sizes());
+            assert_eq!(first.counts(), second.counts());
+            assert!(first.verify_sizes().await.unwrap());
+        })
+        .await;
+    }
+}
@@ -0,0 +1,485 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [redb](https://github.com/cberner/redb).
+//!
+//! # Single persister
+//!
+//! ```rust
+//! # use std::sync::Arc;
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_redb::RedbPersister;
+//! # use automerge_persistent_redb::RedbPersisterError;
+//! # fn main() -> Result<(), RedbPersisterError> {
+//! # let dir = tempfile::tempdir().unwrap();
+//! let db = Arc::new(redb::Database::create(dir.path().join("documents.redb"))?);
+//!
+//! let persister = RedbPersister::new(db, "")?;
+//! let doc = PersistentAutomerge::load(persister);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Multiple persisters sharing the same database
+//!
+//! ```rust
+//! # use std::sync::Arc;
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_redb::RedbPersister;
+//! # use automerge_persistent_redb::RedbPersisterError;
+//! # fn main() -> Result<(), RedbPersisterError> {
+//! # let dir = tempfile::tempdir().unwrap();
+//! let db = Arc::new(redb::Database::create(dir.path().join("documents.redb"))?);
+//!
+//! let persister1 = RedbPersister::new(db.clone(), "1")?;
+//! let doc1 = PersistentAutomerge::load(persister1);
+//!
+//! let persister2 = RedbPersister::new(db, "2")?;
+//! let doc2 = PersistentAutomerge::load(persister2);
+//! # Ok(())
+//! # }
+//! ```
+
+#[cfg(feature = "sled")]
+mod migrate;
+
+use std::{fmt, sync::Arc};
+
+use automerge::ActorId;
+use automerge_persistent::{namespace, Persister, StoredCounts, StoredSizes};
+use redb::{
+    CommitError, Database, DatabaseError, ReadableTable, StorageError, Table, TableDefinition,
+    TableError, TransactionError, WriteTransaction,
+};
+
+/// The persister that stores changes, documents and sync states in redb tables.
+///
+/// Keys start with the length of the prefix followed by the prefix itself, so any number of
+/// persisters can share a database, each with its own prefix, without seeing each other's data.
+///
+/// Every write happens in a single write transaction that also updates the sizes and counts
+/// recorded for the prefix, so they are always accurate, even with other persisters writing to
+/// the same prefix. [`Persister::sizes`] returns them as of the last write or load through this
+/// persister.
+///
+/// Writes are durable once they return, so [`Persister::flush`] has nothing to do.
+pub struct RedbPersister {
+    db: Arc<Database>,
+    /// The start of every key belonging to this persister, see [`namespace`].
+    namespace: Vec<u8>,
+    sizes: StoredSizes,
+    counts: StoredCounts,
+}
+
+impl fmt::Debug for RedbPersister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedbPersister")
+            .field("namespace", &self.namespace)
+            .field("sizes", &self.sizes)
+            .field("counts", &self.counts)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum RedbPersisterError {
+    /// Internal errors from redb.
+    #[error(transparent)]
+    RedbError(#[from] redb::Error),
+    /// Errors from the sled trees being migrated from.
+    #[cfg(feature = "sled")]
+    #[error(transparent)]
+    SledError(#[from] automerge_persistent_sled::SledPersisterError),
+    /// A change being migrated couldn't be loaded.
+    #[cfg(feature = "sled")]
+    #[error(transparent)]
+    LoadChangeError(#[from] automerge::LoadChangeError),
+    /// A prefix being migrated to already has data stored under it.
+    #[cfg(feature = "sled")]
+    #[error("prefix {0:?} is already in use")]
+    PrefixExists(String),
+}
+
+macro_rules! from_redb_error {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for RedbPersisterError {
+                fn from(error: $error) -> Self {
+                    Self::RedbError(error.into())
+                }
+            }
+        )*
+    };
+}
+
+from_redb_error!(
+    CommitError,
+    DatabaseError,
+    StorageError,
+    TableError,
+    TransactionError
+);
+
+const CHANGES: TableDefinition<&[u8], &[u8]> = TableDefinition::new("changes");
+const DOCUMENTS: TableDefinition<&[u8], &[u8]> = TableDefinition::new("documents");
+const SYNC_STATES: TableDefinition<&[u8], &[u8]> = TableDefinition::new("sync_states");
+
+/// The sizes of changes, the document and sync states, then the number of changes and sync
+/// states.
+type SizesRecord = (u64, u64, u64, u64, u64);
+
+/// The sizes and counts for each prefix, keyed by namespace.
+const SIZES: TableDefinition<&[u8], SizesRecord> = TableDefinition::new("sizes");
+
+/// The tables opened in a write transaction.
+struct Tables<'db, 'txn> {
+    changes: Table<'db, 'txn, &'static [u8], &'static [u8]>,
+    documents: Table<'db, 'txn, &'static [u8], &'static [u8]>,
+    sync_states: Table<'db, 'txn, &'static [u8], &'static [u8]>,
+    sizes: Table<'db, 'txn, &'static [u8], SizesRecord>,
+}
+
+impl<'db, 'txn> Tables<'db, 'txn> {
+    /// Open the tables, creating any that don't exist yet.
+    fn open(txn: &'txn WriteTransaction<'db>) -> Result<Self, TableError> {
+        Ok(Self {
+            changes: txn.open_table(CHANGES)?,
+            documents: txn.open_table(DOCUMENTS)?,
+            sync_states: txn.open_table(SYNC_STATES)?,
+            sizes: txn.open_table(SIZES)?,
+        })
+    }
+
+    /// The recorded sizes and counts for `namespace`.
+    fn sizes(&self, namespace: &[u8]) -> Result<(StoredSizes, StoredCounts), StorageError> {
+        let record = self
+            .sizes
+            .get(namespace)?
+            .map(|record| record.value())
+            .unwrap_or_default();
+        Ok((
+            StoredSizes {
+                changes: record.0,
+                document: record.1,
+                sync_states: record.2,
+            },
+            StoredCounts {
+                changes: record.3,
+                sync_states: record.4,
+            },
+        ))
+    }
+
+    fn set_sizes(
+        &mut self,
+        namespace: &[u8],
+        sizes: &StoredSizes,
+        counts: &StoredCounts,
+    ) -> Result<(), StorageError> {
+        self.sizes.insert(
+            namespace,
+            (
+                sizes.changes,
+                sizes.document,
+                sizes.sync_states,
+                counts.changes,
+                counts.sync_states,
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// Whether anything is stored under `namespace`.
+    #[cfg(feature = "sled")]
+    fn in_use(&self, namespace: &[u8]) -> Result<bool, StorageError> {
+        let (_, counts) = self.sizes(namespace)?;
+        Ok(
+            counts.changes > 0
+                || counts.sync_states > 0
+                || self.documents.get(namespace)?.is_some(),
+        )
+    }
+}
+
+/// Keys with their namespace removed, along with their values.
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// The entries in `table` with keys that start with `namespace`.
+fn scan_prefix<T>(table: &T, namespace: &[u8]) -> Result<Entries, StorageError>
+where
+    T: ReadableTable<&'static [u8], &'static [u8]>,
+{
+    let mut entries = Vec::new();
+    for entry in table.range::<&[u8]>(namespace..)? {
+        let (key, value) = entry?;
+        match key.value().strip_prefix(namespace) {
+            Some(rest) => entries.push((rest.to_vec(), value.value().to_vec())),
+            None => break,
+        }
+    }
+    Ok(entries)
+}
+
+impl RedbPersister {
+    /// Construct a new persister.
+    ///
+    /// The tables are created if they don't exist yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from or writing to redb fails.
+    pub fn new<S>(db: Arc<Database>, prefix: S) -> Result<Self, RedbPersisterError>
+    where
+        S: Into<String>,
+    {
+        let namespace = namespace(&prefix.into());
+        let txn = db.begin_write()?;
+        let (sizes, counts) = Tables::open(&txn)?.sizes(&namespace)?;
+        txn.commit()?;
+        Ok(Self {
+            db,
+            namespace,
+            sizes,
+            counts,
+        })
+    }
+
+    /// The number of changes and sync states stored.
+    #[must_use]
+    pub fn counts(&self) -> StoredCounts {
+        self.counts.clone()
+    }
+
+    /// Make a key from the prefix, `actor_id` and `sequence_number`.
+    ///
+    /// Converts the `actor_id` to bytes and appends the `sequence_number` in big endian form.
+    fn make_key(&self, actor_id: &ActorId, seq: u64) -> Vec<u8> {
+        make_key(&self.namespace, actor_id, seq)
+    }
+
+    fn make_peer_key(&self, peer_id: &[u8]) -> Vec<u8> {
+        let mut key = self.namespace.clone();
+        key.extend(peer_id);
+        key
+    }
+
+    /// Run `f` in a write transaction, keeping the recorded sizes and counts up to date with
+    /// what it changes.
+    fn write<F>(&mut self, f: F) -> Result<(), RedbPersisterError>
+    where
+        F: FnOnce(&mut Tables, &mut StoredSizes, &mut StoredCounts) -> Result<(), StorageError>,
+    {
+        let txn = self.db.begin_write()?;
+        let (sizes, counts) = {
+            let mut tables = Tables::open(&txn)?;
+            let (mut sizes, mut counts) = tables.sizes(&self.namespace)?;
+            f(&mut tables, &mut sizes, &mut counts)?;
+            tables.set_sizes(&self.namespace, &sizes, &counts)?;
+            (sizes, counts)
+        };
+        txn.commit()?;
+        self.sizes = sizes;
+        self.counts = counts;
+        Ok(())
+    }
+}
+
+fn make_key(namespace: &[u8], actor_id: &ActorId, seq: u64) -> Vec<u8> {
+    let mut key = namespace.to_vec();
+    key.extend(actor_id.to_bytes());
+    key.extend(&seq.to_be_bytes());
+    key
+}
+
+/// Remove the `keys` from `table`, returning the bytes and number of entries removed.
+fn remove_all(
+    table: &mut Table<&'static [u8], &'static [u8]>,
+    keys: &[Vec<u8>],
+) -> Result<(u64, u64), StorageError> {
+    let mut removed = (0, 0);
+    for key in keys {
+        if let Some(old) = table.remove(key.as_slice())? {
+            removed.0 += old.value().len() as u64;
+            removed.1 += 1;
+        }
+    }
+    Ok(removed)
+}
+
+impl Persister for RedbPersister {
+    type Error = RedbPersisterError;
+
+    /// Get all of the current changes.
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let txn = self.db.begin_read()?;
+        let table = match txn.open_table(CHANGES) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(scan_prefix(&table, &self.namespace)?
+            .into_iter()
+            .map(|(_, change)| change)
+            .collect())
+    }
+
+    /// Insert all of the given changes in a single transaction.
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let keys = changes
+            .iter()
+            .map(|(a, s, _)| self.make_key(a, *s))
+            .collect::<Vec<_>>();
+        self.write(|tables, sizes, counts| {
+            for (key, (_, _, c)) in keys.iter().zip(&changes) {
+                match tables.changes.insert(key.as_slice(), c.as_slice())? {
+                    Some(old) => {
+                        sizes.changes = sizes.changes.saturating_sub(old.value().len() as u64);
+                    }
+                    None => counts.changes += 1,
+                }
+                sizes.changes += c.len() as u64;
+            }
+            Ok(())
+        })
+    }
+
+    /// Remove all of the given changes in a single transaction.
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let keys = changes
+            .into_iter()
+            .map(|(a, s)| self.make_key(a, s))
+            .collect::<Vec<_>>();
+        self.write(|tables, sizes, counts| {
+            let removed = remove_all(&mut tables.changes, &keys)?;
+            sizes.changes = sizes.changes.saturating_sub(removed.0);
+            counts.changes = counts.changes.saturating_sub(removed.1);
+            Ok(())
+        })
+    }
+
+    /// Retrieve the document.
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        let txn = self.db.begin_read()?;
+        let table = match txn.open_table(DOCUMENTS) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let document = table
+            .get(self.namespace.as_slice())?
+            .map(|document| document.value().to_vec());
+        Ok(document)
+    }
+
+    /// Set the document.
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let namespace = self.namespace.clone();
+        self.write(|tables, sizes, _| {
+            tables
+                .documents
+                .insert(namespace.as_slice(), data.as_slice())?;
+            sizes.document = data.len() as u64;
+            Ok(())
+        })
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let txn = self.db.begin_read()?;
+        let table = match txn.open_table(SYNC_STATES) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let sync_state = table
+            .get(self.make_peer_key(peer_id).as_slice())?
+            .map(|sync_state| sync_state.value().to_vec());
+        Ok(sync_state)
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let key = self.make_peer_key(&peer_id);
+        self.write(|tables, sizes, counts| {
+            match tables
+                .sync_states
+                .insert(key.as_slice(), sync_state.as_slice())?
+            {
+                Some(old) => {
+                    sizes.sync_states = sizes.sync_states.saturating_sub(old.value().len() as u64);
+                }
+                None => counts.sync_states += 1,
+            }
+            sizes.sync_states += sync_state.len() as u64;
+            Ok(())
+        })
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let keys = peer_ids
+            .iter()
+            .map(|id| self.make_peer_key(id))
+            .collect::<Vec<_>>();
+        self.write(|tables, sizes, counts| {
+            let removed = remove_all(&mut tables.sync_states, &keys)?;
+            sizes.sync_states = sizes.sync_states.saturating_sub(removed.0);
+            counts.sync_states = counts.sync_states.saturating_sub(removed.1);
+            Ok(())
+        })
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let txn = self.db.begin_read()?;
+        let table = match txn.open_table(SYNC_STATES) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(scan_prefix(&table, &self.namespace)?
+            .into_iter()
+            .map(|(peer_id, _)| peer_id)
+            .collect())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Set the document and remove the changes and sync states in one transaction.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let namespace = self.namespace.clone();
+        let change_keys = changes
+            .into_iter()
+            .map(|(a, s)| self.make_key(a, s))
+            .collect::<Vec<_>>();
+        let peer_keys = old_peer_ids
+            .iter()
+            .map(|id| self.make_peer_key(id))
+            .collect::<Vec<_>>();
+        self.write(|tables, sizes, counts| {
+            tables
+                .documents
+                .insert(namespace.as_slice(), document.as_slice())?;
+            sizes.document = document.len() as u64;
+            let removed = remove_all(&mut tables.changes, &change_keys)?;
+            sizes.changes = sizes.changes.saturating_sub(removed.0);
+            counts.changes = counts.changes.saturating_sub(removed.1);
+            let removed = remove_all(&mut tables.sync_states, &peer_keys)?;
+            sizes.sync_states = sizes.sync_states.saturating_sub(removed.0);
+            counts.sync_states = counts.sync_states.saturating_sub(removed.1);
+            Ok(())
+        })
+    }
+
+    /// Transactions are durable once committed, so there is nothing to flush.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        Ok(0)
+    }
+}
@@ -0,0 +1,128 @@
+//! Moving documents over from the trees of a [`SledPersister`].
+
+use automerge::Change;
+use automerge_persistent::{namespace, Persister, StoredCounts, StoredSizes};
+use automerge_persistent_sled::{SledCatalog, SledPersister};
+use redb::Database;
+
+use crate::{make_key, RedbPersister, RedbPersisterError, Tables};
+
+impl RedbPersister {
+    /// Copy every document stored in the given sled trees into `db`, returning their prefixes.
+    ///
+    /// Each document keeps its prefix, so a [`RedbPersister`] opened with the same prefix as a
+    /// [`SledPersister`] sees the same data. Everything is copied in a single write transaction,
+    /// so an interrupted migration leaves `db` as it was. Each document is read through a
+    /// [`SledPersister`], which writes a size record into the document tree for any prefix that
+    /// doesn't have one yet. Nothing else in the sled trees is changed, and nothing should be
+    /// writing to them while this runs.
+    ///
+    /// The trees must already be in the current sled key layout, see [`SledPersister::migrate`].
+    ///
+    /// ```rust
+    /// # use std::sync::Arc;
+    /// # use automerge_persistent::Persister;
+    /// # use automerge_persistent_redb::{RedbPersister, RedbPersisterError};
+    /// # use automerge_persistent_sled::SledPersister;
+    /// # fn main() -> Result<(), RedbPersisterError> {
+    /// # let dir = tempfile::tempdir().unwrap();
+    /// let sled_db = sled::Config::new().temporary(true).open().unwrap();
+    /// let changes_tree = sled_db.open_tree("changes").unwrap();
+    /// let documents_tree = sled_db.open_tree("documents").unwrap();
+    /// let sync_states_tree = sled_db.open_tree("sync-states").unwrap();
+    /// let mut persister = SledPersister::new(
+    ///     changes_tree.clone(),
+    ///     documents_tree.clone(),
+    ///     sync_states_tree.clone(),
+    ///     "1",
+    /// )?;
+    /// persister.set_document(vec![1, 2, 3])?;
+    /// drop(persister);
+    ///
+    /// let db = Arc::new(redb::Database::create(dir.path().join("documents.redb"))?);
+    /// let prefixes = RedbPersister::migrate_from_sled(
+    ///     &db,
+    ///     &changes_tree,
+    ///     &documents_tree,
+    ///     &sync_states_tree,
+    /// )?;
+    /// assert_eq!(prefixes, vec!["1".to_owned()]);
+    ///
+    /// let persister = RedbPersister::new(db, "1")?;
+    /// assert_eq!(persister.get_document()?, Some(vec![1, 2, 3]));
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from sled or writing to redb fails, if a stored change can't
+    /// be loaded, or if a prefix already has data stored in `db`.
+    pub fn migrate_from_sled(
+        db: &Database,
+        changes_tree: &sled::Tree,
+        document_tree: &sled::Tree,
+        sync_states_tree: &sled::Tree,
+    ) -> Result<Vec<String>, RedbPersisterError> {
+        let catalog = SledCatalog::new(
+            changes_tree.clone(),
+            document_tree.clone(),
+            sync_states_tree.clone(),
+        )?;
+        let prefixes = catalog
+            .list()?
+            .into_iter()
+            .map(|(prefix, _)| prefix)
+            .collect::<Vec<_>>();
+
+        let txn = db.begin_write()?;
+        {
+            let mut tables = Tables::open(&txn)?;
+            for prefix in &prefixes {
+                let namespace = namespace(prefix);
+                if tables.in_use(&namespace)? {
+                    return Err(RedbPersisterError::PrefixExists(prefix.clone()));
+                }
+                let persister = SledPersister::new(
+                    changes_tree.clone(),
+                    document_tree.clone(),
+                    sync_states_tree.clone(),
+                    prefix.as_str(),
+                )?;
+                let mut sizes = StoredSizes::default();
+                let mut counts = StoredCounts::default();
+
+                for bytes in persister.get_changes()? {
+                    // the actor and sequence number for the key come from the change itself
+                    let change = Change::from_bytes(bytes)?;
+                    let key = make_key(&namespace, change.actor_id(), change.seq());
+                    let bytes = change.raw_bytes();
+                    if tables.changes.insert(key.as_slice(), bytes)?.is_none() {
+                        counts.changes += 1;
+                        sizes.changes += bytes.len() as u64;
+                    }
+                }
+                if let Some(document) = persister.get_document()? {
+                    tables
+                        .documents
+                        .insert(namespace.as_slice(), document.as_slice())?;
+                    sizes.document = document.len() as u64;
+                }
+                for peer_id in persister.get_peer_ids()? {
+                    if let Some(sync_state) = persister.get_sync_state(&peer_id)? {
+                        let mut key = namespace.clone();
+                        key.extend(&peer_id);
+                        tables
+                            .sync_states
+                            .insert(key.as_slice(), sync_state.as_slice())?;
+                        counts.sync_states += 1;
+                        sizes.sync_states += sync_state.len() as u64;
+                    }
+                }
+                tables.set_sizes(&namespace, &sizes, &counts)?;
+            }
+        }
+        txn.commit()?;
+        Ok(prefixes)
+    }
+}
@@ -0,0 +1,281 @@
+//! Listing and managing the documents stored in shared trees.
+
+use std::{
+    collections::BTreeSet,
+    convert::{Infallible, TryFrom},
+};
+
+use automerge_persistent::{namespace, StoredSizes};
+use sled::{transaction::ConflictableTransactionError, IVec, Transactional};
+
+use crate::{check_format, load_sizes, scan_sizes, sizes_key, SledPersisterError, SIZES_KEY_TAG};
+
+/// The documents stored in a set of trees shared by [`SledPersister`](crate::SledPersister)s,
+/// each under its own prefix.
+///
+/// Changing a prefix while a persister is open on it leaves that persister out of date, so they
+/// should be dropped first.
+///
+/// ```rust
+/// # use automerge_persistent::Persister;
+/// # use automerge_persistent_sled::{SledCatalog, SledPersister, SledPersisterError};
+/// # fn main() -> Result<(), SledPersisterError> {
+/// let db = sled::Config::new().temporary(true).open()?;
+/// let changes_tree = db.open_tree("changes")?;
+/// let documents_tree = db.open_tree("documents")?;
+/// let sync_states_tree = db.open_tree("sync-states")?;
+///
+/// let mut persister = SledPersister::new(
+///     changes_tree.clone(),
+///     documents_tree.clone(),
+///     sync_states_tree.clone(),
+///     "1",
+/// )?;
+/// persister.set_document(vec![1, 2, 3])?;
+/// drop(persister);
+///
+/// let catalog = SledCatalog::new(changes_tree, documents_tree, sync_states_tree)?;
+/// catalog.rename("1", "2")?;
+/// let prefixes = catalog.list()?;
+/// assert_eq!(prefixes.len(), 1);
+/// assert_eq!(prefixes[0].0, "2");
+/// assert_eq!(prefixes[0].1.document, 3);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SledCatalog {
+    changes: sled::Tree,
+    document: sled::Tree,
+    sync_states: sled::Tree,
+}
+
+impl SledCatalog {
+    /// Construct a catalog of the documents in the given trees.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from or writing to sled fails, or if the trees need to be
+    /// migrated first.
+    pub fn new(
+        changes_tree: sled::Tree,
+        document_tree: sled::Tree,
+        sync_states_tree: sled::Tree,
+    ) -> Result<Self, SledPersisterError> {
+        check_format(&changes_tree, &document_tree, &sync_states_tree)?;
+        Ok(Self {
+            changes: changes_tree,
+            document: document_tree,
+            sync_states: sync_states_tree,
+        })
+    }
+
+    /// List the prefixes that have anything stored, along with the sizes of what they store.
+    ///
+    /// Sizes come from the size record kept for each prefix where there is one, so this only
+    /// reads the data of prefixes without one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from sled fails.
+    pub fn list(&self) -> Result<Vec<(String, StoredSizes)>, SledPersisterError> {
+        let mut prefixes = BTreeSet::new();
+        for tree in &[&self.changes, &self.document, &self.sync_states] {
+            prefixes.extend(prefixes_in(tree)?);
+        }
+        prefixes
+            .into_iter()
+            .map(|prefix| {
+                let sizes = self.sizes(&prefix)?;
+                Ok((prefix, sizes))
+            })
+            .collect()
+    }
+
+    /// The sizes of what is stored under `prefix`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from sled fails.
+    pub fn sizes(&self, prefix: &str) -> Result<StoredSizes, SledPersisterError> {
+        let namespace = namespace(prefix);
+        if let Some((sizes, _)) = load_sizes(&self.document, &namespace)? {
+            return Ok(sizes);
+        }
+        let (sizes, _) = scan_sizes(&self.changes, &self.document, &self.sync_states, &namespace)?;
+        Ok(sizes)
+    }
+
+    /// Delete everything stored under `prefix`, in a single transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from or writing to sled fails.
+    pub fn delete(&self, prefix: &str) -> Result<(), SledPersisterError> {
+        let entries = self.entries(prefix)?;
+        self.apply(&entries, None)
+    }
+
+    /// Copy everything stored under `from` to `to`, in a single transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from or writing to sled fails, or if `to` already has
+    /// something stored.
+    pub fn copy(&self, from: &str, to: &str) -> Result<(), SledPersisterError> {
+        self.check_unused(to)?;
+        let entries = self.entries(from)?;
+        self.apply(&Entries::default(), Some((&entries, from, to)))
+    }
+
+    /// Move everything stored under `from` to `to`, in a single transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from or writing to sled fails, or if `to` already has
+    /// something stored.
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), SledPersisterError> {
+        if from == to {
+            return Ok(());
+        }
+        self.check_unused(to)?;
+        let entries = self.entries(from)?;
+        self.apply(&entries, Some((&entries, from, to)))
+    }
+
+    fn check_unused(&self, prefix: &str) -> Result<(), SledPersisterError> {
+        let namespace = namespace(prefix);
+        if self.changes.scan_prefix(&namespace).next().is_some()
+            || self.document.contains_key(&namespace)?
+            || self.sync_states.scan_prefix(&namespace).next().is_some()
+        {
+            return Err(SledPersisterError::PrefixExists(prefix.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Everything stored under `prefix` in each tree.
+    fn entries(&self, prefix: &str) -> Result<Entries, SledPersisterError> {
+        let namespace = namespace(prefix);
+        let collect = |iter: sled::Iter| iter.collect::<Result<Vec<_>, _>>();
+        let mut document = Vec::new();
+        for key in &[namespace.clone(), sizes_key(&namespace)] {
+            if let Some(value) = self.document.get(key)? {
+                document.push((IVec::from(key.as_slice()), value));
+            }
+        }
+        Ok(Entries {
+            changes: collect(self.changes.scan_prefix(&namespace))?,
+            document,
+            sync_states: collect(self.sync_states.scan_prefix(&namespace))?,
+        })
+    }
+
+    /// Remove the `removed` entries, then add the `added` entries rekeyed from one prefix to
+    /// another.
+    fn apply(
+        &self,
+        removed: &Entries,
+        added: Option<(&Entries, &str, &str)>,
+    ) -> Result<(), SledPersisterError> {
+        let rekeyed = added.map(|(entries, from, to)| entries.rekey(from, to));
+        (&self.changes, &self.document, &self.sync_states).transaction(
+            |(changes_tree, document_tree, sync_states_tree)| {
+                let trees = [changes_tree, document_tree, sync_states_tree];
+                for (tree, entries) in trees.iter().zip(removed.trees()) {
+                    for (key, _) in entries {
+                        tree.remove(key.clone())?;
+                    }
+                }
+                if let Some(rekeyed) = &rekeyed {
+                    for (tree, entries) in trees.iter().zip(rekeyed.trees()) {
+                        for (key, value) in entries {
+                            tree.insert(key.clone(), value.clone())?;
+                        }
+                    }
+                }
+                Ok::<_, ConflictableTransactionError<Infallible>>(())
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// The keys and values stored under a prefix.
+#[derive(Debug, Default)]
+struct Entries {
+    changes: Vec<(IVec, IVec)>,
+    document: Vec<(IVec, IVec)>,
+    sync_states: Vec<(IVec, IVec)>,
+}
+
+impl Entries {
+    const fn trees(&self) -> [&Vec<(IVec, IVec)>; 3] {
+        [&self.changes, &self.document, &self.sync_states]
+    }
+
+    /// The same entries moved from the prefix `from` to `to`.
+    fn rekey(&self, from: &str, to: &str) -> Self {
+        let (from, to) = (namespace(from), namespace(to));
+        let rekey = |entries: &Vec<(IVec, IVec)>| {
+            entries
+                .iter()
+                .map(|(key, value)| {
+                    // the size record key is tagged before the namespace
+                    let (tag, rest) = if key.first() == Some(&SIZES_KEY_TAG) {
+                        (&key[..1], &key[1 + from.len()..])
+                    } else {
+                        (&key[..0], &key[from.len()..])
+                    };
+                    let mut new = tag.to_vec();
+                    new.extend(&to);
+                    new.extend(rest);
+                    (IVec::from(new), value.clone())
+                })
+                .collect()
+        };
+        Self {
+            changes: rekey(&self.changes),
+            document: rekey(&self.document),
+            sync_states: rekey(&self.sync_states),
+        }
+    }
+}
+
+/// The prefixes with keys in `tree`, skipping over the keys of each prefix once it is found.
+fn prefixes_in(tree: &sled::Tree) -> Result<Vec<String>, SledPersisterError> {
+    let mut prefixes = Vec::new();
+    let mut next = tree.first()?;
+    // size records and the format marker sort after all namespaces
+    while let Some(prefix) = next.and_then(|(key, _)| decode_namespace(&key)) {
+        next = namespace_end(&namespace(&prefix))
+            .and_then(|end| tree.range(end..).next())
+            .transpose()?;
+        prefixes.push(prefix);
+    }
+    Ok(prefixes)
+}
+
+/// The prefix from the namespace at the start of `key`.
+fn decode_namespace(key: &[u8]) -> Option<String> {
+    if key.len() < 8 {
+        return None;
+    }
+    let (len, rest) = key.split_at(8);
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(len);
+    let len = usize::try_from(u64::from_be_bytes(bytes)).ok()?;
+    String::from_utf8(rest.get(..len)?.to_vec()).ok()
+}
+
+/// The first key after every key that starts with `namespace`, if there is one.
+fn namespace_end(namespace: &[u8]) -> Option<Vec<u8>> {
+    let mut end = namespace.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
@@ -50,33 +50,105 @@
 //! # }
 //! ```
 
+mod catalog;
+mod migrate;
+mod watch;
+
+use std::{collections::HashMap, convert::Infallible};
+
 use automerge::ActorId;
-use automerge_persistent::{Persister, StoredSizes};
+use automerge_persistent::{namespace, Persister, StoredCounts, StoredSizes};
+pub use catalog::SledCatalog;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Transactional,
+};
+pub use watch::{SledWatchEvent, SledWatcher, SledWatcherError};
 
 /// The persister that stores changes and documents in sled trees.
 ///
 /// Changes and documents are kept in separate trees.
 ///
-/// An optional prefix can be used in case multiple persisters may share the same trees.
+/// An optional prefix can be used in case multiple persisters may share the same trees. Keys
+/// start with the length of the prefix followed by the prefix itself, so one prefix never sees the
+/// data of another that it happens to be the start of. Trees written before keys were laid out
+/// like this need to go through [`SledPersister::migrate`].
+///
+/// Inserting changes applies them as a single batch, and [`Persister::compact`] uses a
+/// transaction over all three trees, so other readers of the trees never see either half done.
+/// For this the trees must all be opened from the same `sled::Db`.
+///
+/// Sizes and counts are kept in a record in the document tree so that opening doesn't have to
+/// read everything. The record is removed before anything else is changed and put back by
+/// [`Persister::flush`], so a crash can only leave it missing, in which case it is rebuilt. This
+/// relies on sled recovering writes in order, another reason to use the same `sled::Db`.
 #[derive(Debug)]
 pub struct SledPersister {
     changes_tree: sled::Tree,
     document_tree: sled::Tree,
     sync_states_tree: sled::Tree,
-    prefix: String,
+    /// The start of every key belonging to this persister, see [`namespace`].
+    namespace: Vec<u8>,
     sizes: StoredSizes,
+    counts: StoredCounts,
+    /// Whether the size record is in place and matches what is stored.
+    sizes_stored: bool,
 }
 
+/// The first byte of size record keys in the document tree.
+///
+/// Document keys start with the big endian length of their prefix, which can't be large enough
+/// to clash with this or [`FORMAT_KEY`].
+const SIZES_KEY_TAG: u8 = 0xff;
+
+/// The key in the document tree recording the layout of keys in all three trees.
+const FORMAT_KEY: &[u8] = &[0xfe];
+
+/// The current layout of keys, with delimited prefixes.
+const KEY_FORMAT: u8 = 2;
+
+/// The version of the size record layout.
+const SIZES_VERSION: u8 = 1;
+
 /// Possible errors from persisting.
 #[derive(Debug, thiserror::Error)]
 pub enum SledPersisterError {
     /// Internal errors from sled.
     #[error(transparent)]
     SledError(#[from] sled::Error),
+    /// The trees hold data with the old key layout, see [`SledPersister::migrate`].
+    #[error("the trees use an old key layout and need migrating")]
+    NeedsMigration,
+    /// The trees use a key layout from a newer version.
+    #[error("unknown key layout {0:?}")]
+    UnknownFormat(Vec<u8>),
+    /// A key being migrated doesn't start with any of the prefixes given.
+    #[error("key {0:?} does not belong to any of the given prefixes")]
+    UnmatchedKey(Vec<u8>),
+    /// A prefix being copied or renamed to already has data stored under it.
+    #[error("prefix {0:?} is already in use")]
+    PrefixExists(String),
+}
+
+impl From<TransactionError<Infallible>> for SledPersisterError {
+    fn from(error: TransactionError<Infallible>) -> Self {
+        match error {
+            TransactionError::Abort(never) => match never {},
+            TransactionError::Storage(error) => Self::SledError(error),
+        }
+    }
 }
 
 impl SledPersister {
     /// Construct a new persister.
+    ///
+    /// Sizes are loaded from the size record. If there isn't one they are worked out from the
+    /// trees and a record of them is written to the document tree.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from or writing to sled fails, or if the trees need to be
+    /// migrated first.
     pub fn new<S>(
         changes_tree: sled::Tree,
         document_tree: sled::Tree,
@@ -86,32 +158,96 @@ impl SledPersister {
     where
         S: Into<String>,
     {
-        let prefix = prefix.into();
+        check_format(&changes_tree, &document_tree, &sync_states_tree)?;
 
         let mut s = Self {
             changes_tree,
             document_tree,
             sync_states_tree,
-            prefix,
+            namespace: namespace(&prefix.into()),
             sizes: StoredSizes::default(),
+            counts: StoredCounts::default(),
+            sizes_stored: false,
         };
-        s.sizes.changes = s.get_changes()?.iter().map(Vec::len).sum::<usize>() as u64;
-        s.sizes.document = s.get_document()?.unwrap_or_default().len() as u64;
-        s.sizes.sync_states = s
-            .get_peer_ids()?
-            .iter()
-            .map(|id| s.get_sync_state(id).map(|o| o.unwrap_or_default().len()))
-            .collect::<Result<Vec<usize>, _>>()?
-            .iter()
-            .sum::<usize>() as u64;
+        let (sizes, counts) = if let Some(record) = s.load_sizes()? {
+            record
+        } else {
+            let (sizes, counts) = s.scan_sizes()?;
+            s.document_tree
+                .insert(s.make_sizes_key(), encode_sizes(&sizes, &counts))?;
+            (sizes, counts)
+        };
+        s.sizes = sizes;
+        s.counts = counts;
+        s.sizes_stored = true;
         Ok(s)
     }
 
+    /// The number of changes and sync states stored.
+    #[must_use]
+    pub fn counts(&self) -> StoredCounts {
+        self.counts.clone()
+    }
+
+    /// Work out the sizes and counts from what is stored, correcting them if they were wrong.
+    ///
+    /// They are normally loaded from the size record, this is for when it can't be trusted, such
+    /// as when the trees have been changed by something else. Returns whether they were already
+    /// right.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from or writing to sled fails.
+    pub fn verify_sizes(&mut self) -> Result<bool, SledPersisterError> {
+        let (sizes, counts) = self.scan_sizes()?;
+        self.correct_sizes(sizes, counts)
+    }
+
+    /// Replace the sizes and counts with ones worked out elsewhere, returning whether they were
+    /// already right.
+    fn correct_sizes(
+        &mut self,
+        sizes: StoredSizes,
+        counts: StoredCounts,
+    ) -> Result<bool, SledPersisterError> {
+        if sizes == self.sizes && counts == self.counts {
+            return Ok(true);
+        }
+        self.sizes = sizes;
+        self.counts = counts;
+        self.invalidate_sizes()?;
+        Ok(false)
+    }
+
+    /// Add up the sizes and counts of everything stored.
+    fn scan_sizes(&self) -> Result<(StoredSizes, StoredCounts), SledPersisterError> {
+        scan_sizes(
+            &self.changes_tree,
+            &self.document_tree,
+            &self.sync_states_tree,
+            &self.namespace,
+        )
+    }
+
+    /// The stored size record, if there is a valid one.
+    fn load_sizes(&self) -> Result<Option<(StoredSizes, StoredCounts)>, SledPersisterError> {
+        load_sizes(&self.document_tree, &self.namespace)
+    }
+
+    /// Remove the size record before anything else is changed.
+    fn invalidate_sizes(&mut self) -> Result<(), SledPersisterError> {
+        if self.sizes_stored {
+            self.document_tree.remove(self.make_sizes_key())?;
+            self.sizes_stored = false;
+        }
+        Ok(())
+    }
+
     /// Make a key from the prefix, `actor_id` and `sequence_number`.
     ///
     /// Converts the `actor_id` to bytes and appends the `sequence_number` in big endian form.
     fn make_key(&self, actor_id: &ActorId, seq: u64) -> Vec<u8> {
-        let mut key = self.prefix.as_bytes().to_vec();
+        let mut key = self.namespace.clone();
         key.extend(actor_id.to_bytes());
         key.extend(&seq.to_be_bytes());
         key
@@ -120,14 +256,117 @@ impl SledPersister {
     /// Make a key just from the prefix.
     /// Since each document only has one thing to store in this tree we can just use the prefix.
     fn make_document_key(&self) -> Vec<u8> {
-        self.prefix.as_bytes().to_vec()
+        self.namespace.clone()
     }
 
     fn make_peer_key(&self, peer_id: &[u8]) -> Vec<u8> {
-        let mut key = self.prefix.as_bytes().to_vec();
+        let mut key = self.namespace.clone();
         key.extend(peer_id);
         key
     }
+
+    /// The key of the size record for this prefix in the document tree.
+    fn make_sizes_key(&self) -> Vec<u8> {
+        sizes_key(&self.namespace)
+    }
+}
+
+/// The key of the size record for `namespace` in the document tree.
+fn sizes_key(namespace: &[u8]) -> Vec<u8> {
+    let mut key = vec![SIZES_KEY_TAG];
+    key.extend(namespace);
+    key
+}
+
+/// Add up the sizes and counts of everything stored under `namespace`.
+fn scan_sizes(
+    changes_tree: &sled::Tree,
+    document_tree: &sled::Tree,
+    sync_states_tree: &sled::Tree,
+    namespace: &[u8],
+) -> Result<(StoredSizes, StoredCounts), SledPersisterError> {
+    let mut sizes = StoredSizes::default();
+    let mut counts = StoredCounts::default();
+    for change in changes_tree.scan_prefix(namespace).values() {
+        sizes.changes += change?.len() as u64;
+        counts.changes += 1;
+    }
+    sizes.document = document_tree
+        .get(namespace)?
+        .map_or(0, |document| document.len() as u64);
+    for sync_state in sync_states_tree.scan_prefix(namespace).values() {
+        sizes.sync_states += sync_state?.len() as u64;
+        counts.sync_states += 1;
+    }
+    Ok((sizes, counts))
+}
+
+/// The size record stored for `namespace`, if there is a valid one.
+fn load_sizes(
+    document_tree: &sled::Tree,
+    namespace: &[u8],
+) -> Result<Option<(StoredSizes, StoredCounts)>, SledPersisterError> {
+    Ok(document_tree
+        .get(sizes_key(namespace))?
+        .and_then(|record| decode_sizes(&record)))
+}
+
+/// Make sure the trees use the current key layout, marking them as doing so if they are new.
+fn check_format(
+    changes_tree: &sled::Tree,
+    document_tree: &sled::Tree,
+    sync_states_tree: &sled::Tree,
+) -> Result<(), SledPersisterError> {
+    match document_tree.get(FORMAT_KEY)? {
+        Some(format) if *format == [KEY_FORMAT] => Ok(()),
+        Some(format) => Err(SledPersisterError::UnknownFormat(format.to_vec())),
+        None if changes_tree.is_empty()
+            && document_tree.is_empty()
+            && sync_states_tree.is_empty() =>
+        {
+            document_tree.insert(FORMAT_KEY, &[KEY_FORMAT])?;
+            Ok(())
+        }
+        None => Err(SledPersisterError::NeedsMigration),
+    }
+}
+
+fn encode_sizes(sizes: &StoredSizes, counts: &StoredCounts) -> Vec<u8> {
+    let mut record = vec![SIZES_VERSION];
+    for field in &[
+        sizes.changes,
+        sizes.document,
+        sizes.sync_states,
+        counts.changes,
+        counts.sync_states,
+    ] {
+        record.extend(&field.to_be_bytes());
+    }
+    record
+}
+
+fn decode_sizes(record: &[u8]) -> Option<(StoredSizes, StoredCounts)> {
+    let (version, fields) = record.split_first()?;
+    if *version != SIZES_VERSION || fields.len() != 5 * 8 {
+        return None;
+    }
+    let mut fields = fields.chunks_exact(8).map(|field| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(field);
+        u64::from_be_bytes(bytes)
+    });
+    let mut next = || fields.next().unwrap_or_default();
+    Some((
+        StoredSizes {
+            changes: next(),
+            document: next(),
+            sync_states: next(),
+        },
+        StoredCounts {
+            changes: next(),
+            sync_states: next(),
+        },
+    ))
 }
 
 impl Persister for SledPersister {
@@ -136,30 +375,44 @@ impl Persister for SledPersister {
     /// Get all of the current changes.
     fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
         self.changes_tree
-            .scan_prefix(&self.prefix)
+            .scan_prefix(&self.namespace)
             .values()
             .map(|v| v.map(|v| v.to_vec()).map_err(Self::Error::SledError))
             .collect()
     }
 
-    /// Insert all of the given changes into the tree.
+    /// Insert all of the given changes into the tree, as a single batch.
     fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        self.invalidate_sizes()?;
+        // the lengths of what each key holds, including earlier changes in this batch
+        let mut lengths = HashMap::new();
+        let mut batch = sled::Batch::default();
         for (a, s, c) in changes {
             let key = self.make_key(&a, s);
-            self.sizes.changes += c.len() as u64;
-            if let Some(old) = self.changes_tree.insert(key, c)? {
-                self.sizes.changes -= old.len() as u64;
+            let old = match lengths.get(&key) {
+                Some(len) => Some(*len),
+                None => self.changes_tree.get(&key)?.map(|old| old.len() as u64),
+            };
+            match old {
+                Some(len) => self.sizes.changes -= len,
+                None => self.counts.changes += 1,
             }
+            self.sizes.changes += c.len() as u64;
+            lengths.insert(key.clone(), c.len() as u64);
+            batch.insert(key, c);
         }
+        self.changes_tree.apply_batch(batch)?;
         Ok(())
     }
 
     /// Remove all of the given changes from the tree.
     fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        self.invalidate_sizes()?;
         for (a, s) in changes {
             let key = self.make_key(a, s);
             if let Some(old) = self.changes_tree.remove(key)? {
                 self.sizes.changes -= old.len() as u64;
+                self.counts.changes -= 1;
             }
         }
         Ok(())
@@ -175,6 +428,7 @@ impl Persister for SledPersister {
 
     /// Set the document in the tree.
     fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.invalidate_sizes()?;
         self.sizes.document = data.len() as u64;
         self.document_tree.insert(self.make_document_key(), data)?;
         Ok(())
@@ -189,19 +443,23 @@ impl Persister for SledPersister {
     }
 
     fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.invalidate_sizes()?;
         let sync_state_key = self.make_peer_key(&peer_id);
         self.sizes.sync_states += sync_state.len() as u64;
-        if let Some(old) = self.sync_states_tree.insert(sync_state_key, sync_state)? {
-            self.sizes.sync_states -= old.len() as u64;
+        match self.sync_states_tree.insert(sync_state_key, sync_state)? {
+            Some(old) => self.sizes.sync_states -= old.len() as u64,
+            None => self.counts.sync_states += 1,
         }
         Ok(())
     }
 
     fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        self.invalidate_sizes()?;
         for id in peer_ids {
             let key = self.make_peer_key(id);
             if let Some(old) = self.sync_states_tree.remove(key)? {
                 self.sizes.sync_states -= old.len() as u64;
+                self.counts.sync_states -= 1;
             }
         }
         Ok(())
@@ -209,9 +467,12 @@ impl Persister for SledPersister {
 
     fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
         self.sync_states_tree
-            .scan_prefix(&self.prefix)
+            .scan_prefix(&self.namespace)
             .keys()
-            .map(|v| v.map(|v| v.to_vec()).map_err(Self::Error::SledError))
+            .map(|v| {
+                v.map(|v| v[self.namespace.len()..].to_vec())
+                    .map_err(Self::Error::SledError)
+            })
             .collect()
     }
 
@@ -219,7 +480,69 @@ impl Persister for SledPersister {
         self.sizes.clone()
     }
 
+    /// Set the document and remove the changes and sync states in one transaction.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let sizes_key = self.make_sizes_key();
+        let document_key = self.make_document_key();
+        let change_keys = changes
+            .into_iter()
+            .map(|(a, s)| self.make_key(a, s))
+            .collect::<Vec<_>>();
+        let peer_keys = old_peer_ids
+            .iter()
+            .map(|id| self.make_peer_key(id))
+            .collect::<Vec<_>>();
+        let document_len = document.len() as u64;
+        let (changes_removed, sync_states_removed) = (
+            &self.changes_tree,
+            &self.document_tree,
+            &self.sync_states_tree,
+        )
+            .transaction(|(changes_tree, document_tree, sync_states_tree)| {
+                document_tree.remove(sizes_key.clone())?;
+                document_tree.insert(document_key.clone(), document.clone())?;
+                // the bytes and number of items removed
+                let mut changes_removed = (0, 0);
+                for key in &change_keys {
+                    if let Some(old) = changes_tree.remove(key.clone())? {
+                        changes_removed.0 += old.len() as u64;
+                        changes_removed.1 += 1;
+                    }
+                }
+                let mut sync_states_removed = (0, 0);
+                for key in &peer_keys {
+                    if let Some(old) = sync_states_tree.remove(key.clone())? {
+                        sync_states_removed.0 += old.len() as u64;
+                        sync_states_removed.1 += 1;
+                    }
+                }
+                Ok::<_, ConflictableTransactionError<Infallible>>((
+                    changes_removed,
+                    sync_states_removed,
+                ))
+            })?;
+        self.sizes_stored = false;
+        self.sizes.document = document_len;
+        self.sizes.changes -= changes_removed.0;
+        self.counts.changes -= changes_removed.1;
+        self.sizes.sync_states -= sync_states_removed.0;
+        self.counts.sync_states -= sync_states_removed.1;
+        Ok(())
+    }
+
     fn flush(&mut self) -> Result<usize, Self::Error> {
+        if !self.sizes_stored {
+            self.document_tree.insert(
+                self.make_sizes_key(),
+                encode_sizes(&self.sizes, &self.counts),
+            )?;
+            self.sizes_stored = true;
+        }
         let mut flushed = 0;
         flushed += self.changes_tree.flush()?;
         flushed += self.document_tree.flush()?;
@@ -0,0 +1,206 @@
+//! Moving trees over from the key layout where keys started with the bare prefix.
+
+use std::{collections::HashSet, convert::Infallible, ops::Bound};
+
+use automerge::Change;
+use automerge_persistent::namespace;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    Transactional,
+};
+
+use crate::{SledPersister, SledPersisterError, FORMAT_KEY, KEY_FORMAT};
+
+/// The key in the document tree recording how far a migration has got, so that an interrupted
+/// one carries on from there.
+///
+/// It holds the index of the tree being migrated followed by the last key moved in it. Like
+/// [`FORMAT_KEY`] this can't be the start of a prefix, as it isn't valid UTF-8.
+const PROGRESS_KEY: &[u8] = &[0xfd];
+
+/// The number of keys moved in each transaction.
+const BATCH_SIZE: usize = 1000;
+
+/// The trees being migrated, each of which has its keys given to prefixes differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Changes,
+    Document,
+    SyncStates,
+}
+
+/// A key to remove, along with the key and value to replace it with if it is kept.
+type Move = (sled::IVec, Option<(Vec<u8>, sled::IVec)>);
+
+impl SledPersister {
+    /// Rewrite trees written before prefixes were delimited in keys to the current layout.
+    ///
+    /// The old keys didn't record where the prefix ended, so every prefix that has been used with
+    /// the trees needs to be given. Changes are given to a prefix by the actor and sequence number
+    /// they hold, and documents by matching a prefix exactly. Sync states only have the peer id
+    /// after the prefix, so one whose key starts with more than one of the prefixes, such as `1`
+    /// and `10` for a peer id starting with `0`, can't be placed. These are dropped, costing some
+    /// extra messages in the next sync with that peer, and the number dropped is returned.
+    ///
+    /// Keys are moved in batches, each in its own transaction along with a record of how far the
+    /// migration has got, so an interrupted migration carries on where it stopped when this is
+    /// called again. Trees that are already migrated are left alone. No persisters should be using
+    /// the trees while this runs.
+    ///
+    /// ```rust
+    /// # use automerge_persistent_sled::{SledPersister, SledPersisterError};
+    /// # fn main() -> Result<(), SledPersisterError> {
+    /// let db = sled::Config::new().temporary(true).open()?;
+    /// let changes_tree = db.open_tree("changes")?;
+    /// let documents_tree = db.open_tree("documents")?;
+    /// let sync_states_tree = db.open_tree("sync-states")?;
+    /// // written with the old layout for the documents "1" and "10"
+    /// documents_tree.insert("1", vec![1])?;
+    /// documents_tree.insert("10", vec![2])?;
+    /// // the peer "0a" of "1", or the peer "a" of "10"
+    /// sync_states_tree.insert("10a", vec![3])?;
+    ///
+    /// let dropped =
+    ///     SledPersister::migrate(&changes_tree, &documents_tree, &sync_states_tree, &["1", "10"])?;
+    /// assert_eq!(dropped, 1);
+    /// let persister = SledPersister::new(changes_tree, documents_tree, sync_states_tree, "1")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from or writing to sled fails, or if a key doesn't belong to
+    /// any of the `prefixes`.
+    pub fn migrate(
+        changes_tree: &sled::Tree,
+        document_tree: &sled::Tree,
+        sync_states_tree: &sled::Tree,
+        prefixes: &[&str],
+    ) -> Result<usize, SledPersisterError> {
+        match document_tree.get(FORMAT_KEY)? {
+            Some(format) if *format == [KEY_FORMAT] => return Ok(0),
+            Some(format) => return Err(SledPersisterError::UnknownFormat(format.to_vec())),
+            None => {}
+        }
+        let progress = document_tree.get(PROGRESS_KEY)?;
+        let (first_tree, mut after) = match progress.as_deref().and_then(<[u8]>::split_first) {
+            Some((tree, key)) => (usize::from(*tree), Some(key.to_vec())),
+            None => (0, None),
+        };
+
+        let trees = [
+            (changes_tree, Kind::Changes),
+            (document_tree, Kind::Document),
+            (sync_states_tree, Kind::SyncStates),
+        ];
+        let mut dropped = 0;
+        for (index, (tree, kind)) in trees.iter().enumerate().skip(first_tree) {
+            // new keys sort before the old ones they replace, so are behind the batches still
+            // to come, except for the odd key that is all zeros
+            let mut ahead = HashSet::new();
+            loop {
+                let lower = after.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+                let mut moves = Vec::new();
+                let mut last = None;
+                for entry in tree.range::<&[u8], _>((lower, Bound::Unbounded)) {
+                    let (key, value) = entry?;
+                    last = Some(key.to_vec());
+                    if !ahead.remove(&*key) {
+                        if let Some(mv) = rekey(*kind, key, value, prefixes, &mut dropped)? {
+                            moves.push(mv);
+                        }
+                    }
+                    if moves.len() == BATCH_SIZE {
+                        break;
+                    }
+                }
+                let Some(last) = last else {
+                    break;
+                };
+                for (old, new) in &moves {
+                    if let Some((new, _)) = new {
+                        if new.as_slice() > &**old {
+                            ahead.insert(new.clone());
+                        }
+                    }
+                }
+
+                #[allow(clippy::cast_possible_truncation)]
+                let mut progress = vec![index as u8];
+                progress.extend(&last);
+                let write = |tree: &TransactionalTree, document_tree: &TransactionalTree| {
+                    // everything old goes before anything new is added, as they may overlap
+                    for (old, _) in &moves {
+                        tree.remove(old.clone())?;
+                    }
+                    for (_, new) in &moves {
+                        if let Some((key, value)) = new {
+                            tree.insert(key.clone(), value.clone())?;
+                        }
+                    }
+                    document_tree.insert(PROGRESS_KEY, progress.clone())?;
+                    Ok::<_, ConflictableTransactionError<Infallible>>(())
+                };
+                if *kind == Kind::Document {
+                    document_tree.transaction(|tree| write(tree, tree))?;
+                } else {
+                    (*tree, document_tree)
+                        .transaction(|(tree, document_tree)| write(tree, document_tree))?;
+                }
+                after = Some(last);
+            }
+            after = None;
+        }
+
+        document_tree.transaction(|tree| {
+            tree.insert(FORMAT_KEY, &[KEY_FORMAT])?;
+            tree.remove(PROGRESS_KEY)?;
+            Ok::<_, ConflictableTransactionError<Infallible>>(())
+        })?;
+        Ok(dropped)
+    }
+}
+
+/// Work out the new key for `key` from a tree of the given kind, or `None` if it should be left
+/// where it is.
+fn rekey(
+    kind: Kind,
+    key: sled::IVec,
+    value: sled::IVec,
+    prefixes: &[&str],
+    dropped: &mut usize,
+) -> Result<Option<Move>, SledPersisterError> {
+    let prefix = match kind {
+        Kind::Changes => change_prefix(&key, &value, prefixes),
+        Kind::Document => match key.first() {
+            // left by this migration
+            Some(tag) if *tag == PROGRESS_KEY[0] => return Ok(None),
+            _ => prefixes.iter().find(|p| *key == *p.as_bytes()).copied(),
+        },
+        Kind::SyncStates => {
+            let mut matching = prefixes.iter().filter(|p| key.starts_with(p.as_bytes()));
+            match (matching.next(), matching.next()) {
+                (Some(_), Some(_)) => {
+                    *dropped += 1;
+                    return Ok(Some((key, None)));
+                }
+                (prefix, _) => prefix.copied(),
+            }
+        }
+    };
+    let prefix = prefix.ok_or_else(|| SledPersisterError::UnmatchedKey(key.to_vec()))?;
+    let mut new = namespace(prefix);
+    new.extend(&key[prefix.len()..]);
+    Ok(Some((key, Some((new, value)))))
+}
+
+/// The prefix a change is stored under, found from the actor and sequence number at the end of
+/// its key.
+fn change_prefix<'a>(key: &[u8], change: &[u8], prefixes: &[&'a str]) -> Option<&'a str> {
+    let change = Change::from_bytes(change.to_vec()).ok()?;
+    let mut suffix = change.actor_id().to_bytes().to_vec();
+    suffix.extend(&change.seq().to_be_bytes());
+    let prefix = key.strip_suffix(suffix.as_slice())?;
+    prefixes.iter().find(|p| p.as_bytes() == prefix).copied()
+}
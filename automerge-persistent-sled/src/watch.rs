@@ -0,0 +1,338 @@
+//! Watching the trees of a [`SledPersister`] for changes written by other handles.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::mpsc::RecvTimeoutError,
+    time::Duration,
+};
+
+use automerge::{Automerge, AutomergeError, Change, ChangeHash, LoadChangeError, ReadDoc};
+use automerge_persistent::{PersistentAutomerge, StoredCounts, StoredSizes};
+use sled::{Event, IVec, Subscriber};
+
+use crate::{SledPersister, SledPersisterError};
+
+/// Something the watcher found and applied to the document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SledWatchEvent {
+    /// New changes were inserted, these were applied to the document.
+    Changes(Vec<ChangeHash>),
+    /// A new document was set, merging it in gave these new changes.
+    Document(Vec<ChangeHash>),
+}
+
+/// Possible errors from watching.
+#[derive(Debug, thiserror::Error)]
+pub enum SledWatcherError {
+    /// An error from the persister when reading what is stored or correcting its sizes.
+    #[error(transparent)]
+    Persister(#[from] SledPersisterError),
+    /// An error from automerge when applying changes.
+    #[error(transparent)]
+    Automerge(#[from] AutomergeError),
+    /// A stored change couldn't be loaded.
+    #[error(transparent)]
+    LoadChange(#[from] LoadChangeError),
+    /// The database the trees belong to was closed.
+    #[error("the database being watched was closed")]
+    Closed,
+}
+
+/// Watches the trees of a [`SledPersister`] for writes under its prefix.
+///
+/// Any number of persisters can share a prefix within a process, but a document only learns of
+/// what its own persister writes. The watcher picks up changes and documents written through the
+/// others and applies them to the live document, so the documents stay converged without a sync
+/// round-trip. Changes the document already has, such as those it wrote itself, are skipped.
+///
+/// The sizes a persister keeps only account for its own writes. The watcher also sees sync states
+/// and follows the length of everything stored under the prefix from the events, so once it has
+/// caught up with them it corrects the persister's sizes without reading the trees again. This
+/// costs an entry in memory for each stored change and sync state. A persister can't account for
+/// removing something another handle stored that it hasn't been told about, so every handle that
+/// writes should have a watcher that is polled before it removes anything.
+///
+/// Sled hands events to subscribers through a bounded queue and writers wait for room in it, so
+/// the watcher should be polled regularly for as long as it is kept around.
+///
+/// ```rust
+/// # use automerge::{transaction::Transactable, ReadDoc, ROOT};
+/// # use automerge_persistent::PersistentAutomerge;
+/// # use automerge_persistent_sled::{SledPersister, SledWatcher};
+/// let db = sled::Config::new().temporary(true).open().unwrap();
+/// let changes_tree = db.open_tree("changes").unwrap();
+/// let documents_tree = db.open_tree("documents").unwrap();
+/// let sync_states_tree = db.open_tree("sync-states").unwrap();
+/// let open = || {
+///     let persister = SledPersister::new(
+///         changes_tree.clone(),
+///         documents_tree.clone(),
+///         sync_states_tree.clone(),
+///         "",
+///     )
+///     .unwrap();
+///     PersistentAutomerge::load(persister).unwrap()
+/// };
+///
+/// let mut doc1 = open();
+/// let mut doc2 = open();
+/// let mut watcher = SledWatcher::new(doc2.persister()).unwrap();
+///
+/// doc1.transact(|tx| tx.put(ROOT, "a", 1)).unwrap();
+/// let applied = watcher
+///     .poll(&mut doc2, |event| println!("applied {:?}", event))
+///     .unwrap();
+/// assert_eq!(applied, 1);
+/// assert!(doc2.document().get(ROOT, "a").unwrap().is_some());
+/// ```
+pub struct SledWatcher {
+    namespace: Vec<u8>,
+    changes: Subscriber,
+    document: Subscriber,
+    sync_states: Subscriber,
+    change_lengths: Lengths,
+    document_lengths: Lengths,
+    sync_state_lengths: Lengths,
+}
+
+impl fmt::Debug for SledWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SledWatcher")
+            .field("namespace", &self.namespace)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SledWatcher {
+    /// Start watching the prefix used by `persister`.
+    ///
+    /// Only writes from this point on are applied, anything written before should already have
+    /// been loaded into the document. The lengths of what is already stored are read to start
+    /// following the sizes from.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from sled fails.
+    pub fn new(persister: &SledPersister) -> Result<Self, SledWatcherError> {
+        let namespace = persister.namespace.clone();
+        // subscribing first means a write racing the scan is seen by both, which is harmless
+        // as events set lengths rather than adjusting them
+        let changes = persister.changes_tree.watch_prefix(&namespace);
+        // only the document itself is under the namespace in this tree, size records start
+        // with a tag
+        let document = persister.document_tree.watch_prefix(&namespace);
+        let sync_states = persister.sync_states_tree.watch_prefix(&namespace);
+        Ok(Self {
+            change_lengths: Lengths::scan(&persister.changes_tree, &namespace)?,
+            document_lengths: Lengths::scan(&persister.document_tree, &namespace)?,
+            sync_state_lengths: Lengths::scan(&persister.sync_states_tree, &namespace)?,
+            namespace,
+            changes,
+            document,
+            sync_states,
+        })
+    }
+
+    /// Apply any updates that have happened since the last call, without blocking.
+    ///
+    /// `on_event` is called for each batch of changes that was applied. Returns the number of
+    /// changes applied.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a change or document can't be loaded or applied.
+    pub fn poll<F>(
+        &mut self,
+        doc: &mut PersistentAutomerge<SledPersister>,
+        on_event: F,
+    ) -> Result<usize, SledWatcherError>
+    where
+        F: FnMut(SledWatchEvent),
+    {
+        self.read_and_apply(doc, on_event, None)
+    }
+
+    /// Block until there are updates and then apply them.
+    ///
+    /// Only writes to the changes tree wake this up. Compacting writes there along with the
+    /// document, but a document set on its own is left for the next call.
+    ///
+    /// Events for changes the document already has still wake this up, in which case nothing is
+    /// applied and 0 is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a change or document can't be loaded or applied, or if the database
+    /// has been closed.
+    pub fn wait<F>(
+        &mut self,
+        doc: &mut PersistentAutomerge<SledPersister>,
+        on_event: F,
+    ) -> Result<usize, SledWatcherError>
+    where
+        F: FnMut(SledWatchEvent),
+    {
+        let first = self.changes.next().ok_or(SledWatcherError::Closed)?;
+        self.read_and_apply(doc, on_event, Some(first))
+    }
+
+    fn read_and_apply<F>(
+        &mut self,
+        doc: &mut PersistentAutomerge<SledPersister>,
+        mut on_event: F,
+        first: Option<Event>,
+    ) -> Result<usize, SledWatcherError>
+    where
+        F: FnMut(SledWatchEvent),
+    {
+        let mut events = first.into_iter().collect();
+        let mut caught_up = drain(&mut self.changes, &mut events);
+        let mut changes = Vec::new();
+        for event in events {
+            self.change_lengths.apply(&event);
+            if let Event::Insert { value, .. } = event {
+                changes.push(value);
+            }
+        }
+        let mut events = Vec::new();
+        caught_up &= drain(&mut self.document, &mut events);
+        let mut document = None;
+        for event in events {
+            if *event.key() != *self.namespace {
+                continue;
+            }
+            self.document_lengths.apply(&event);
+            document = match event {
+                Event::Insert { value, .. } => Some(value),
+                Event::Remove { .. } => None,
+            };
+        }
+        let mut events = Vec::new();
+        caught_up &= drain(&mut self.sync_states, &mut events);
+        for event in events {
+            self.sync_state_lengths.apply(&event);
+        }
+
+        let mut applied = 0;
+        if let Some(bytes) = document {
+            applied += apply_document(&bytes, doc, &mut on_event)?;
+        }
+        applied += apply_changes(changes, doc, &mut on_event)?;
+        // the persister's own writes are in the events too, so the lengths only match what is
+        // stored once every event before this point has been seen
+        if caught_up {
+            let sizes = StoredSizes {
+                changes: self.change_lengths.total,
+                document: self.document_lengths.total,
+                sync_states: self.sync_state_lengths.total,
+            };
+            let counts = StoredCounts {
+                changes: self.change_lengths.count(),
+                sync_states: self.sync_state_lengths.count(),
+            };
+            doc.persister_mut().correct_sizes(sizes, counts)?;
+        }
+        Ok(applied)
+    }
+}
+
+/// Add the events waiting on `subscriber` to `events`, returning whether all of them were taken.
+///
+/// Sled reports an event that has been reserved by a write still in progress as disconnected
+/// rather than timed out, so either ends the events that are ready. The pending event is kept by
+/// the subscriber for the next call, and any after it wait behind it.
+fn drain(subscriber: &mut Subscriber, events: &mut Vec<Event>) -> bool {
+    loop {
+        match subscriber.next_timeout(Duration::from_secs(0)) {
+            Ok(event) => events.push(event),
+            Err(RecvTimeoutError::Timeout) => return true,
+            Err(RecvTimeoutError::Disconnected) => return false,
+        }
+    }
+}
+
+/// The length of each item stored in a tree under the prefix, followed from the tree's events.
+#[derive(Debug, Default)]
+struct Lengths {
+    lengths: HashMap<IVec, u64>,
+    total: u64,
+}
+
+impl Lengths {
+    /// Read the lengths of what is stored in `tree` under `namespace`.
+    fn scan(tree: &sled::Tree, namespace: &[u8]) -> Result<Self, SledPersisterError> {
+        let mut lengths = Self::default();
+        for entry in tree.scan_prefix(namespace) {
+            let (key, value) = entry?;
+            lengths.apply(&Event::Insert { key, value });
+        }
+        Ok(lengths)
+    }
+
+    fn apply(&mut self, event: &Event) {
+        let old = match event {
+            Event::Insert { key, value } => {
+                self.total += value.len() as u64;
+                self.lengths.insert(key.clone(), value.len() as u64)
+            }
+            Event::Remove { key } => self.lengths.remove(key),
+        };
+        if let Some(old) = old {
+            self.total -= old;
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.lengths.len() as u64
+    }
+}
+
+fn apply_document<F>(
+    bytes: &[u8],
+    doc: &mut PersistentAutomerge<SledPersister>,
+    on_event: &mut F,
+) -> Result<usize, SledWatcherError>
+where
+    F: FnMut(SledWatchEvent),
+{
+    if bytes.is_empty() {
+        return Ok(0);
+    }
+    let mut other = Automerge::load(bytes)?;
+    // the document is already stored so it is applied directly rather than persisted again
+    let new = doc.document_mut().merge(&mut other)?;
+    let applied = new.len();
+    if applied > 0 {
+        on_event(SledWatchEvent::Document(new));
+    }
+    Ok(applied)
+}
+
+fn apply_changes<F>(
+    values: Vec<sled::IVec>,
+    doc: &mut PersistentAutomerge<SledPersister>,
+    on_event: &mut F,
+) -> Result<usize, SledWatcherError>
+where
+    F: FnMut(SledWatchEvent),
+{
+    let mut seen = HashSet::new();
+    let mut changes = Vec::new();
+    for value in values {
+        let change = Change::from_bytes(value.to_vec())?;
+        if doc.document().get_change_by_hash(&change.hash()).is_none() && seen.insert(change.hash())
+        {
+            changes.push(change);
+        }
+    }
+    if changes.is_empty() {
+        return Ok(0);
+    }
+    let hashes = changes.iter().map(Change::hash).collect::<Vec<_>>();
+    let applied = hashes.len();
+    // the changes are already stored so they are applied directly rather than persisted again
+    doc.document_mut().apply_changes(changes)?;
+    on_event(SledWatchEvent::Changes(hashes));
+    Ok(applied)
+}
@@ -0,0 +1,474 @@
+#![warn(missing_docs)]
+#![warn(missing_crate_level_docs)]
+#![warn(missing_doc_code_examples)]
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+
+//! A persister targetting [SQLite](https://sqlite.org) through
+//! [rusqlite](https://github.com/rusqlite/rusqlite).
+//!
+//! # Single persister
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_sqlite::SqlitePersister;
+//! # use automerge_persistent_sqlite::SqlitePersisterError;
+//! # fn main() -> Result<(), SqlitePersisterError> {
+//! let connection = rusqlite::Connection::open_in_memory()?;
+//!
+//! let persister = SqlitePersister::new(connection, "")?;
+//! let doc = PersistentAutomerge::load(persister);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! # Multiple persisters sharing the same database
+//!
+//! Each persister has its own connection, which may be in another process.
+//!
+//! ```rust
+//! # use automerge_persistent::PersistentAutomerge;
+//! # use automerge_persistent_sqlite::SqlitePersister;
+//! # use automerge_persistent_sqlite::SqlitePersisterError;
+//! # fn main() -> Result<(), SqlitePersisterError> {
+//! # let dir = tempfile::tempdir().unwrap();
+//! let path = dir.path().join("documents.sqlite");
+//!
+//! let persister1 = SqlitePersister::open(&path, "1")?;
+//! let doc1 = PersistentAutomerge::load(persister1);
+//!
+//! let persister2 = SqlitePersister::open(&path, "2")?;
+//! let doc2 = PersistentAutomerge::load(persister2);
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{convert::TryFrom, path::Path, time::Duration};
+
+use automerge::ActorId;
+use automerge_persistent::{Persister, StoredCounts, StoredSizes};
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+
+/// The persister that stores changes, documents and sync states in SQLite tables.
+///
+/// Every row is keyed by the id of the document it belongs to, so any number of documents can be
+/// kept in one database. The tables can be read with the usual SQLite tools:
+///
+/// - `changes (doc_id, actor, seq, data)`
+/// - `documents (doc_id, data)`
+/// - `sync_states (doc_id, peer_id, data)`
+///
+/// Inserting changes and compacting each happen in a single transaction, so other connections
+/// never see either half done.
+///
+/// Sizes and counts are worked out with a query when the persister is created and kept up to date
+/// with what it writes. Writes from other connections for the same document aren't seen until
+/// [`SqlitePersister::verify_sizes`] is called.
+#[derive(Debug)]
+pub struct SqlitePersister {
+    connection: Connection,
+    doc_id: String,
+    sizes: StoredSizes,
+    counts: StoredCounts,
+}
+
+/// Possible errors from persisting.
+#[derive(Debug, thiserror::Error)]
+pub enum SqlitePersisterError {
+    /// Internal errors from SQLite.
+    #[error(transparent)]
+    SqliteError(#[from] rusqlite::Error),
+    /// The database has tables laid out by a newer version of this crate.
+    #[error("unknown schema version {0}")]
+    UnknownSchema(i64),
+}
+
+/// The version of the table layout, kept in the database's `user_version`.
+const SCHEMA_VERSION: i64 = 1;
+
+/// How long to wait for another connection to finish writing before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS changes (
+    doc_id TEXT NOT NULL,
+    actor BLOB NOT NULL,
+    seq INTEGER NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (doc_id, actor, seq)
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS documents (
+    doc_id TEXT NOT NULL PRIMARY KEY,
+    data BLOB NOT NULL
+) WITHOUT ROWID;
+CREATE TABLE IF NOT EXISTS sync_states (
+    doc_id TEXT NOT NULL,
+    peer_id BLOB NOT NULL,
+    data BLOB NOT NULL,
+    PRIMARY KEY (doc_id, peer_id)
+) WITHOUT ROWID;
+";
+
+impl SqlitePersister {
+    /// Open the database at `path`, creating it if needed, and construct a persister for the
+    /// document `doc_id` in it.
+    ///
+    /// The database is switched to write-ahead logging so that readers and a writer in other
+    /// connections don't block each other, and writes wait a while for other connections rather
+    /// than failing straight away.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database can't be opened or set up.
+    pub fn open<P, S>(path: P, doc_id: S) -> Result<Self, SqlitePersisterError>
+    where
+        P: AsRef<Path>,
+        S: Into<String>,
+    {
+        let connection = Connection::open(path)?;
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        Self::new(connection, doc_id)
+    }
+
+    /// Construct a persister for the document `doc_id` using an already open connection.
+    ///
+    /// The tables are created if they don't exist yet. Settings such as the journal mode are left
+    /// as they are on the connection.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tables can't be created or read, or if they were made by a newer
+    /// version of this crate.
+    pub fn new<S>(mut connection: Connection, doc_id: S) -> Result<Self, SqlitePersisterError>
+    where
+        S: Into<String>,
+    {
+        setup(&mut connection)?;
+        let mut s = Self {
+            connection,
+            doc_id: doc_id.into(),
+            sizes: StoredSizes::default(),
+            counts: StoredCounts::default(),
+        };
+        let (sizes, counts) = s.query_sizes()?;
+        s.sizes = sizes;
+        s.counts = counts;
+        Ok(s)
+    }
+
+    /// The id of the document this persister stores.
+    #[must_use]
+    pub fn doc_id(&self) -> &str {
+        &self.doc_id
+    }
+
+    /// The number of changes and sync states stored.
+    #[must_use]
+    pub fn counts(&self) -> StoredCounts {
+        self.counts.clone()
+    }
+
+    /// Work out the sizes and counts from what is stored, correcting them if they were wrong.
+    ///
+    /// This is for when other connections may have written to the same document. Returns whether
+    /// they were already right.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from SQLite fails.
+    pub fn verify_sizes(&mut self) -> Result<bool, SqlitePersisterError> {
+        let (sizes, counts) = self.query_sizes()?;
+        if sizes == self.sizes && counts == self.counts {
+            return Ok(true);
+        }
+        self.sizes = sizes;
+        self.counts = counts;
+        Ok(false)
+    }
+
+    /// Add up the sizes and counts of everything stored for this document.
+    fn query_sizes(&self) -> Result<(StoredSizes, StoredCounts), SqlitePersisterError> {
+        let total = |table: &str| {
+            self.connection.query_row(
+                &format!(
+                    "SELECT COALESCE(SUM(LENGTH(data)), 0), COUNT(*) FROM {table} WHERE doc_id = ?1"
+                ),
+                params![self.doc_id],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+            )
+        };
+        let (changes, change_count) = total("changes")?;
+        let (document, _) = total("documents")?;
+        let (sync_states, sync_state_count) = total("sync_states")?;
+        Ok((
+            StoredSizes {
+                changes: to_u64(changes),
+                document: to_u64(document),
+                sync_states: to_u64(sync_states),
+            },
+            StoredCounts {
+                changes: to_u64(change_count),
+                sync_states: to_u64(sync_state_count),
+            },
+        ))
+    }
+}
+
+/// Start a transaction that writes.
+///
+/// It takes the write lock straight away, as a deferred transaction that reads first and then
+/// finds another connection writing fails with `SQLITE_BUSY` rather than waiting out the busy
+/// timeout.
+fn write_transaction(connection: &mut Connection) -> Result<Transaction<'_>, rusqlite::Error> {
+    connection.transaction_with_behavior(TransactionBehavior::Immediate)
+}
+
+/// Create the tables, or check that the existing ones are a layout we know.
+fn setup(connection: &mut Connection) -> Result<(), SqlitePersisterError> {
+    let tx = write_transaction(connection)?;
+    let version: i64 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > SCHEMA_VERSION {
+        return Err(SqlitePersisterError::UnknownSchema(version));
+    }
+    if version < SCHEMA_VERSION {
+        tx.execute_batch(SCHEMA)?;
+        tx.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Convert a size from SQLite, which are never negative.
+fn to_u64(n: i64) -> u64 {
+    u64::try_from(n).unwrap_or_default()
+}
+
+/// The length selected by `sql`, if there is a row.
+fn stored_len(
+    tx: &Transaction,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Option<u64>, rusqlite::Error> {
+    tx.query_row(sql, params, |row| row.get::<_, i64>(0))
+        .optional()
+        .map(|len| len.map(to_u64))
+}
+
+/// Remove the changes in `changes` for `doc_id`, returning the bytes and number of rows removed.
+fn delete_changes(
+    tx: &Transaction,
+    doc_id: &str,
+    changes: Vec<(&ActorId, u64)>,
+) -> Result<(u64, u64), rusqlite::Error> {
+    let mut removed = (0, 0);
+    let mut delete = tx.prepare_cached(
+        "DELETE FROM changes WHERE doc_id = ?1 AND actor = ?2 AND seq = ?3 RETURNING LENGTH(data)",
+    )?;
+    for (actor_id, seq) in changes {
+        let len = delete
+            .query_row(params![doc_id, actor_id.to_bytes(), seq], |row| {
+                row.get::<_, i64>(0)
+            })
+            .optional()?;
+        if let Some(len) = len {
+            removed.0 += to_u64(len);
+            removed.1 += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Remove the sync states of `peer_ids` for `doc_id`, returning the bytes and number of rows
+/// removed.
+fn delete_sync_states(
+    tx: &Transaction,
+    doc_id: &str,
+    peer_ids: &[&[u8]],
+) -> Result<(u64, u64), rusqlite::Error> {
+    let mut removed = (0, 0);
+    let mut delete = tx.prepare_cached(
+        "DELETE FROM sync_states WHERE doc_id = ?1 AND peer_id = ?2 RETURNING LENGTH(data)",
+    )?;
+    for peer_id in peer_ids {
+        let len = delete
+            .query_row(params![doc_id, peer_id], |row| row.get::<_, i64>(0))
+            .optional()?;
+        if let Some(len) = len {
+            removed.0 += to_u64(len);
+            removed.1 += 1;
+        }
+    }
+    Ok(removed)
+}
+
+impl Persister for SqlitePersister {
+    type Error = SqlitePersisterError;
+
+    /// Get all of the current changes.
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let mut select = self
+            .connection
+            .prepare_cached("SELECT data FROM changes WHERE doc_id = ?1 ORDER BY actor, seq")?;
+        let changes = select
+            .query_map(params![self.doc_id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(changes)
+    }
+
+    /// Insert all of the given changes in a single transaction.
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        let tx = write_transaction(&mut self.connection)?;
+        let mut sizes = self.sizes.clone();
+        let mut counts = self.counts.clone();
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO changes (doc_id, actor, seq, data) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (a, s, c) in changes {
+                let actor = a.to_bytes();
+                let old = stored_len(
+                    &tx,
+                    "SELECT LENGTH(data) FROM changes WHERE doc_id = ?1 AND actor = ?2 AND seq = ?3",
+                    params![self.doc_id, actor, s],
+                )?;
+                match old {
+                    Some(len) => sizes.changes = sizes.changes.saturating_sub(len),
+                    None => counts.changes += 1,
+                }
+                sizes.changes += c.len() as u64;
+                insert.execute(params![self.doc_id, actor, s, c])?;
+            }
+        }
+        tx.commit()?;
+        self.sizes = sizes;
+        self.counts = counts;
+        Ok(())
+    }
+
+    /// Remove all of the given changes in a single transaction.
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        let tx = write_transaction(&mut self.connection)?;
+        let removed = delete_changes(&tx, &self.doc_id, changes)?;
+        tx.commit()?;
+        self.sizes.changes = self.sizes.changes.saturating_sub(removed.0);
+        self.counts.changes = self.counts.changes.saturating_sub(removed.1);
+        Ok(())
+    }
+
+    /// Retrieve the document.
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT data FROM documents WHERE doc_id = ?1",
+                params![self.doc_id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Set the document.
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        let len = data.len() as u64;
+        self.connection.execute(
+            "INSERT OR REPLACE INTO documents (doc_id, data) VALUES (?1, ?2)",
+            params![self.doc_id, data],
+        )?;
+        self.sizes.document = len;
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self
+            .connection
+            .query_row(
+                "SELECT data FROM sync_states WHERE doc_id = ?1 AND peer_id = ?2",
+                params![self.doc_id, peer_id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        let tx = write_transaction(&mut self.connection)?;
+        let old = stored_len(
+            &tx,
+            "SELECT LENGTH(data) FROM sync_states WHERE doc_id = ?1 AND peer_id = ?2",
+            params![self.doc_id, peer_id],
+        )?;
+        let len = sync_state.len() as u64;
+        tx.execute(
+            "INSERT OR REPLACE INTO sync_states (doc_id, peer_id, data) VALUES (?1, ?2, ?3)",
+            params![self.doc_id, peer_id, sync_state],
+        )?;
+        tx.commit()?;
+        match old {
+            Some(old) => {
+                self.sizes.sync_states = self.sizes.sync_states.saturating_sub(old);
+            }
+            None => self.counts.sync_states += 1,
+        }
+        self.sizes.sync_states += len;
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        let tx = write_transaction(&mut self.connection)?;
+        let removed = delete_sync_states(&tx, &self.doc_id, peer_ids)?;
+        tx.commit()?;
+        self.sizes.sync_states = self.sizes.sync_states.saturating_sub(removed.0);
+        self.counts.sync_states = self.counts.sync_states.saturating_sub(removed.1);
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let mut select = self
+            .connection
+            .prepare_cached("SELECT peer_id FROM sync_states WHERE doc_id = ?1 ORDER BY peer_id")?;
+        let peer_ids = select
+            .query_map(params![self.doc_id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(peer_ids)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    /// Set the document and remove the changes and sync states in one transaction.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        let document_len = document.len() as u64;
+        let tx = write_transaction(&mut self.connection)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO documents (doc_id, data) VALUES (?1, ?2)",
+            params![self.doc_id, document],
+        )?;
+        let changes_removed = delete_changes(&tx, &self.doc_id, changes)?;
+        let sync_states_removed = delete_sync_states(&tx, &self.doc_id, old_peer_ids)?;
+        tx.commit()?;
+        self.sizes.document = document_len;
+        // other connections may have stored things for this document that this one didn't count
+        self.sizes.changes = self.sizes.changes.saturating_sub(changes_removed.0);
+        self.counts.changes = self.counts.changes.saturating_sub(changes_removed.1);
+        self.sizes.sync_states = self.sizes.sync_states.saturating_sub(sync_states_removed.0);
+        self.counts.sync_states = self
+            .counts
+            .sync_states
+            .saturating_sub(sync_states_removed.1);
+        Ok(())
+    }
+
+    /// Everything is written as it happens, so this only moves the write-ahead log into the main
+    /// database file, if it isn't in use by another connection.
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        self.connection
+            .query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_| Ok(()))?;
+        Ok(0)
+    }
+}
@@ -0,0 +1,79 @@
+use std::error::Error;
+
+use automerge::ActorId;
+
+use crate::StoredSizes;
+
+/// An asynchronous version of [`Persister`](crate::Persister), for storage that is better driven
+/// without blocking.
+///
+/// The semantics of each operation match those of [`Persister`](crate::Persister) and
+/// implementations that provide both should share the same stored format so that data written
+/// through one can be read through the other.
+///
+/// Futures are `Send` except on `wasm32` targets, where storage APIs are single threaded.
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+pub trait AsyncPersister {
+    /// The error type that the operations can produce
+    type Error: Error + 'static;
+
+    /// Returns all of the changes that have been persisted through this persister.
+    /// Ordering is not specified as the automerge Backend should handle that.
+    async fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Inserts the given change at the unique address specified by the `actor_id` and `sequence_number`.
+    async fn insert_changes(
+        &mut self,
+        changes: Vec<(ActorId, u64, Vec<u8>)>,
+    ) -> Result<(), Self::Error>;
+
+    /// Removes the change at the unique address specified by the `actor_id` and `sequence_number`.
+    ///
+    /// If the change does not exist this should not return an error.
+    async fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error>;
+
+    /// Returns the document, if one has been persisted previously.
+    async fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Sets the document to the given data.
+    async fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// Returns the sync state for the given peer if one exists.
+    async fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Sets the sync state for the given peer.
+    async fn set_sync_state(
+        &mut self,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error>;
+
+    /// Removes the sync states associated with the given `peer_ids`.
+    async fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error>;
+
+    /// Returns the list of peer ids with stored `SyncStates`.
+    async fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error>;
+
+    /// Returns the sizes components being stored consume.
+    fn sizes(&self) -> StoredSizes;
+
+    /// Flush the data out to storage.
+    async fn flush(&mut self) -> Result<usize, Self::Error>;
+
+    /// Replace the given changes with a document covering them, removing the sync states for
+    /// `old_peer_ids` at the same time.
+    ///
+    /// By default this sets the document then removes the changes and sync states, so a reader
+    /// can see it part way through. Implementations that can apply it all at once should do so.
+    async fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        self.set_document(document).await?;
+        self.remove_changes(changes).await?;
+        self.remove_sync_states(old_peer_ids).await
+    }
+}
@@ -107,8 +107,9 @@ where
 
     /// Compact the storage.
     ///
-    /// This first obtains the changes currently in the document, saves the document and persists the
-    /// saved document. We then can remove the previously obtained changes one by one.
+    /// This first obtains the changes currently in the document and saves the document. The saved
+    /// document then replaces the previously obtained changes through [`Persister::compact`], which
+    /// persisters may apply atomically.
     ///
     /// It also clears out the storage used up by old sync states for peers by removing those given
     /// in `old_peers`.
@@ -125,19 +126,15 @@ where
         self.saved_heads = self.document.get_heads();
         let changes = self.document.get_changes(&[])?;
         self.persister
-            .set_document(saved_document)
-            .map_err(Error::PersisterError)?;
-        self.persister
-            .remove_changes(
+            .compact(
+                saved_document,
                 changes
                     .into_iter()
                     .map(|c| (c.actor_id(), c.seq()))
                     .collect(),
+                old_peer_ids,
             )
             .map_err(Error::PersisterError)?;
-        self.persister
-            .remove_sync_states(old_peer_ids)
-            .map_err(Error::PersisterError)?;
         Ok(())
     }
 
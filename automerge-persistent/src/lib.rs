@@ -19,12 +19,16 @@
 //! let doc = PersistentAutomerge::load(persister).unwrap();
 //! ```
 
+#[cfg(feature = "async")]
+mod async_persister;
 mod autocommit;
 mod mem;
 mod persister;
 
 use std::{collections::HashMap, fmt::Debug};
 
+#[cfg(feature = "async")]
+pub use async_persister::AsyncPersister;
 pub use autocommit::PersistentAutoCommit;
 use automerge::{
     op_observer::BranchableObserver,
@@ -36,7 +40,7 @@ pub use mem::MemoryPersister;
 pub use persister::Persister;
 
 /// Bytes stored for each of the stored types.
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct StoredSizes {
     /// Total bytes stored for all changes.
     pub changes: u64,
@@ -46,6 +50,33 @@ pub struct StoredSizes {
     pub sync_states: u64,
 }
 
+/// The number of items stored for each of the stored types that can have many.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StoredCounts {
+    /// The number of changes stored.
+    pub changes: u64,
+    /// The number of peers with a stored sync state.
+    pub sync_states: u64,
+}
+
+/// The start of every key belonging to `prefix`, for persisters that keep many documents in one
+/// key space, each under its own prefix.
+///
+/// This is the length of the prefix as a big endian u64 followed by the prefix, so that no
+/// prefix's keys can be mistaken for those of a longer prefix that starts the same way.
+///
+/// ```rust
+/// # use automerge_persistent::namespace;
+/// assert_eq!(namespace("ab"), vec![0, 0, 0, 0, 0, 0, 0, 2, b'a', b'b']);
+/// assert!(!namespace("abc").starts_with(&namespace("ab")));
+/// ```
+#[must_use]
+pub fn namespace(prefix: &str) -> Vec<u8> {
+    let mut namespace = (prefix.len() as u64).to_be_bytes().to_vec();
+    namespace.extend(prefix.as_bytes());
+    namespace
+}
+
 /// Errors that persistent documents can return.
 #[derive(Debug, thiserror::Error)]
 pub enum Error<E> {
@@ -200,8 +231,9 @@ where
 
     /// Compact the storage.
     ///
-    /// This first obtains the changes currently in the document, saves the document and persists the
-    /// saved document. We then can remove the previously obtained changes one by one.
+    /// This first obtains the changes currently in the document and saves the document. The saved
+    /// document then replaces the previously obtained changes through [`Persister::compact`], which
+    /// persisters may apply atomically.
     ///
     /// It also clears out the storage used up by old sync states for peers by removing those given
     /// in `old_peers`.
@@ -217,19 +249,15 @@ where
         let saved_document = self.document.save();
         let changes = self.document.get_changes(&[])?;
         self.persister
-            .set_document(saved_document)
-            .map_err(Error::PersisterError)?;
-        self.persister
-            .remove_changes(
+            .compact(
+                saved_document,
                 changes
                     .into_iter()
                     .map(|c| (c.actor_id(), c.seq()))
                     .collect(),
+                old_peer_ids,
             )
             .map_err(Error::PersisterError)?;
-        self.persister
-            .remove_sync_states(old_peer_ids)
-            .map_err(Error::PersisterError)?;
         Ok(())
     }
 
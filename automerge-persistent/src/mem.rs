@@ -90,3 +90,61 @@ impl Persister for MemoryPersister {
         Ok(0)
     }
 }
+
+#[cfg(feature = "async")]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl crate::AsyncPersister for MemoryPersister {
+    type Error = std::convert::Infallible;
+
+    async fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Persister::get_changes(self)
+    }
+
+    async fn insert_changes(
+        &mut self,
+        changes: Vec<(ActorId, u64, Vec<u8>)>,
+    ) -> Result<(), Self::Error> {
+        Persister::insert_changes(self, changes)
+    }
+
+    async fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        Persister::remove_changes(self, changes)
+    }
+
+    async fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Persister::get_document(self)
+    }
+
+    async fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        Persister::set_document(self, data)
+    }
+
+    async fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Persister::get_sync_state(self, peer_id)
+    }
+
+    async fn set_sync_state(
+        &mut self,
+        peer_id: Vec<u8>,
+        sync_state: Vec<u8>,
+    ) -> Result<(), Self::Error> {
+        Persister::set_sync_state(self, peer_id, sync_state)
+    }
+
+    async fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        Persister::remove_sync_states(self, peer_ids)
+    }
+
+    async fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        Persister::get_peer_ids(self)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        Persister::sizes(self)
+    }
+
+    async fn flush(&mut self) -> Result<usize, Self::Error> {
+        Persister::flush(self)
+    }
+}
@@ -63,4 +63,20 @@ pub trait Persister {
 
     /// Flush the data out to disk.
     fn flush(&mut self) -> Result<usize, Self::Error>;
+
+    /// Replace the given changes with a document covering them, removing the sync states for
+    /// `old_peer_ids` at the same time.
+    ///
+    /// By default this sets the document then removes the changes and sync states, so a reader
+    /// can see it part way through. Implementations that can apply it all at once should do so.
+    fn compact(
+        &mut self,
+        document: Vec<u8>,
+        changes: Vec<(&ActorId, u64)>,
+        old_peer_ids: &[&[u8]],
+    ) -> Result<(), Self::Error> {
+        self.set_document(document)?;
+        self.remove_changes(changes)?;
+        self.remove_sync_states(old_peer_ids)
+    }
 }